@@ -0,0 +1,63 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Returns the files that change when `HEAD` moves, so that the commit is captured again.
+///
+/// ## See Also
+///
+/// - `HEAD` changes on checkout, and the branch's ref file or `packed-refs` on commit.
+/// - Files that do not exist are left out, since cargo would rerun the build script on every build for them.
+fn git_head_files() -> Vec<PathBuf> {
+    let git_dir = Command::new("git")
+        .args(["rev-parse", "--git-dir"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()));
+    let git_dir = match git_dir {
+        Some(git_dir) => git_dir,
+        None => return Vec::new()
+    };
+    let head = git_dir.join("HEAD");
+    let mut files = vec![git_dir.join("packed-refs")];
+    // sample: ref: refs/heads/main
+    if let Some(reference) = std::fs::read_to_string(&head).ok()
+        .and_then(|content| content.strip_prefix("ref:").map(|reference| reference.trim().to_string())) {
+        files.push(git_dir.join(reference));
+    }
+    files.push(head);
+    files.into_iter().filter(|file| file.is_file()).collect()
+}
+
+/// Captures the git commit, the build time and the compiler version, so that they are available to `env!` at compile time.
+///
+/// ## See Also
+///
+/// The commit and the compiler version default to `unknown` when they cannot be retrieved, e.g. when building from a source archive.
+fn main() {
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|sha| !sha.is_empty())
+        .unwrap_or("unknown".to_string());
+    // Cargo points RUSTC to the compiler used for the build, the output looks like 'rustc 1.76.0 (07dca489a 2024-02-04)'
+    let rust_version = Command::new(std::env::var("RUSTC").unwrap_or("rustc".to_string()))
+        .arg("--version")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8_lossy(&output.stdout).split_whitespace().nth(1).map(String::from))
+        .unwrap_or("unknown".to_string());
+    let build_timestamp = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    println!("cargo:rustc-env=GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_timestamp);
+    println!("cargo:rustc-env=RUST_VERSION={}", rust_version);
+    // Declaring any file replaces the default of rerunning on every change in the package, so the script is included
+    println!("cargo:rerun-if-changed=build.rs");
+    for file in git_head_files() {
+        println!("cargo:rerun-if-changed={}", file.display());
+    }
+}
@@ -1,8 +1,19 @@
-use std::collections::HashMap;
-use std::env;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::{env, fs, io};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
 
-/// Instantiates the `Session` struct with empty `HashMap` for both `tracker` and `mapping` fields.
+use chrono::NaiveDate;
+use dashmap::DashMap;
+
+use crate::routes;
+
+/// Repositories that a GitHub App installation token can reach, `None` if the token was rejected.
+pub type InstallationScope = Option<HashSet<String>>;
+
+/// Instantiates the `Session` struct with empty `HashMap` for both `tracker` and `mapping` fields,
+/// along with the identifiers for the running instance.
 ///
 /// ## See Also
 ///
@@ -13,12 +24,36 @@ use std::sync::{Arc, Mutex};
 /// Returns the constructed `Arc` for the `Session` struct.
 pub fn session_info() -> Arc<Session> {
     Arc::new(Session {
-        tracker: Mutex::new(HashMap::new())
+        tracker: Mutex::new(HashMap::new()),
+        repo_sizes: Mutex::new(HashMap::new()),
+        installation_tokens: Mutex::new(HashMap::new()),
+        recent_payloads: DashMap::new(),
+        blob_index: DashMap::new(),
+        backup_log: Mutex::new(None),
+        access_log: Mutex::new(None),
+        repo_locks: Mutex::new(HashMap::new()),
+        active_requests: Arc::new(AtomicUsize::new(0)),
+        skipped_unchanged: AtomicU64::new(0),
+        fallback_clones: AtomicU64::new(0),
+        http_client: reqwest::Client::new(),
+        scheduled: Mutex::new(BinaryHeap::new()),
+        start_time: Instant::now(),
+        instance_id: uuid::Uuid::new_v4().to_string(),
+        hostname: get_hostname(),
     })
 }
 
+/// Retrieves the hostname of the machine, falling back to the `HOSTNAME` env var.
+fn get_hostname() -> String {
+    match hostname::get() {
+        Ok(name) => name.to_string_lossy().to_string(),
+        Err(_) => env::var("HOSTNAME").unwrap_or("unknown".to_string())
+    }
+}
+
 /// Struct to store the cargo information gathered at compile time using the `env!` macro.
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub struct MetaData {
     pub crate_name: String,
     pub manifest_dir: String,
@@ -32,6 +67,9 @@ pub struct MetaData {
     pub pkg_version_minor: String,
     pub pkg_version_patch: String,
     pub pkg_version_pre: String,
+    pub git_sha: String,
+    pub build_timestamp: String,
+    pub rust_version: String,
 }
 
 /// Uses compile time macros to load Cargo metadata via environment variables during compilation process
@@ -54,6 +92,10 @@ pub fn build_info() -> Arc<MetaData> {
         pkg_version_minor: env!("CARGO_PKG_VERSION_MINOR").to_string(),
         pkg_version_patch: env!("CARGO_PKG_VERSION_PATCH").to_string(),
         pkg_version_pre: env!("CARGO_PKG_VERSION_PRE").to_string(),
+        // Set by the build script
+        git_sha: env!("GIT_SHA").to_string(),
+        build_timestamp: env!("BUILD_TIMESTAMP").to_string(),
+        rust_version: env!("RUST_VERSION").to_string(),
     };
     Arc::new(metadata)
 }
@@ -63,11 +105,110 @@ pub fn build_info() -> Arc<MetaData> {
 /// ## Fields
 ///
 /// * `tracker` - Used to log connection information without redundancy.
-/// * `mapping` - Used to store username and session token's payload as key value pairs.
+/// * `repo_sizes` - Used to cache the repository size (in KB) along with the time it was fetched.
+/// * `installation_tokens` - Used to cache the repositories a GitHub App installation token can reach, keyed by its hash.
+/// * `recent_payloads` - Used to store the time when a payload hash was last applied to a repository.
+/// * `blob_index` - Used to look up the blobs in the content-addressed store by their SHA256.
+/// * `backup_log` - Used to write the transaction log for the current day.
+/// * `access_log` - Used to write the access log for the current day.
+/// * `repo_locks` - Used to serialize the operations on each repository, removed once no request holds or waits for them.
+/// * `active_requests` - Number of requests that are currently being processed.
+/// * `skipped_unchanged` - Number of files that were skipped, since their content was unchanged.
+/// * `fallback_clones` - Number of times a repository was re-cloned after an operation on it failed.
+/// * `http_client` - Shared HTTP client, to reuse the connections across requests.
+/// * `scheduled` - Backups deferred with the `X-Execute-After` header, ordered by their execution time.
+/// * `start_time` - Time when the server was started.
+/// * `instance_id` - Unique identifier generated at startup to tell replicas apart.
+/// * `hostname` - Hostname of the machine running the server.
 ///
 /// ## See Also:
 ///
 /// These fields are updated and used only for authenticated sessions.
 pub struct Session {
-    pub tracker: Mutex<HashMap<String, String>>
+    pub tracker: Mutex<HashMap<String, String>>,
+    pub repo_sizes: Mutex<HashMap<String, (Instant, u64)>>,
+    pub installation_tokens: Mutex<HashMap<String, (Instant, InstallationScope)>>,
+    pub recent_payloads: DashMap<(String, String), Instant>,
+    pub blob_index: DashMap<String, std::path::PathBuf>,
+    pub backup_log: Mutex<Option<(NaiveDate, io::BufWriter<fs::File>)>>,
+    pub access_log: Mutex<Option<(NaiveDate, io::BufWriter<fs::File>)>>,
+    pub repo_locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    pub active_requests: Arc<AtomicUsize>,
+    pub skipped_unchanged: AtomicU64,
+    pub fallback_clones: AtomicU64,
+    pub http_client: reqwest::Client,
+    pub scheduled: Mutex<BinaryHeap<routes::backup::Scheduled>>,
+    pub start_time: Instant,
+    pub instance_id: String,
+    pub hostname: String,
+}
+
+impl Session {
+    /// Retrieves the lock for a repository, creating one if it doesn't exist yet.
+    fn repo_lock(&self, repository: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.repo_locks.lock().unwrap()
+            .entry(repository.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Waits for the lock on a repository, that is held until the returned guard is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `repository` - Name of the repository.
+    ///
+    /// # Returns
+    ///
+    /// Returns the `RepoGuard` that releases the lock, and removes it once no other request holds or waits for it.
+    pub async fn lock_repo(&self, repository: &str) -> RepoGuard<'_> {
+        let guard = self.repo_lock(repository).lock_owned().await;
+        RepoGuard { session: self, repository: repository.to_string(), guard: Some(guard) }
+    }
+
+    /// Removes the lock for a repository, if no request holds or waits for it.
+    ///
+    /// ## See Also
+    ///
+    /// Requests clone the lock while the map is held, so the count cannot change while it is being checked.
+    pub fn prune_repo_lock(&self, repository: &str) {
+        let mut repo_locks = self.repo_locks.lock().unwrap();
+        if repo_locks.get(repository).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            repo_locks.remove(repository);
+        }
+    }
+
+    /// Marks a request as active until the returned guard is dropped.
+    ///
+    /// # Returns
+    ///
+    /// Returns the `RequestGuard` that decrements the counter when the handler exits.
+    pub fn track_request(&self) -> RequestGuard<'_> {
+        self.active_requests.fetch_add(1, Ordering::SeqCst);
+        RequestGuard(&self.active_requests)
+    }
+}
+
+/// Guard that releases the lock on a repository when dropped.
+pub struct RepoGuard<'a> {
+    session: &'a Session,
+    repository: String,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for RepoGuard<'_> {
+    fn drop(&mut self) {
+        // Lock is released first, so that its reference is not counted as another holder
+        drop(self.guard.take());
+        self.session.prune_repo_lock(&self.repository);
+    }
+}
+
+/// Guard that decrements the active requests' counter when dropped.
+pub struct RequestGuard<'a>(&'a AtomicUsize);
+
+impl Drop for RequestGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
@@ -38,18 +38,41 @@ pub async fn start() -> io::Result<()> {
     let metadata = constant::build_info();
     let config = squire::startup::get_config(&metadata);
 
-    squire::startup::init_logger(config.debug, config.utc_logging, &metadata.crate_name);
-    println!("{}[v{}] - {}", &metadata.pkg_name, &metadata.pkg_version, &metadata.description);
-    if !squire::command::run("git version") {
+    println!("{}[v{}] [commit:{} built:{}] - {}", &metadata.pkg_name, &metadata.pkg_version,
+             &metadata.git_sha, &metadata.build_timestamp, &metadata.description);
+    if !squire::command::GitCommand::new("version").run() {
         println!("'git' command line is mandatory!!");
         exit(1)
     }
-    squire::ascii_art::random();
+    let (major, minor, patch) = squire::command::MIN_GIT_VERSION;
+    match squire::command::git_version() {
+        Some(version) if version >= squire::command::MIN_GIT_VERSION => (),
+        Some((found_major, found_minor, found_patch)) => {
+            println!("'git' version {}.{}.{} is not supported, {}.{}.{} or later is required!!",
+                     found_major, found_minor, found_patch, major, minor, patch);
+            exit(1)
+        }
+        None => log::warn!("Unable to determine the 'git' version, {}.{}.{} or later is required", major, minor, patch)
+    }
+    if config.show_ascii_art {
+        match &config.ascii_art_file {
+            Some(filepath) => squire::ascii_art::from_file(filepath),
+            None => squire::ascii_art::random()
+        }
+    }
+
+    let max_age = std::time::Duration::from_secs(config.partial_file_max_age_seconds);
+    let removed = squire::partial::remove_partial_files(&config.github_source, max_age);
+    if removed > 0 {
+        log::info!("Removed {} orphaned partial files", removed);
+    }
 
     // Create a dedicated clone, since it will be used within closure
     let config_clone = config.clone();
     let session = constant::session_info();
-    let host = format!("{}:{}", config.server_host, config.server_port);
+    // Runs on the main thread's runtime, outside the server workers
+    actix_web::rt::spawn(routes::backup::process_scheduled(session.clone(), config.clone()));
+    let host = config.address();
     log::info!("{} [workers:{}] running on http://{} (Press CTRL+C to quit)",
         &metadata.pkg_name, &config.workers, &host);
     /*
@@ -64,26 +87,56 @@ pub async fn start() -> io::Result<()> {
             .app_data(web::Data::new(session.clone()))
             .app_data(web::Data::new(routes::backup::Payload::default()))
             .app_data(web::PayloadConfig::default().limit(config_clone.max_payload_size))
-            .wrap(squire::middleware::get_cors(config_clone.websites.clone()))
+            .app_data(web::JsonConfig::default()
+                .limit(config_clone.max_payload_size)
+                .error_handler(squire::custom::json_error_handler))
+            .wrap(squire::middleware::get_cors(&config_clone))
+            .wrap(squire::middleware::ResponseTimeout {
+                duration: std::time::Duration::from_millis(config_clone.response_timeout_ms)
+            })
             .wrap(middleware::Logger::default())  // Adds a default logger middleware to the application
+            .wrap(squire::middleware::AccessLog)  // Wraps ResponseTimeout, so that timed out requests are logged with 504
+            .wrap(squire::middleware::SecurityHeaders {
+                content_security_policy: config_clone.content_security_policy.clone()
+            })
+            .wrap(middleware::DefaultHeaders::new().add(("X-Instance-ID", session.instance_id.clone())))
             .service(routes::backup::backup_endpoint)
+            .service(routes::backup::backup_stream_endpoint)
+            .service(routes::backup::backup_tar_endpoint)
+            .service(routes::backup::backup_log_endpoint)
+            .service(routes::backup::backup_scheduled_endpoint)
             .service(routes::clone::clone_endpoint)
+            .service(routes::status::status_endpoint)
+            .service(routes::health::health_endpoint)
+            .service(routes::version::version_endpoint)
+            .service(routes::config::schema_endpoint)
+            .service(routes::repos::get_repo)
+            .service(routes::repos::create_tag)
+            .service(routes::repos::delete_files)
+            .service(routes::repos::fetch_repo)
+            .service(routes::repos::sparse_checkout)
+            .service(routes::repos::get_manifest)
     };
     let server = HttpServer::new(application)
         .workers(config.workers)
         .max_connections(config.max_connections);
     // Reference: https://actix.rs/docs/http2/
-    if config.cert_file.exists() && config.key_file.exists() {
+    let server = if config.cert_file.exists() && config.key_file.exists() {
         log::info!("Binding SSL certificate to serve over HTTPS");
         let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
         builder.set_private_key_file(&config.key_file, SslFiletype::PEM).unwrap();
         builder.set_certificate_chain_file(&config.cert_file).unwrap();
-        server.bind_openssl(host, builder)?
-            .run()
-            .await
+        server.bind_openssl(&host, builder)?
     } else {
-        server.bind(host)?
-            .run()
-            .await
+        server.bind(&host)?
+    };
+    // Port 0 is assigned by the OS, so the actual port is only known after binding
+    if let Some(actual) = server.addrs().first().filter(|addr| host.ends_with(":0") && addr.port() != 0) {
+        log::info!("Listening on {}:{}", actual.ip(), actual.port());
+        let port_file = config.github_source.join(".server_port");
+        if let Err(err) = std::fs::write(&port_file, actual.port().to_string()) {
+            log::warn!("Failed to write the port to {:?}: {}", port_file, err);
+        }
     }
+    server.run().await
 }
@@ -1,49 +1,502 @@
-use std::sync;
-use actix_web::{HttpRequest, web};
-use crate::squire;
+use std::{collections, sync, time};
+use actix_web::{HttpRequest, HttpResponse, web};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::{constant, routes, squire};
+
+/// Duration for which the result of verifying a GitHub App installation token is cached.
+const INSTALLATION_TOKEN_TTL: time::Duration = time::Duration::from_secs(300);
+
+/// Maximum number of the GitHub App's installations that an installation token is matched against.
+const MAX_INSTALLATIONS: usize = 10;
+
+/// Claims of the JWT that authenticates as the GitHub App.
+#[derive(Serialize)]
+struct AppClaims {
+    iat: i64,
+    exp: i64,
+    iss: String,
+}
 
 /// Struct for the authentication response.
+///
+/// ## See Also
+///
+/// `repositories` is set for GitHub App installation tokens, to the repositories covered by the installation.
 pub struct AuthResponse {
     pub ok: bool,
     pub repository: String,
+    pub org: String,
+    pub repo_name: String,
+    pub branch: String,
+    pub repositories: Option<collections::HashSet<String>>,
+}
+
+impl AuthResponse {
+    /// Checks if the token can reach a repository, the static token can reach all of them.
+    pub fn allows(&self, repository: &str) -> bool {
+        self.repositories.as_ref()
+            .map_or(true, |repositories| repositories.contains(&repository.to_lowercase()))
+    }
+}
+
+/// Struct for the components of the `content-location` header.
+pub struct ContentLocation {
+    pub repository: String,
+    pub org: String,
+    pub repo_name: String,
     pub branch: String,
 }
 
+/// Checks if a repository name is in `{org}/{repo}` format, with only `[A-Za-z0-9_.-]` in each part.
+///
+/// # Arguments
+///
+/// * `repository` - Name of the repository.
+/// * `max_length` - Maximum length of the name, each part is limited to half of it.
+///
+/// ## See Also
+///
+/// Long names are rejected, since they can exceed the path limits of the filesystem, e.g. `MAX_PATH` on Windows.
+///
+/// # Returns
+///
+/// Returns a `Result` with the organization and repository name, or the reason for rejection.
+pub fn sanitize_repo_name(repository: &str, max_length: usize) -> Result<(String, String), String> {
+    if repository.len() < squire::settings::MIN_REPO_NAME_LENGTH || repository.len() > max_length {
+        return Err(format!("repository name should be {} to {} characters long, received {}",
+                           squire::settings::MIN_REPO_NAME_LENGTH, max_length, repository.len()));
+    }
+    let (org, repo_name) = match repository.split_once('/') {
+        Some(parts) => parts,
+        None => return Err("repository must be in org/repo format".to_string())
+    };
+    if org.len() > max_length / 2 || repo_name.len() > max_length / 2 {
+        return Err(format!("organization and repository names should be up to {} characters each",
+                           max_length / 2));
+    }
+    let is_valid = |component: &str| {
+        !component.is_empty() && component.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    };
+    if !is_valid(org) || !is_valid(repo_name) {
+        return Err(format!("invalid repository name: {}", repository));
+    }
+    routes::helper::validate_repo_name(repository)?;
+    Ok((org.to_string(), repo_name.to_string()))
+}
+
+/// Parses the `content-location` header in the format `{org}/{repo};{branch}`
+///
+/// # Arguments
+///
+/// * `value` - Value of the `content-location` header.
+/// * `max_length` - Maximum length of the repository name.
+///
+/// # Returns
+///
+/// Returns a `Result` with the `ContentLocation` components, or the reason for rejection.
+pub fn parse_content_location(value: &str, max_length: usize) -> Result<ContentLocation, String> {
+    let (repository, branch) = match value.split_once(';') {
+        Some(parts) => parts,
+        None => return Err("'content-location' header should be in the format {org}/{repo};{branch}".to_string())
+    };
+    // Branch names are allowed to have slashes, e.g. 'feature/login'
+    if branch.is_empty() || !branch.chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '/')) {
+        return Err(format!("invalid branch name: {}", branch));
+    }
+    let (org, repo_name) = sanitize_repo_name(repository, max_length)?;
+    Ok(ContentLocation {
+        repository: repository.to_string(),
+        org,
+        repo_name,
+        branch: branch.to_string(),
+    })
+}
+
 /// Verifies the token received against the one set in env vars.
 ///
 /// * `request` - A reference to the Actix web `HttpRequest` object.
 /// * `config` - Configuration data for the application.
 ///
+/// ## See Also
+///
+/// - When `preferred_auth_header` is set to `x-api-key`, the token is read from the `X-Api-Key` header
+///   in the absence of the `Authorization` header.
+/// - When the GitHub App is configured, a bearer token that doesn't match is verified as an installation token.
+///
 /// # Returns
 ///
-/// A configured `AuthResponse` instance.
-pub fn verify_token(request: &HttpRequest,
-                    config: &web::Data<sync::Arc<squire::settings::Config>>) -> AuthResponse {
+/// A configured `AuthResponse` instance, with empty repository information.
+pub async fn verify_token(request: &HttpRequest,
+                          config: &web::Data<sync::Arc<squire::settings::Config>>) -> AuthResponse {
     let headers = request.headers();
-    if let Some(authorization) = headers.get("authorization") {
-        let auth = authorization.to_str().unwrap().to_string();
+    // Token is never logged, the truncated user agent is enough to identify the failing client
+    let user_agent: String = headers.get("user-agent")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .chars().take(100).collect();
+    let mut repositories = None;
+    let ok = if let Some(authorization) = headers.get("authorization") {
+        log::debug!("Authenticating with the 'Authorization' header");
+        let auth = authorization.to_str().unwrap_or_default().to_string();
         if format!("Bearer {}", config.authorization) == auth {
-            let mut location = String::new();
-            if let Some(header_value) = headers.get("content-location") {
-                if let Ok(location_str) = header_value.to_str() {
-                    location = location_str.to_string();
-                } else {
-                    log::error!("Failed to convert 'content-location' header to string");
-                }
+            true
+        } else if let (Some(token), Some(session)) = (
+            auth.strip_prefix("Bearer ").filter(|_| config.github_app_id.is_some()),
+            request.app_data::<web::Data<sync::Arc<constant::Session>>>()
+        ) {
+            repositories = verify_installation_token(token, config, session).await;
+            if repositories.is_none() {
+                log::error!("Invalid token [ua={}]", user_agent);
             }
-            let (repository, branch) = {
-                let mut parts = location.split(';');
-                let repository = parts.next().unwrap_or("");
-                let branch = parts.next().unwrap_or("");
-                (repository.to_string(), branch.to_string())
-            };
-            AuthResponse { ok: true, repository, branch }
+            repositories.is_some()
         } else {
-            log::error!("Invalid token: {}", auth);
-            AuthResponse { ok: false, repository: String::new(), branch: String::new() }
+            log::error!("Invalid token [ua={}]", user_agent);
+            false
+        }
+    } else if let (true, Some(api_key)) = (config.preferred_auth_header == "x-api-key", headers.get("x-api-key")) {
+        log::debug!("Authenticating with the 'X-Api-Key' header");
+        let auth = api_key.to_str().unwrap_or_default().to_string();
+        if config.authorization == auth {
+            true
+        } else {
+            log::error!("Invalid token [ua={}]", user_agent);
+            false
         }
     } else {
-        log::error!("No auth header received");
-        AuthResponse { ok: false, repository: String::new(), branch: String::new() }
+        log::error!("No auth header received for {} [ua={}]", request.path(), user_agent);
+        false
+    };
+    AuthResponse {
+        ok,
+        repository: String::new(),
+        org: String::new(),
+        repo_name: String::new(),
+        branch: String::new(),
+        repositories,
+    }
+}
+
+/// Verifies the token and the `content-location` header for the endpoints that operate on a repository.
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `config` - Configuration data for the application.
+///
+/// # Returns
+///
+/// A `Result` with the `AuthResponse`, or the boxed `HttpResponse` to return for failures.
+pub async fn authorize(request: &HttpRequest,
+                       config: &web::Data<sync::Arc<squire::settings::Config>>) -> Result<AuthResponse, Box<HttpResponse>> {
+    let auth_response = verify_token(request, config).await;
+    if !auth_response.ok {
+        return Err(Box::new(HttpResponse::Unauthorized().finish()));
+    }
+    let location = match request.headers().get("content-location").map(|value| value.to_str()) {
+        Some(Ok(location)) if !location.is_empty() => location,
+        _ => {
+            log::warn!("'content-location' header is invalid");
+            return Err(Box::new(HttpResponse::BadRequest().json("'content-location' header is invalid")));
+        }
+    };
+    let parsed = match parse_content_location(location, config.max_repo_name_length) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            log::warn!("{}", error);
+            return Err(Box::new(HttpResponse::BadRequest().json(error)));
+        }
+    };
+    if !auth_response.allows(&parsed.repository) {
+        log::warn!("Repository '{}' is not covered by the installation token", parsed.repository);
+        return Err(Box::new(HttpResponse::Forbidden().json("repository is not covered by the installation")));
+    }
+    Ok(AuthResponse {
+        ok: true,
+        repository: parsed.repository,
+        org: parsed.org,
+        repo_name: parsed.repo_name,
+        branch: parsed.branch,
+        repositories: auth_response.repositories,
+    })
+}
+
+/// Creates the short-lived JWT to authenticate as the GitHub App.
+///
+/// # Arguments
+///
+/// * `app_id` - ID of the GitHub App.
+/// * `private_key_file` - Private key of the GitHub App in PEM format.
+///
+/// ## See Also
+///
+/// The issue time is set a minute in the past to allow for clock drift, as recommended by GitHub.
+///
+/// # Returns
+///
+/// Returns a `Result` with the encoded JWT, or the reason for failure.
+fn app_jwt(app_id: u64, private_key_file: &std::path::Path) -> Result<String, String> {
+    let pem = std::fs::read(private_key_file)
+        .map_err(|err| format!("unable to read {:?}: {}", private_key_file, err))?;
+    let key = jsonwebtoken::EncodingKey::from_rsa_pem(&pem)
+        .map_err(|err| format!("{:?} is not a valid RSA private key: {}", private_key_file, err))?;
+    let now = chrono::Utc::now().timestamp();
+    let claims = AppClaims { iat: now - 60, exp: now + 540, iss: app_id.to_string() };
+    jsonwebtoken::encode(&jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256), &claims, &key)
+        .map_err(|err| format!("unable to sign the JWT: {}", err))
+}
+
+/// Verifies a GitHub App installation token, and caches the result in the session.
+///
+/// # Arguments
+///
+/// * `token` - Bearer token received in the request.
+/// * `config` - Configuration data for the application.
+/// * `session` - Session struct that holds the cached results.
+///
+/// ## See Also
+///
+/// - The token is valid if it reaches the same repositories as one of the GitHub App's own installations.
+/// - Results are cached by the SHA256 of the token for 5 minutes, so the token itself is never stored.
+///
+/// # Returns
+///
+/// Returns the repositories covered by the installation, or `None` if the token does not match one.
+async fn verify_installation_token(token: &str,
+                                   config: &squire::settings::Config,
+                                   session: &constant::Session) -> constant::InstallationScope {
+    let key = format!("{:x}", Sha256::digest(token.as_bytes()));
+    if let Some((verified, repositories)) = session.installation_tokens.lock().unwrap().get(&key) {
+        if verified.elapsed() < INSTALLATION_TOKEN_TTL {
+            return repositories.clone();
+        }
+    }
+    let repositories = match installation_repositories(token, config, session).await {
+        Ok(repositories) => repositories,
+        Err(err) => {
+            // Failures to reach GitHub are not cached, so the next request tries again
+            log::warn!("Unable to verify the installation token: {}", err);
+            return None;
+        }
+    };
+    let mut cache = session.installation_tokens.lock().unwrap();
+    cache.retain(|_, (verified, _)| verified.elapsed() < INSTALLATION_TOKEN_TTL);
+    cache.insert(key, (time::Instant::now(), repositories.clone()));
+    repositories
+}
+
+/// Sends a request to the GitHub API with a bearer token.
+///
+/// # Arguments
+///
+/// * `session` - Session struct that holds the shared HTTP client.
+/// * `method` - HTTP method of the request.
+/// * `url` - URL of the GitHub API endpoint.
+/// * `bearer` - Installation token or JWT to authenticate with.
+///
+/// # Returns
+///
+/// Returns a `Result` with the response, or the reason the request failed.
+async fn github_api(session: &constant::Session,
+                    method: reqwest::Method,
+                    url: &str,
+                    bearer: &str) -> Result<reqwest::Response, String> {
+    // GitHub API rejects requests without a user agent
+    session.http_client.request(method, url)
+        .bearer_auth(bearer)
+        .header("user-agent", env!("CARGO_PKG_NAME"))
+        .header("accept", "application/vnd.github+json")
+        .send().await
+        .map_err(|err| err.to_string())
+}
+
+/// Reads the body of a successful GitHub API response as JSON.
+///
+/// # Arguments
+///
+/// * `response` - Response from the GitHub API.
+///
+/// # Returns
+///
+/// Returns a `Result` with the parsed body, or the reason for failure when the status is an error.
+async fn read_json(response: reqwest::Response) -> Result<serde_json::Value, String> {
+    let body = response.error_for_status().map_err(|err| err.to_string())?
+        .text().await.map_err(|err| err.to_string())?;
+    serde_json::from_str(&body).map_err(|err| err.to_string())
+}
+
+/// Lists the repositories an installation token can reach.
+///
+/// # Arguments
+///
+/// * `session` - Session struct that holds the shared HTTP client.
+/// * `token` - Installation token.
+///
+/// # Returns
+///
+/// Returns a `Result` with the lowercase full names of the repositories, `None` if GitHub rejects the token.
+async fn list_repositories(session: &constant::Session,
+                           token: &str) -> Result<Option<collections::HashSet<String>>, String> {
+    let mut repositories = collections::HashSet::new();
+    for page in 1.. {
+        let url = format!("https://api.github.com/installation/repositories?per_page=100&page={}", page);
+        let response = github_api(session, reqwest::Method::GET, &url, token).await?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(None);
+        }
+        let body = read_json(response).await?;
+        let listed: Vec<String> = body["repositories"].as_array().into_iter().flatten()
+            .filter_map(|repository| repository["full_name"].as_str())
+            .map(str::to_lowercase)
+            .collect();
+        let total = body["total_count"].as_u64().unwrap_or_default() as usize;
+        let last_page = listed.is_empty();
+        repositories.extend(listed);
+        if last_page || repositories.len() >= total {
+            break;
+        }
+    }
+    Ok(Some(repositories))
+}
+
+/// Matches the repositories of an installation token against the installations of the GitHub App.
+///
+/// # Arguments
+///
+/// * `token` - Installation token received in the request.
+/// * `config` - Configuration data for the application.
+/// * `session` - Session struct that holds the shared HTTP client.
+///
+/// ## See Also
+///
+/// - Installations are listed with the app's JWT, and a token is minted and revoked for each of them to list
+///   its repositories, so only the first `MAX_INSTALLATIONS` are walked on each cache miss.
+/// - Identical scope is the only check, since GitHub does not tell which app a token was issued to.
+///   A token issued to another app, that reaches exactly the same repositories as one of the installations, is accepted.
+///
+/// # Returns
+///
+/// Returns a `Result` with the repositories of the matching installation, or the reason GitHub could not be reached.
+async fn installation_repositories(token: &str,
+                                   config: &squire::settings::Config,
+                                   session: &constant::Session) -> Result<constant::InstallationScope, String> {
+    let (app_id, private_key_file) = match (config.github_app_id, &config.github_app_private_key_file) {
+        (Some(app_id), Some(private_key_file)) => (app_id, private_key_file),
+        _ => return Ok(None)
+    };
+    let repositories = match list_repositories(session, token).await? {
+        Some(repositories) if !repositories.is_empty() => repositories,
+        Some(_) => {
+            log::warn!("Installation token has no repositories to match an installation");
+            return Ok(None);
+        }
+        None => return Ok(None)
+    };
+    let jwt = app_jwt(app_id, private_key_file)?;
+    let installations = read_json(github_api(session, reqwest::Method::GET,
+                                             "https://api.github.com/app/installations?per_page=100", &jwt).await?).await?;
+    let installation_ids: Vec<u64> = installations.as_array().into_iter().flatten()
+        .filter_map(|installation| installation["id"].as_u64())
+        .collect();
+    if installation_ids.len() > MAX_INSTALLATIONS {
+        log::warn!("GitHub App {} has {} installations, only the first {} are matched",
+            app_id, installation_ids.len(), MAX_INSTALLATIONS);
+    }
+    for installation_id in installation_ids.into_iter().take(MAX_INSTALLATIONS) {
+        let url = format!("https://api.github.com/app/installations/{}/access_tokens", installation_id);
+        let minted = read_json(github_api(session, reqwest::Method::POST, &url, &jwt).await?).await?;
+        let minted = minted["token"].as_str()
+            .ok_or(format!("no token was issued for installation {}", installation_id))?;
+        let covered = list_repositories(session, minted).await;
+        // Minted token is only needed for the comparison, so it is revoked instead of being left valid for an hour
+        if let Err(err) = github_api(session, reqwest::Method::DELETE, "https://api.github.com/installation/token", minted).await {
+            log::warn!("Unable to revoke the token minted for installation {}: {}", installation_id, err);
+        }
+        if covered?.as_ref() == Some(&repositories) {
+            log::debug!("Installation token matches installation {} of GitHub App {}", installation_id, app_id);
+            return Ok(Some(repositories));
+        }
+    }
+    log::warn!("Installation token does not match any installation of GitHub App {}", app_id);
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Repeats the characters allowed in a repository name up to the given length, starting with a letter.
+    fn name_of_length(length: usize) -> String {
+        "aZ9_.-".chars().cycle().take(length).collect()
+    }
+
+    #[actix_web::test]
+    async fn test_sanitize_repo_name_length() {
+        let max_length = squire::settings::default_max_repo_name_length();
+        // Exactly at the limit, with each part within half of it
+        let repository = format!("{}/{}", name_of_length(max_length / 2 - 1), name_of_length(max_length / 2));
+        assert_eq!(repository.len(), max_length);
+        let (org, repo_name) = sanitize_repo_name(&repository, max_length).unwrap();
+        assert_eq!(format!("{}/{}", org, repo_name), repository);
+        // One over the limit
+        let repository = format!("{}/{}", name_of_length(max_length / 2), name_of_length(max_length / 2));
+        assert_eq!(repository.len(), max_length + 1);
+        assert!(sanitize_repo_name(&repository, max_length).unwrap_err().contains("characters long"));
+        // A single part over half of the limit
+        let repository = format!("{}/ab", name_of_length(max_length / 2 + 1));
+        assert!(sanitize_repo_name(&repository, max_length).unwrap_err().contains("characters each"));
+        // Minimum length
+        assert!(sanitize_repo_name("a/b", max_length).is_ok());
+        assert!(sanitize_repo_name("ab", max_length).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_sanitize_repo_name_characters() {
+        assert!(sanitize_repo_name("org/repo", 200).is_ok());
+        for repository in ["org", "org/", "/repo", "org/re po", "org/repo/extra", "org/.git", "../repo", "org/répo"] {
+            assert!(sanitize_repo_name(repository, 200).is_err(), "{}", repository);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_parse_content_location() {
+        let max_length = squire::settings::default_max_repo_name_length();
+        let repository = format!("{}/{}", name_of_length(max_length / 2 - 1), name_of_length(max_length / 2));
+        let location = parse_content_location(&format!("{};feature/login-2", repository), max_length).unwrap();
+        assert_eq!(location.repository, repository);
+        assert_eq!(location.org, name_of_length(max_length / 2 - 1));
+        assert_eq!(location.repo_name, name_of_length(max_length / 2));
+        assert_eq!(location.branch, "feature/login-2");
+        let repository = format!("{}/{}", name_of_length(max_length / 2), name_of_length(max_length / 2));
+        assert!(parse_content_location(&format!("{};main", repository), max_length).is_err());
+        for value in ["org/repo", "org/repo;", "org/repo;main branch", "org;main"] {
+            assert!(parse_content_location(value, max_length).is_err(), "{}", value);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_installation_token_is_scoped() {
+        let mut config = squire::settings::test_config(std::env::temp_dir());
+        config.github_app_id = Some(1);
+        let config = web::Data::new(sync::Arc::new(config));
+        let session = constant::session_info();
+        // Verification is cached, so the token is matched to an installation without reaching GitHub
+        let key = format!("{:x}", Sha256::digest(b"installation-token"));
+        let covered = collections::HashSet::from(["org/covered".to_string()]);
+        session.installation_tokens.lock().unwrap().insert(key, (time::Instant::now(), Some(covered)));
+        let request = |token: &str, location: &str| actix_web::test::TestRequest::default()
+            .app_data(web::Data::new(session.clone()))
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .insert_header(("Content-Location", location.to_string()))
+            .to_http_request();
+        let auth_response = authorize(&request("installation-token", "Org/Covered;main"), &config).await.ok().unwrap();
+        assert!(auth_response.allows("org/covered"));
+        assert!(!auth_response.allows("org/other"));
+        let response = authorize(&request("installation-token", "org/other;main"), &config).await.err().unwrap();
+        assert_eq!(response.status(), actix_web::http::StatusCode::FORBIDDEN);
+        // Static token reaches every repository
+        let auth_response = authorize(&request("test-token", "org/other;main"), &config).await.ok().unwrap();
+        assert!(auth_response.repositories.is_none());
+        assert!(auth_response.allows("org/other"));
     }
 }
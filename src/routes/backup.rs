@@ -1,11 +1,19 @@
-use std::{collections, fs, sync};
-use std::io::Write;
+use std::{cmp, collections, fs, path, sync, time};
+use std::io::{Read, Write};
+use std::sync::{atomic, OnceLock};
 
-use actix_web::{HttpRequest, HttpResponse, web};
+use actix_multipart::Multipart;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, web};
 use actix_web::http::StatusCode;
+use actix_web::mime;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::{stream, StreamExt};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::{constant, routes, squire};
+use crate::routes::helper::DownloadError;
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Payload {
@@ -14,125 +22,2221 @@ pub struct Payload {
     create: collections::HashMap<String, String>,
 
     #[serde(default = "default_hash")]
-    // sample: {'src/plain/main.py': 'src/main.py'} - move/rename
+    // sample: {'src/plain/main.py': 'src/main.py'} - move/rename, applied before 'create'
     modify: collections::HashMap<String, String>,
 
+    #[serde(default = "default_hash")]
+    // sample: {'src/main.py': 'backup/main.py'} - source and destination, applied after 'modify' and before 'create'
+    copy: collections::HashMap<String, String>,
+
     #[serde(default = "default_vec")]
-    // sample: ['matrix/executor.py', 'src/plain/main.py']
+    // sample: ['matrix/executor.py', 'src/plain/main.py', '**/*.pyc'] - glob patterns are expanded
     remove: Vec<String>,
 
+    #[serde(default)]
+    // sample: true - reject glob patterns in 'remove' that match nothing
+    remove_strict: bool,
+
     #[serde(default = "default_vec")]
     // sample: ['src/sample.png'] - since bytes can't be JSON encoded
     download: Vec<String>,
+
+    #[serde(default = "default_hash")]
+    // sample: {'assets/logo.png': 'https://cdn.example.com/logo.png'} - destination and the URL to download from
+    download_urls: collections::HashMap<String, String>,
+
+    #[serde(default = "default_hash")]
+    // sample: {'docs/README.md': '../README.md'} - link path and its target
+    symlink: collections::HashMap<String, String>,
+
+    #[serde(default = "default_permissions")]
+    // sample: {'scripts/run.sh': 493} - octal 0o755 as an integer
+    create_permissions: collections::HashMap<String, u32>,
+
+    #[serde(default = "default_owners")]
+    // sample: {'src/plain/main.py': [1000, 1000]} - owner and group IDs, applied only when running as root
+    create_owner: collections::HashMap<String, (u32, u32)>,
+
+    #[serde(default = "default_hash")]
+    // sample: {'scripts/run.bat': 'crlf'} - one of 'lf', 'crlf' or 'auto'
+    create_encoding: collections::HashMap<String, String>,
+
+    #[serde(default = "default_timestamps")]
+    // sample: {'src/plain/.keep': 1714521600} - modification time as Unix timestamp in seconds
+    create_timestamps: collections::HashMap<String, i64>,
+
+    #[serde(default = "default_hash")]
+    // sample: {'src/sample.png': 'sha256:9f86d08...'} - verified after the download
+    download_checksums: collections::HashMap<String, String>,
+
+    #[serde(default = "default_vec")]
+    // sample: ['releases/app.zip'] - downloads that are extracted in place, and then removed
+    download_unzip: Vec<String>,
+
+    #[serde(default = "default_hash")]
+    // sample: {'https://artifacts.example.com/private/': 'token'} - bearer token for the URLs that start with the prefix
+    download_tokens: collections::HashMap<String, String>,
+
+    #[serde(default = "default_fail_fast")]
+    // sample: false - attempt all the files in 'create', and report the failures together
+    fail_fast: bool,
+
+    #[serde(default = "default_skip_unchanged")]
+    // sample: false - rewrite files in 'create' even if their content is unchanged
+    skip_unchanged: bool,
+
+    #[serde(default = "default_download_overwrite")]
+    // sample: false - reject the backup if a file in 'download' or 'download_urls' already exists
+    download_overwrite: bool,
+
+    #[serde(default)]
+    // sample: true - store downloads by their hash, and hard link identical content instead of duplicating it
+    link_to_latest: bool,
+
+    #[serde(default = "default_hash")]
+    // sample: {'BUILD_URL': 'https://ci.example.com/42'} - replaces '{{BUILD_URL}}' in 'create', along with the built-ins
+    template_vars: collections::HashMap<String, String>,
 }
 
 fn default_vec() -> Vec<String> { Vec::new() }
 
+fn default_permissions() -> collections::HashMap<String, u32> { collections::HashMap::new() }
+
+fn default_owners() -> collections::HashMap<String, (u32, u32)> { collections::HashMap::new() }
+
+fn default_timestamps() -> collections::HashMap<String, i64> { collections::HashMap::new() }
+
 fn default_hash() -> collections::HashMap<String, String> { collections::HashMap::new() }
 
+fn default_skip_unchanged() -> bool { true }
 
-#[post("/backup")]
-pub async fn backup_endpoint(request: HttpRequest,
-                             payload: web::Json<Payload>,
-                             session: web::Data<sync::Arc<constant::Session>>,
-                             config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
-    squire::custom::log_connection(&request, &session);
-    let auth_response = routes::auth::verify_token(&request, &config);
-    if !auth_response.ok {
-        return HttpResponse::Unauthorized().finish();
+fn default_fail_fast() -> bool { true }
+
+fn default_download_overwrite() -> bool { true }
+
+/// Variables that are always available in templates, and cannot be overridden by `template_vars`
+const TEMPLATE_BUILTINS: [&str; 3] = ["TIMESTAMP", "REPOSITORY", "SERVER_VERSION"];
+
+/// Maximum length of a template variable's name.
+const MAX_TEMPLATE_VAR_LENGTH: usize = 64;
+
+/// Returns the pattern that template placeholders have to match, e.g. `{{BUILD_URL}}`
+fn template_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"\{\{([A-Z][A-Z0-9_]*)\}\}").unwrap())
+}
+
+/// Checks the names in `template_vars`, and the placeholders in the files to `create`
+///
+/// # Arguments
+///
+/// * `payload` - Payload received in the request.
+///
+/// ## See Also
+///
+/// - Built-ins are always available, so placeholders are checked even when `template_vars` is empty.
+/// - Only placeholders with names like `[A-Z][A-Z0-9_]*` are substituted, so `{{ name }}` is not a placeholder.
+///
+/// # Returns
+///
+/// Returns the boxed `HttpResponse` with `400` for invalid names, or `422` for placeholders that have no value.
+fn validate_templates(payload: &Payload) -> Result<(), Box<HttpResponse>> {
+    let template_vars = &payload.template_vars;
+    for name in template_vars.keys() {
+        let valid = name.len() <= MAX_TEMPLATE_VAR_LENGTH && template_pattern()
+            .captures(&format!("{{{{{}}}}}", name))
+            .is_some_and(|captures| &captures[1] == name);
+        if !valid || TEMPLATE_BUILTINS.contains(&name.as_str()) {
+            log::warn!("Invalid template variable '{}'", name);
+            return Err(Box::new(HttpResponse::BadRequest().json(format!("invalid template variable: {}", name))));
+        }
+    }
+    let mut unknown = Vec::new();
+    for (filepath, content) in &payload.create {
+        for captures in template_pattern().captures_iter(content) {
+            let name = &captures[1];
+            if !TEMPLATE_BUILTINS.contains(&name) && !template_vars.contains_key(name) {
+                unknown.push(serde_json::json!({"file": filepath, "variable": name}));
+            }
+        }
+    }
+    if !unknown.is_empty() {
+        log::warn!("Unknown template variables in {} placeholders", unknown.len());
+        return Err(Box::new(HttpResponse::UnprocessableEntity().json(serde_json::json!({
+            "error": "unknown_template_variable",
+            "placeholders": unknown
+        }))));
+    }
+    Ok(())
+}
+
+/// Substitutes the template variables in the content of a file.
+///
+/// # Arguments
+///
+/// * `content` - Content of the file in `create`
+/// * `variables` - Values of the variables, including the built-ins.
+///
+/// ## See Also
+///
+/// Placeholders are replaced in a single pass, so a value that contains a placeholder is written as-is.
+///
+/// # Returns
+///
+/// Returns the content with the placeholders replaced.
+fn render_template(content: &str, variables: &collections::HashMap<String, String>) -> String {
+    template_pattern().replace_all(content, |captures: &regex::Captures| {
+        variables.get(&captures[1]).cloned().unwrap_or_else(|| captures[0].to_string())
+    }).into_owned()
+}
+
+/// Reason an operation in the payload cannot be applied, along with the field and path it was found in.
+#[derive(Debug, Serialize)]
+struct OperationError {
+    field: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    path: String,
+    error: String,
+}
+
+impl OperationError {
+    fn new(field: impl Into<String>, path: &str, error: &str) -> Self {
+        OperationError { field: field.into(), path: path.to_string(), error: error.to_string() }
+    }
+}
+
+impl std::fmt::Display for OperationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Indices are dropped, so the message reads as the operation that failed
+        let operation = self.field.split('[').next().unwrap_or_default();
+        write!(f, "{} {}: {}", operation, self.error, self.path)
+    }
+}
+
+/// Validates that the operations in the payload can be applied, before any of them are executed.
+///
+/// # Arguments
+///
+/// * `payload` - Payload received in the request.
+/// * `repository` - Repository's root directory.
+///
+/// ## See Also
+///
+/// - Sources for `modify` must exist, since files are moved before any of them are created.
+/// - Sources for `copy` must exist, or be moved into place by `modify`.
+/// - Paths to `remove` must exist, or be created earlier in the same payload.
+/// - Repository's root directory and the `.git` directory cannot be removed.
+/// - Destinations for `copy`, `download` and `download_urls` must be within a directory, or a path where one can be created.
+/// - Glob patterns in `remove` are allowed to match nothing, unless `remove_strict` is set.
+///
+/// # Returns
+///
+/// Returns a list of all the validation errors.
+fn validate_operations(payload: &Payload, repository: &path::Path) -> Vec<OperationError> {
+    let exists = |filepath: &String| repository.join(filepath).symlink_metadata().is_ok();
+    // Nearest existing ancestor has to be a directory, for the rest of them to be created
+    let creatable = |filepath: &String| !repository.join(filepath).ancestors().skip(1)
+        .find(|ancestor| ancestor.exists())
+        .is_some_and(|ancestor| !ancestor.is_dir());
+    let mut errors = Vec::new();
+    for source in payload.modify.keys() {
+        if !exists(source) {
+            errors.push(OperationError::new("modify", source, "source not found"));
+        }
+    }
+    for (source, destination) in &payload.copy {
+        if !copy_source_available(payload, repository, source) {
+            errors.push(OperationError::new("copy", source, "source not found"));
+        }
+        if !creatable(destination) {
+            errors.push(OperationError::new("copy", destination, "destination cannot be created"));
+        }
+    }
+    for (index, removable) in payload.remove.iter().enumerate() {
+        let field = format!("remove[{}]", index);
+        if is_glob(removable) {
+            if payload.remove_strict && !glob_has_matches(payload, repository, removable) {
+                errors.push(OperationError::new(field, removable, "pattern has no matches"));
+            }
+            continue;
+        }
+        if routes::helper::is_protected_path(path::Path::new(removable)) {
+            errors.push(OperationError::new(field, removable, "path cannot be removed"));
+            continue;
+        }
+        let created = payload.create.contains_key(removable)
+            || payload.modify.values().any(|destination| destination == removable)
+            || payload.copy.values().any(|destination| destination == removable);
+        if !exists(removable) && !created {
+            errors.push(OperationError::new(field, removable, "path not found"));
+        }
+    }
+    for (index, downloadable) in payload.download.iter().enumerate() {
+        if !creatable(downloadable) {
+            errors.push(OperationError::new(format!("download[{}]", index), downloadable,
+                                            "destination cannot be created"));
+        }
+    }
+    for downloadable in payload.download_urls.keys() {
+        if !creatable(downloadable) {
+            errors.push(OperationError::new("download_urls", downloadable, "destination cannot be created"));
+        }
+    }
+    errors
+}
+
+/// Checks if the request only asks for the payload to be validated, through the `X-Pre-Validate` header.
+fn is_pre_validate(request: &HttpRequest) -> bool {
+    request.headers().get("X-Pre-Validate")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Checks the payload without executing it, so clients can find problems before sending large files.
+///
+/// # Arguments
+///
+/// * `auth_response` - Authentication response with the repository information.
+/// * `payload` - Payload received in the request.
+/// * `config` - Configuration data for the application.
+///
+/// ## See Also
+///
+/// - Checks the number of operations, free disk space, path validity, and the constraints in `validate_operations`.
+/// - Content of the files is not checked, and a repository that isn't cloned yet is treated as empty.
+///
+/// # Returns
+///
+/// Returns a list of all the validation errors.
+fn pre_validate(auth_response: &routes::auth::AuthResponse,
+                payload: &Payload,
+                config: &squire::settings::Config) -> Vec<OperationError> {
+    let mut errors = Vec::new();
+    let operations = operation_count(payload);
+    if operations > config.max_operations_per_backup {
+        errors.push(OperationError::new(
+            "operations", "", &format!("{} operations exceed the limit of {}", operations, config.max_operations_per_backup)
+        ));
+    }
+    if let Err(response) = check_free_space(config) {
+        errors.push(OperationError::new("operations", "", &format!("insufficient storage [{}]", response.status())));
+    }
+    let paths: Vec<(String, &String)> = payload.create.keys().map(|filepath| ("create".to_string(), filepath))
+        .chain(payload.modify.iter().flat_map(|(source, destination)| [("modify".to_string(), source), ("modify".to_string(), destination)]))
+        .chain(payload.copy.iter().flat_map(|(source, destination)| [("copy".to_string(), source), ("copy".to_string(), destination)]))
+        .chain(payload.remove.iter().enumerate().map(|(index, filepath)| (format!("remove[{}]", index), filepath)))
+        .chain(payload.download.iter().enumerate().map(|(index, filepath)| (format!("download[{}]", index), filepath)))
+        .chain(payload.download_urls.keys().map(|filepath| ("download_urls".to_string(), filepath)))
+        .chain(payload.symlink.keys().map(|filepath| ("symlink".to_string(), filepath)))
+        .collect();
+    let mut safe = true;
+    for (field, filepath) in &paths {
+        if !routes::helper::is_safe_path(filepath) {
+            errors.push(OperationError::new(field, filepath, "path is outside the repository"));
+            safe = false;
+        } else if exceeds_max_depth(filepath, config.max_path_depth) {
+            errors.push(OperationError::new(
+                field, filepath, &format!("path exceeds the maximum depth of {}", config.max_path_depth)
+            ));
+        }
+    }
+    // Paths outside the repository are never looked up on disk
+    if !safe {
+        return errors;
+    }
+    let repository = config.github_source.join(&auth_response.repository);
+    if repository.is_dir() {
+        for (field, filepath) in &paths {
+            if !routes::helper::resolves_within(&repository, &repository.join(filepath)) {
+                errors.push(OperationError::new(field, filepath, "path resolves outside the repository"));
+            }
+        }
+        for (link, target) in &payload.symlink {
+            if !routes::helper::is_safe_link_target(&repository, &repository.join(link), target) {
+                errors.push(OperationError::new("symlink", link, "target is outside the repository"));
+            }
+        }
+    }
+    errors.extend(validate_operations(payload, &repository));
+    errors
+}
+
+/// Counts the operations in the payload, that are limited by `max_operations_per_backup`
+fn operation_count(payload: &Payload) -> usize {
+    payload.create.len() + payload.modify.len() + payload.copy.len() + payload.remove.len()
+        + payload.download.len() + payload.download_urls.len() + payload.symlink.len()
+}
+
+/// Rejects a payload with more operations than allowed in a single backup.
+///
+/// # Arguments
+///
+/// * `payload` - Payload received in the request.
+/// * `repository` - Name of the repository, used in the logs.
+/// * `limit` - Maximum number of operations allowed.
+///
+/// # Returns
+///
+/// Returns the boxed `HttpResponse` with `400 Bad Request`, if the limit is exceeded.
+fn check_operation_limit(payload: &Payload, repository: &str, limit: usize) -> Result<(), Box<HttpResponse>> {
+    let operations = operation_count(payload);
+    if operations > limit {
+        log::warn!("Backup for '{}' has {} operations, exceeding the limit of {}", repository, operations, limit);
+        return Err(Box::new(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "too_many_operations",
+            "count": operations,
+            "limit": limit
+        }))));
+    }
+    Ok(())
+}
+
+/// Checks if a path has more components than allowed by `max_path_depth`
+fn exceeds_max_depth(filepath: &str, max_path_depth: usize) -> bool {
+    path::Path::new(filepath).components().count() > max_path_depth
+}
+
+/// Checks if the source of a copy exists and is not moved away, or is moved into place earlier in the same payload.
+fn copy_source_available(payload: &Payload, repository: &path::Path, source: &String) -> bool {
+    (repository.join(source).is_file() && !payload.modify.contains_key(source))
+        || payload.modify.values().any(|destination| destination == source)
+}
+
+/// Rejects the backup when the data source is running out of disk space, before any operation begins.
+///
+/// # Arguments
+///
+/// * `config` - Configuration data for the application.
+///
+/// ## See Also
+///
+/// The backup is allowed to proceed if the available space cannot be determined.
+///
+/// # Returns
+///
+/// Returns the boxed `HttpResponse` with `507 Insufficient Storage`, if the backup should not proceed.
+fn check_free_space(config: &squire::settings::Config) -> Result<(), Box<HttpResponse>> {
+    match routes::helper::check_disk_space(&config.github_source) {
+        Ok(available) if available < config.min_free_disk_bytes => {
+            log::error!("Only {} bytes available in {:?}, the minimum is {} bytes",
+                available, config.github_source, config.min_free_disk_bytes);
+            Err(Box::new(HttpResponse::InsufficientStorage().json(serde_json::json!({
+                "available_bytes": available,
+                "minimum_bytes": config.min_free_disk_bytes
+            }))))
+        }
+        Ok(_) => Ok(()),
+        Err(err) => {
+            log::warn!("Unable to check the available disk space: {}", err);
+            Ok(())
+        }
+    }
+}
+
+/// Checks if an entry in `remove` is a glob pattern, instead of a literal path.
+fn is_glob(removable: &str) -> bool {
+    removable.contains(['*', '?', '['])
+}
+
+/// Checks if a glob pattern matches an existing path, or a path created earlier in the same payload.
+///
+/// # Arguments
+///
+/// * `payload` - Payload received in the request.
+/// * `repository` - Repository's root directory.
+/// * `pattern` - Glob pattern relative to the repository.
+///
+/// # Returns
+///
+/// Returns `true` if the pattern matches at least one path.
+fn glob_has_matches(payload: &Payload, repository: &path::Path, pattern: &str) -> bool {
+    let created = match glob::Pattern::new(pattern) {
+        Ok(compiled) => payload.create.keys()
+            .chain(payload.modify.values())
+            .any(|filepath| compiled.matches(filepath)),
+        Err(_) => false
+    };
+    created || expand_glob(repository, pattern).is_ok_and(|matches| !matches.is_empty())
+}
+
+/// Expands a glob pattern into the paths it matches within the repository.
+///
+/// # Arguments
+///
+/// * `repository` - Repository's root directory.
+/// * `pattern` - Glob pattern relative to the repository.
+///
+/// ## See Also
+///
+/// - Paths within `.git` are never matched, so a broad pattern like `**/*` cannot corrupt the repository.
+/// - Paths that resolve outside the repository through a symbolic link are skipped.
+///
+/// # Returns
+///
+/// Returns the matched paths, or the error if the pattern is invalid.
+fn expand_glob(repository: &path::Path, pattern: &str) -> Result<Vec<path::PathBuf>, String> {
+    let absolute = format!("{}/{}", glob::Pattern::escape(&repository.to_string_lossy()), pattern);
+    let entries = glob::glob(&absolute)
+        .map_err(|err| format!("invalid pattern '{}': {}", pattern, err))?;
+    let mut matches = Vec::new();
+    for entry in entries.flatten() {
+        let relative = match entry.strip_prefix(repository) {
+            Ok(relative) => relative,
+            Err(_) => continue
+        };
+        if relative.components().any(|component| component.as_os_str() == ".git") {
+            continue;
+        }
+        if routes::helper::resolves_within(repository, &entry) {
+            matches.push(entry);
+        } else {
+            log::warn!("Skipping {:?} since it resolves outside the repository", entry);
+        }
+    }
+    Ok(matches)
+}
+
+/// Writes the content to a partial file, and moves it to the destination once it is complete.
+///
+/// # Arguments
+///
+/// * `payload` - Payload with the encoding, permissions and timestamps for the file.
+/// * `filepath` - Path of the file relative to the repository.
+/// * `content` - Content to be written.
+/// * `destination` - Absolute path of the file.
+/// * `retries` - Number of times each filesystem operation is retried on transient errors.
+///
+/// ## See Also
+///
+/// The partial file is removed on failure, so an interrupted write never replaces the existing file.
+/// Parent directories are created as needed.
+///
+/// # Returns
+///
+/// Returns an error message if any of the steps fail.
+async fn write_file(payload: &Payload,
+                    filepath: &str,
+                    content: &str,
+                    destination: &path::Path,
+                    retries: u32) -> Result<(), String> {
+    let partial = squire::partial::partial_path(destination);
+    let result = async {
+        // Creates all the directories along the way, right before the file so nothing can remove them in between
+        if let Some(parent) = partial.parent() {
+            routes::helper::retry_transient(retries, || fs::create_dir_all(parent)).await
+                .map_err(|err| format!("Error creating directories: {}", err))?;
+        }
+        let content = match payload.create_encoding.get(filepath) {
+            Some(mode) => squire::encoding::normalize_line_endings(content, mode),
+            None => content.to_string()
+        };
+        routes::helper::retry_transient(retries, || {
+            squire::partial::create(&partial).and_then(|mut file| file.write_all(content.as_bytes()))
+        }).await
+            .map_err(|err| format!("Error writing to file: {}", err))?;
+        finalize_file(payload, filepath, &partial, destination, retries).await
+    }.await;
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+/// Checks if a file in `create` already exists on disk with the same content.
+///
+/// # Arguments
+///
+/// * `payload` - Payload with the encoding, permissions and timestamps for the file.
+/// * `filepath` - Path of the file relative to the repository.
+/// * `content` - Content to be written.
+/// * `destination` - Absolute path of the file.
+/// * `manifest` - Manifest of the repository, with the hashes of the files written by earlier backups.
+///
+/// ## See Also
+///
+/// - Empty files are always overwritten.
+/// - Files with permissions, an owner or a timestamp in the payload are always written, so that those are applied.
+/// - Existing files are hashed only if they were changed after the manifest recorded them.
+///
+/// # Returns
+///
+/// Returns a boolean flag to indicate if writing the file can be skipped.
+fn is_unchanged(payload: &Payload,
+                filepath: &String,
+                content: &str,
+                destination: &path::Path,
+                manifest: &squire::manifest::Manifest) -> bool {
+    if payload.create_permissions.contains_key(filepath)
+        || payload.create_owner.contains_key(filepath)
+        || payload.create_timestamps.contains_key(filepath) {
+        return false;
     }
-    if auth_response.repository.is_empty() {
-        log::warn!("'content-location' header is invalid");
-        return HttpResponse::BadRequest().json("'content-location' header is invalid");
+    let content = match payload.create_encoding.get(filepath) {
+        Some(mode) => squire::encoding::normalize_line_endings(content, mode),
+        None => content.to_string()
+    };
+    if content.is_empty() {
+        return false;
+    }
+    let expected = format!("{:x}", Sha256::digest(content.as_bytes()));
+    if let Some(recorded) = squire::manifest::recorded_hash(manifest, filepath, destination) {
+        return recorded == expected;
+    }
+    match fs::read(destination) {
+        Ok(existing) if !existing.is_empty() => format!("{:x}", Sha256::digest(&existing)) == expected,
+        _ => false
+    }
+}
+
+/// Sets the permissions, owner and modification time on a partial file, and moves it to the destination.
+///
+/// # Arguments
+///
+/// * `payload` - Payload with the permissions, owners and timestamps for the file.
+/// * `filepath` - Path of the file relative to the repository.
+/// * `partial` - Partial file with the complete content.
+/// * `destination` - Absolute path of the file.
+/// * `retries` - Number of times moving the file is retried on transient errors.
+///
+/// # Returns
+///
+/// Returns an error message if any of the steps fail.
+async fn finalize_file(payload: &Payload,
+                       filepath: &str,
+                       partial: &path::Path,
+                       destination: &path::Path,
+                       retries: u32) -> Result<(), String> {
+    if let Some(mode) = payload.create_permissions.get(filepath) {
+        routes::helper::set_permissions(partial, *mode)
+            .map_err(|err| format!("Error setting permissions: {}", err))?;
+    }
+    if let Some((uid, gid)) = payload.create_owner.get(filepath) {
+        routes::helper::set_owner(partial, *uid, *gid)
+            .map_err(|err| format!("Error setting owner: {}", err))?;
+    }
+    if let Some(timestamp) = payload.create_timestamps.get(filepath) {
+        let mtime = filetime::FileTime::from_unix_time(*timestamp, 0);
+        filetime::set_file_mtime(partial, mtime)
+            .map_err(|err| format!("Error setting modification time: {}", err))?;
+    }
+    routes::helper::retry_transient(retries, || fs::rename(partial, destination)).await
+        .map_err(|err| format!("Error moving file into place: {}", err))
+}
+
+/// Computes the hash of a payload, to detect the same payload being received again.
+fn payload_hash(payload: &Payload) -> String {
+    // Converting to a Value sorts the map keys, so the hash is the same for identical payloads
+    let canonical = serde_json::to_value(payload)
+        .and_then(|value| serde_json::to_vec(&value))
+        .unwrap_or_default();
+    format!("{:x}", Sha256::digest(&canonical))
+}
+
+/// Checks if the same payload was applied to a repository within the deduplication window.
+///
+/// # Arguments
+///
+/// * `payload_hash` - Hash of the payload received in the request.
+/// * `repository` - Name of the repository.
+/// * `session` - Session struct that holds the recently applied payloads.
+/// * `window` - Duration within which an identical payload is considered a duplicate.
+///
+/// ## See Also
+///
+/// Payloads are recorded with `record_payload` only once they are applied successfully,
+/// so that a retry after a failed attempt is executed again.
+///
+/// # Returns
+///
+/// Returns a boolean value to indicate whether the payload is a duplicate.
+fn is_duplicate(payload_hash: &str,
+                repository: &str,
+                session: &constant::Session,
+                window: time::Duration) -> bool {
+    let now = time::Instant::now();
+    session.recent_payloads.retain(|_, received| now.duration_since(*received) < window);
+    session.recent_payloads.contains_key(&(repository.to_string(), payload_hash.to_string()))
+}
+
+/// Records a payload that was applied successfully, so that it is skipped if received again within the window.
+fn record_payload(payload_hash: String, repository: &str, session: &constant::Session) {
+    session.recent_payloads.insert((repository.to_string(), payload_hash), time::Instant::now());
+}
+
+/// Validates the payload, and the repository it applies to, before any file is modified.
+///
+/// # Arguments
+///
+/// * `auth_response` - Authentication response with the repository information.
+/// * `payload` - Payload received in the request.
+/// * `payload_hash` - Hash of the payload, to skip payloads that were applied within the deduplication window.
+/// * `session` - Session struct that holds the recently received payloads and cached repository sizes.
+/// * `config` - Configuration data for the application.
+///
+/// # Returns
+///
+/// Returns the boxed `HttpResponse` to return early, if the backup should not proceed.
+async fn prepare(auth_response: &routes::auth::AuthResponse,
+                 payload: &Payload,
+                 payload_hash: Option<&str>,
+                 session: &constant::Session,
+                 config: &squire::settings::Config) -> Result<(), Box<HttpResponse>> {
+    check_operation_limit(payload, &auth_response.repository, config.max_operations_per_backup)?;
+    for filepath in payload.create.keys()
+        .chain(payload.modify.keys())
+        .chain(payload.modify.values())
+        .chain(payload.copy.keys())
+        .chain(payload.copy.values())
+        .chain(payload.remove.iter())
+        .chain(payload.download.iter())
+        .chain(payload.download_urls.keys())
+        .chain(payload.symlink.keys()) {
+        if exceeds_max_depth(filepath, config.max_path_depth) {
+            log::warn!("Path '{}' exceeds the maximum depth of {}", filepath, config.max_path_depth);
+            return Err(Box::new(HttpResponse::BadRequest().json(
+                format!("path exceeds the maximum depth of {}: {}", config.max_path_depth, filepath)
+            )));
+        }
+    }
+    let window = time::Duration::from_millis(config.dedup_window_ms);
+    if payload_hash.is_some_and(|payload_hash| is_duplicate(payload_hash, &auth_response.repository, session, window)) {
+        log::info!("Duplicate payload received for '{}', skipping", &auth_response.repository);
+        return Err(Box::new(HttpResponse::Ok().finish()));
     }
     let repo_validation = routes::helper::validate_repo(
-        &auth_response.repository, &config.github_source,
-    );
+        auth_response, config, session,
+    ).await;
     if !repo_validation.ok {
-        return HttpResponse::BadRequest().json("unable to locate or clone repository in data source");
+        return Err(Box::new(HttpResponse::BadRequest().json("unable to locate or clone repository in data source")));
+    }
+    if repo_validation.revalidated {
+        log::warn!("Repository '{}' was recovered from an incomplete clone", &auth_response.repository);
     }
     if repo_validation.cloned {
         log::info!("Repository '{}' was cloned, so no point in proceeding further", &auth_response.repository);
-        return HttpResponse::Ok().finish();
+        return Err(Box::new(HttpResponse::Ok().finish()));
     }
 
+    let repository = config.github_source.join(&auth_response.repository);
+    if !payload.symlink.is_empty() && cfg!(not(unix)) {
+        log::warn!("Symbolic links are not supported on this platform");
+        return Err(Box::new(HttpResponse::NotImplemented().json("symbolic links are not supported on this platform")));
+    }
+    if !payload.create_owner.is_empty() && cfg!(not(unix)) {
+        log::warn!("File ownership is not supported on this platform");
+        return Err(Box::new(HttpResponse::NotImplemented().json("file ownership is not supported on this platform")));
+    }
+    for filepath in payload.create.keys()
+        .chain(payload.create_permissions.keys())
+        .chain(payload.create_owner.keys())
+        .chain(payload.create_encoding.keys())
+        .chain(payload.create_timestamps.keys())
+        .chain(payload.symlink.keys())
+        .chain(payload.modify.keys())
+        .chain(payload.modify.values())
+        .chain(payload.copy.keys())
+        .chain(payload.copy.values())
+        .chain(payload.remove.iter())
+        .chain(payload.download.iter())
+        .chain(payload.download_urls.keys()) {
+        if !routes::helper::is_safe_path(filepath) {
+            log::warn!("Path '{}' is outside the repository", filepath);
+            return Err(Box::new(HttpResponse::BadRequest().json(format!("invalid path: {}", filepath))));
+        }
+        // Symbolic links in the existing directories are followed, which the path text alone cannot reveal
+        if !routes::helper::resolves_within(&repository, &repository.join(filepath)) {
+            log::warn!("Path '{}' resolves outside the repository", filepath);
+            return Err(Box::new(HttpResponse::BadRequest().json(format!("invalid path: {}", filepath))));
+        }
+    }
+    for (link, target) in &payload.symlink {
+        if !routes::helper::is_safe_link_target(&repository, &repository.join(link), target) {
+            log::warn!("Symlink target '{}' for '{}' is outside the repository", target, link);
+            return Err(Box::new(HttpResponse::BadRequest().json(format!("invalid symlink target for {}: {}", link, target))));
+        }
+    }
+    for (filepath, mode) in &payload.create_permissions {
+        if *mode > 0o777 {
+            log::warn!("Invalid permissions {:o} for '{}'", mode, filepath);
+            return Err(Box::new(HttpResponse::BadRequest().json(format!("invalid permissions for {}: {:o}", filepath, mode))));
+        }
+    }
+
+    for (filepath, timestamp) in &payload.create_timestamps {
+        if *timestamp <= 0 || *timestamp > u32::MAX as i64 {
+            log::warn!("Invalid timestamp {} for '{}'", timestamp, filepath);
+            return Err(Box::new(HttpResponse::BadRequest().json(format!("invalid timestamp for {}: {}", filepath, timestamp))));
+        }
+    }
+    for (filepath, url) in &payload.download_urls {
+        let host = match reqwest::Url::parse(url) {
+            Ok(parsed) if parsed.scheme() == "https" => parsed.host_str().map(str::to_lowercase),
+            _ => {
+                log::warn!("Invalid download URL '{}' for '{}'", url, filepath);
+                return Err(Box::new(HttpResponse::BadRequest().json(format!("invalid download URL for {}: {}", filepath, url))));
+            }
+        };
+        let allowed = host.as_ref().is_some_and(|host| {
+            config.download_allowed_domains.iter().any(|domain| domain.eq_ignore_ascii_case(host))
+        });
+        if !allowed {
+            log::warn!("Download URL '{}' for '{}' is not in the allowed domains", url, filepath);
+            return Err(Box::new(HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "domain_not_allowed",
+                "file": filepath,
+                "host": host
+            }))));
+        }
+    }
+    validate_templates(payload)?;
+    for archive in &payload.download_unzip {
+        if !payload.download.contains(archive) && !payload.download_urls.contains_key(archive) {
+            log::warn!("Archive '{}' to unzip is not downloaded in the same payload", archive);
+            return Err(Box::new(HttpResponse::BadRequest().json(format!("unzip path is not a download: {}", archive))));
+        }
+    }
+    // Tokens are never sent in plain text, and never logged
+    for prefix in payload.download_tokens.keys() {
+        if !prefix.starts_with("https://") {
+            log::warn!("Invalid download token prefix '{}'", prefix);
+            return Err(Box::new(HttpResponse::BadRequest().json(format!("download token prefix must be an https URL: {}", prefix))));
+        }
+    }
+    for (filepath, checksum) in &payload.download_checksums {
+        let valid = checksum.strip_prefix("sha256:")
+            .is_some_and(|digest| digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()));
+        if !valid {
+            log::warn!("Invalid checksum '{}' for '{}'", checksum, filepath);
+            return Err(Box::new(HttpResponse::BadRequest().json(format!("invalid checksum for {}: {}", filepath, checksum))));
+        }
+    }
+    for (filepath, mode) in &payload.create_encoding {
+        if !squire::encoding::MODES.contains(&mode.as_str()) {
+            log::warn!("Invalid encoding '{}' for '{}'", mode, filepath);
+            return Err(Box::new(HttpResponse::BadRequest().json(format!("invalid encoding for {}: {}", filepath, mode))));
+        }
+    }
+    let repository = config.github_source.join(&auth_response.repository);
+    let missing: Vec<&String> = payload.copy.keys()
+        .filter(|source| !copy_source_available(payload, &repository, source))
+        .collect();
+    if !missing.is_empty() {
+        log::warn!("Copy sources not found for '{}': {:?}", &auth_response.repository, missing);
+        return Err(Box::new(HttpResponse::UnprocessableEntity().json(serde_json::json!({
+            "error": "copy_source_not_found",
+            "sources": missing
+        }))));
+    }
+    let errors = validate_operations(payload, &repository);
+    if !errors.is_empty() {
+        log::warn!("Backup for '{}' failed validation with {} errors", &auth_response.repository, errors.len());
+        let errors: Vec<String> = errors.iter().map(OperationError::to_string).collect();
+        return Err(Box::new(HttpResponse::BadRequest().json(errors)));
+    }
+    if !payload.download_overwrite {
+        let conflicts: Vec<&String> = payload.download.iter()
+            .chain(payload.download_urls.keys())
+            .filter(|downloadable| repository.join(downloadable).exists())
+            .collect();
+        if !conflicts.is_empty() {
+            log::warn!("Downloads for '{}' already exist: {:?}", &auth_response.repository, conflicts);
+            return Err(Box::new(HttpResponse::Conflict().json(serde_json::json!({
+                "conflicts": conflicts
+            }))));
+        }
+    }
+    Ok(())
+}
+
+/// Number of attempts to deliver the backup notification, when the receiver cannot be reached.
+const NOTIFICATION_ATTEMPTS: u32 = 3;
+
+/// Maximum duration (in seconds) a backup can be deferred with the `X-Execute-After` header.
+const MAX_SCHEDULE_DELAY_SECS: i64 = 24 * 60 * 60;
+
+/// Duration (in seconds) after its execution time, beyond which a scheduled backup is dropped.
+const SCHEDULE_EXPIRY_SECS: i64 = 5 * 60;
+
+/// Counter to order the backups that are scheduled for the same time, in the order they were received.
+static SCHEDULE_SEQUENCE: atomic::AtomicU64 = atomic::AtomicU64::new(0);
+
+/// Backup that was deferred with the `X-Execute-After` header.
+///
+/// ## See Also
+///
+/// Ordering is reversed, so that the `BinaryHeap` yields the earliest execution time first.
+pub struct Scheduled {
+    id: String,
+    sequence: u64,
+    execute_after: i64,
+    auth_response: routes::auth::AuthResponse,
+    payload: Payload,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.execute_after == other.execute_after && self.sequence == other.sequence
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        other.execute_after.cmp(&self.execute_after)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Converts a Unix timestamp into an ISO 8601 string.
+fn iso_8601(timestamp: i64) -> String {
+    DateTime::<Utc>::from_timestamp(timestamp, 0)
+        .map(|datetime| datetime.to_rfc3339())
+        .unwrap_or_default()
+}
+
+/// Reads the `X-Execute-After` header, to defer the backup until the given Unix timestamp.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+///
+/// # Returns
+///
+/// Returns the timestamp if it is in the future, `None` if the backup should run right away,
+/// or the boxed `HttpResponse` for an invalid header.
+fn execute_after(request: &HttpRequest) -> Result<Option<i64>, Box<HttpResponse>> {
+    let value = match request.headers().get("X-Execute-After") {
+        Some(value) => value,
+        None => return Ok(None)
+    };
+    let timestamp = match value.to_str().ok().and_then(|value| value.trim().parse::<i64>().ok()) {
+        Some(timestamp) => timestamp,
+        None => {
+            log::warn!("Invalid X-Execute-After header: {:?}", value);
+            return Err(Box::new(HttpResponse::BadRequest().json("X-Execute-After should be a Unix timestamp in seconds")));
+        }
+    };
+    let now = Utc::now().timestamp();
+    if timestamp <= now {
+        return Ok(None);
+    }
+    if timestamp - now > MAX_SCHEDULE_DELAY_SECS {
+        log::warn!("X-Execute-After {} is more than 24 hours away", timestamp);
+        return Err(Box::new(HttpResponse::BadRequest().json("X-Execute-After should be within the next 24 hours")));
+    }
+    Ok(Some(timestamp))
+}
+
+/// Validates and applies the payload, while the repository lock is held by the caller.
+///
+/// # Arguments
+///
+/// * `auth_response` - Authentication response with the repository information.
+/// * `payload` - Payload received in the request.
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+/// * `started` - Time when the backup started, to report the duration.
+///
+/// ## See Also
+///
+/// With `fail_fast` disabled, all the files in `create` are attempted, and the failures are reported with `207`
+///
+/// # Returns
+///
+/// Returns the `HttpResponse` for the backup.
+async fn apply(auth_response: &routes::auth::AuthResponse,
+               payload: &Payload,
+               session: &constant::Session,
+               config: &squire::settings::Config,
+               started: time::Instant) -> HttpResponse {
+    let payload_hash = payload_hash(payload);
+    if let Err(response) = prepare(auth_response, payload, Some(&payload_hash), session, config).await {
+        return *response;
+    }
+    if let Err(response) = relocate(auth_response, payload, session, config).await {
+        return *response;
+    }
+    let mut unchanged = Vec::new();
+    let mut written = Vec::new();
+    let mut failed = collections::HashMap::new();
+    let manifest = if payload.skip_unchanged && !payload.create.is_empty() {
+        squire::manifest::load(&config.github_source.join(&auth_response.repository))
+    } else {
+        squire::manifest::Manifest::default()
+    };
+    // Built-ins are set last, so that they cannot be overridden, and the timestamp is the same for all the files
+    let mut template_vars = payload.template_vars.clone();
+    template_vars.insert("TIMESTAMP".to_string(), Utc::now().to_rfc3339());
+    template_vars.insert("REPOSITORY".to_string(), auth_response.repository.to_string());
+    template_vars.insert("SERVER_VERSION".to_string(), env!("CARGO_PKG_VERSION").to_string());
     for (filepath, content) in &payload.create {
         let true_path = &config.github_source
             .join(&auth_response.repository)
             .join(filepath);
+        let content = &render_template(content, &template_vars);
 
-        // Creates all the directories along the way
-        if let Some(parent) = true_path.parent() {
-            if let Err(err) = fs::create_dir_all(parent) {
-                let error = format!("Error creating directories: {}", err);
-                log::error!("{}", error);
-                return routes::helper::fallback_clone(&config.github_source,
-                                                      &auth_response.repository,
-                                                      HttpResponse::ExpectationFailed().json(error));
+        if payload.skip_unchanged && is_unchanged(payload, filepath, content, true_path, &manifest) {
+            log::info!("Skipped unchanged file {}", filepath);
+            unchanged.push(filepath);
+            continue;
+        }
+        if let Ok(metadata) = fs::metadata(true_path) {
+            log::warn!("Overwriting {:?} [{} bytes]", true_path, metadata.len());
+        }
+        if let Err(error) = write_file(payload, filepath, content, true_path, config.io_retry_count).await {
+            log::error!("{}", error);
+            if payload.fail_fast {
+                return routes::helper::fallback_clone(config,
+                                                      session,
+                                                      auth_response,
+                                                      StatusCode::EXPECTATION_FAILED,
+                                                      error.into()).await.1;
             }
+            failed.insert(filepath, error);
+            continue;
+        }
+        log::info!("File content has been updated for {:?}", true_path);
+        written.push(filepath);
+    }
+    if !failed.is_empty() {
+        log::warn!("{} of {} files could not be created", failed.len(), payload.create.len());
+        let mut body = serde_json::json!({
+            "succeeded": written,
+            "failed": failed
+        });
+        // Outcome of each file is reported instead of the clone's, along with whether the repository was recovered
+        let (recovered, _) = routes::helper::fallback_clone(config,
+                                                            session,
+                                                            auth_response,
+                                                            StatusCode::MULTI_STATUS,
+                                                            body.clone()).await;
+        body["recovered"] = serde_json::Value::Bool(recovered);
+        return HttpResponse::build(StatusCode::MULTI_STATUS).json(body);
+    }
+    if !unchanged.is_empty() {
+        session.skipped_unchanged.fetch_add(unchanged.len() as u64, atomic::Ordering::SeqCst);
+    }
+    let response = finish(auth_response, payload, session, config, started, &unchanged).await;
+    if response.status() == StatusCode::OK {
+        record_payload(payload_hash, &auth_response.repository, session);
+    }
+    response
+}
+
+#[post("/backup")]
+pub async fn backup_endpoint(request: HttpRequest,
+                             payload: squire::extractor::MsgpackOrJson<Payload>,
+                             session: web::Data<sync::Arc<constant::Session>>,
+                             config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let started = time::Instant::now();
+    let _guard = session.track_request();
+    squire::custom::log_connection(&request, &session, &config);
+    let auth_response = match routes::auth::authorize(&request, &config).await {
+        Ok(auth_response) => auth_response,
+        Err(response) => return *response
+    };
+    if is_pre_validate(&request) {
+        let errors = pre_validate(&auth_response, &payload, &config);
+        if errors.is_empty() {
+            return HttpResponse::Ok().json(serde_json::json!({"valid": true}));
+        }
+        log::info!("Pre-validation for '{}' found {} errors", &auth_response.repository, errors.len());
+        return HttpResponse::BadRequest().json(serde_json::json!({"valid": false, "errors": errors}));
+    }
+    match execute_after(&request) {
+        Ok(Some(timestamp)) => {
+            let scheduled = Scheduled {
+                id: uuid::Uuid::new_v4().to_string(),
+                sequence: SCHEDULE_SEQUENCE.fetch_add(1, atomic::Ordering::SeqCst),
+                execute_after: timestamp,
+                auth_response,
+                payload: payload.0,
+            };
+            let mut queue = session.scheduled.lock().unwrap();
+            // Entries scheduled for the same time run in the order they were received, so this one goes after them
+            let queue_position = queue.iter().filter(|queued| queued.execute_after <= timestamp).count() + 1;
+            log::info!("Backup for '{}' scheduled for {} at position {}",
+                scheduled.auth_response.repository, iso_8601(timestamp), queue_position);
+            queue.push(scheduled);
+            return HttpResponse::Accepted().json(serde_json::json!({
+                "scheduled_for": iso_8601(timestamp),
+                "queue_position": queue_position
+            }));
         }
+        Ok(None) => (),
+        Err(response) => return *response
+    }
+    if let Err(response) = check_free_space(&config) {
+        return *response;
+    }
+    let _lock = match routes::helper::acquire_repo_lock(&request, &session, &auth_response.repository).await {
+        Ok(guard) => guard,
+        Err(response) => return *response
+    };
+    apply(&auth_response, &payload, &session, &config, started).await
+}
 
-        let mut file = match fs::File::create(true_path) {
-            Ok(file_buf) => file_buf,
+/// Runs the scheduled backups when their time arrives, for as long as the server is running.
+///
+/// # Arguments
+///
+/// * `session` - Session struct that holds the scheduled backups.
+/// * `config` - Configuration data for the application.
+///
+/// ## See Also
+///
+/// Backups that are more than 5 minutes past their execution time are dropped, instead of being applied late.
+pub async fn process_scheduled(session: sync::Arc<constant::Session>,
+                               config: sync::Arc<squire::settings::Config>) {
+    loop {
+        tokio::time::sleep(time::Duration::from_secs(1)).await;
+        let now = Utc::now().timestamp();
+        loop {
+            let due = {
+                let mut queue = session.scheduled.lock().unwrap();
+                match queue.peek() {
+                    Some(next) if next.execute_after <= now => queue.pop(),
+                    _ => None
+                }
+            };
+            let scheduled = match due {
+                Some(scheduled) => scheduled,
+                None => break
+            };
+            let repository = &scheduled.auth_response.repository;
+            if now - scheduled.execute_after > SCHEDULE_EXPIRY_SECS {
+                log::warn!("Dropping backup '{}' for '{}', scheduled for {}",
+                    scheduled.id, repository, iso_8601(scheduled.execute_after));
+                continue;
+            }
+            log::info!("Running backup '{}' for '{}', scheduled for {}",
+                scheduled.id, repository, iso_8601(scheduled.execute_after));
+            if let Err(response) = check_free_space(&config) {
+                log::error!("Skipping backup '{}' for '{}' with status {}", scheduled.id, repository, response.status());
+                continue;
+            }
+            let _lock = session.lock_repo(repository).await;
+            let response = apply(&scheduled.auth_response, &scheduled.payload, &session, &config, time::Instant::now()).await;
+            log::info!("Scheduled backup '{}' for '{}' finished with status {}", scheduled.id, repository, response.status());
+        }
+    }
+}
+
+/// Lists the backups that are waiting for their scheduled time.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `session` - Session struct that holds the scheduled backups.
+/// * `config` - Configuration data for the application.
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the scheduled backups, ordered by their execution time.
+#[get("/backup/scheduled", wrap = "squire::middleware::AdminIpFilter")]
+pub async fn backup_scheduled_endpoint(request: HttpRequest,
+                                       session: web::Data<sync::Arc<constant::Session>>,
+                                       config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let _guard = session.track_request();
+    squire::custom::log_connection(&request, &session, &config);
+    let auth_response = routes::auth::verify_token(&request, &config).await;
+    if !auth_response.ok {
+        return HttpResponse::Unauthorized().finish();
+    }
+    // Installation tokens are limited to their repositories, while these entries span all of them
+    if auth_response.repositories.is_some() {
+        return HttpResponse::Forbidden().json("installation tokens cannot access this endpoint");
+    }
+    let mut queue: Vec<&Scheduled> = Vec::new();
+    let scheduled = session.scheduled.lock().unwrap();
+    queue.extend(scheduled.iter());
+    // Reversed ordering puts the earliest execution time last, so the sort is reversed as well
+    queue.sort_by(|a, b| b.cmp(a));
+    let entries: Vec<serde_json::Value> = queue.iter().enumerate()
+        .map(|(index, queued)| serde_json::json!({
+            "id": queued.id,
+            "repository": queued.auth_response.repository,
+            "branch": queued.auth_response.branch,
+            "scheduled_for": iso_8601(queued.execute_after),
+            "queue_position": index + 1
+        }))
+        .collect();
+    HttpResponse::Ok().json(entries)
+}
+
+/// Streams the files to be created as `multipart/form-data`, instead of embedding them in a JSON body.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `multipart` - Multipart stream with the `metadata` part first, followed by one part per file named by its path.
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+///
+/// ## See Also
+///
+/// - The `metadata` part is the same payload as `/backup`, with the paths in `create` mapped to empty strings.
+/// - Each file is written to disk as it is received, so the content is never held in memory entirely.
+///
+/// # Returns
+///
+/// Returns the same `HttpResponse` as `/backup`
+#[post("/backup/stream")]
+pub async fn backup_stream_endpoint(request: HttpRequest,
+                                    mut multipart: Multipart,
+                                    session: web::Data<sync::Arc<constant::Session>>,
+                                    config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let started = time::Instant::now();
+    let _guard = session.track_request();
+    squire::custom::log_connection(&request, &session, &config);
+    let auth_response = match routes::auth::authorize(&request, &config).await {
+        Ok(auth_response) => auth_response,
+        Err(response) => return *response
+    };
+    if let Err(response) = check_free_space(&config) {
+        return *response;
+    }
+    let payload = match multipart.next().await {
+        Some(Ok(mut field)) if field.name() == "metadata" => {
+            let mut metadata = Vec::new();
+            while let Some(chunk) = field.next().await {
+                match chunk {
+                    Ok(bytes) if metadata.len() + bytes.len() <= config.max_payload_size => metadata.extend_from_slice(&bytes),
+                    Ok(_) => return HttpResponse::PayloadTooLarge().json("metadata exceeds the maximum payload size"),
+                    Err(err) => return HttpResponse::BadRequest().json(format!("unable to read metadata: {}", err))
+                }
+            }
+            match serde_json::from_slice::<Payload>(&metadata) {
+                Ok(payload) => payload,
+                Err(err) => return HttpResponse::BadRequest().json(format!("invalid metadata: {}", err))
+            }
+        }
+        _ => return HttpResponse::BadRequest().json("'metadata' should be the first part")
+    };
+    if !payload.create_encoding.is_empty() {
+        return HttpResponse::BadRequest().json("create_encoding is not supported for streamed files");
+    }
+    let _lock = match routes::helper::acquire_repo_lock(&request, &session, &auth_response.repository).await {
+        Ok(guard) => guard,
+        Err(response) => return *response
+    };
+    // Content is not part of the metadata, so identical metadata does not imply a duplicate backup
+    if let Err(response) = prepare(&auth_response, &payload, None, &session, &config).await {
+        return *response;
+    }
+    if let Err(response) = relocate(&auth_response, &payload, &session, &config).await {
+        return *response;
+    }
+    let repository = config.github_source.join(&auth_response.repository);
+    let mut received = collections::HashSet::new();
+    while let Some(item) = multipart.next().await {
+        let mut field = match item {
+            Ok(field) => field,
+            Err(err) => return HttpResponse::BadRequest().json(format!("unable to read multipart: {}", err))
+        };
+        let filepath = field.name().to_string();
+        if !payload.create.contains_key(&filepath) || !received.insert(filepath.clone()) {
+            log::warn!("Unexpected file part '{}' for '{}'", filepath, &auth_response.repository);
+            return HttpResponse::BadRequest().json(format!("unexpected file part: {}", filepath));
+        }
+        let true_path = repository.join(&filepath);
+        let partial = squire::partial::partial_path(&true_path);
+        let created = true_path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| squire::partial::create(&partial));
+        let mut file = match created {
+            Ok(file) => file,
             Err(err) => {
                 let error = format!("Error creating file: {}", err);
                 log::error!("{}", error);
-                return routes::helper::fallback_clone(&config.github_source,
-                                                      &auth_response.repository,
-                                                      HttpResponse::ExpectationFailed().json(error));
+                return routes::helper::fallback_clone(&config,
+                                                      &session,
+                                                      &auth_response,
+                                                      StatusCode::EXPECTATION_FAILED,
+                                                      error.into()).await.1;
             }
         };
-        match file.write_all(content.as_bytes()) {
-            Ok(_) => log::info!("File content has been updated for {:?}", true_path),
-            Err(err) => {
-                let error = format!("Error writing to file: {}", err);
+        while let Some(chunk) = field.next().await {
+            let written = chunk
+                .map_err(|err| format!("Upload interrupted: {}", err))
+                .and_then(|bytes| file.write_all(&bytes).map_err(|err| format!("Error writing to file: {}", err)));
+            if let Err(error) = written {
                 log::error!("{}", error);
-                return routes::helper::fallback_clone(&config.github_source,
-                                                      &auth_response.repository,
-                                                      HttpResponse::ExpectationFailed().json(error));
+                let _ = fs::remove_file(&partial);
+                return HttpResponse::BadRequest().json(error);
             }
         }
+        if let Err(error) = finalize_file(&payload, &filepath, &partial, &true_path, config.io_retry_count).await {
+            log::error!("{}", error);
+            let _ = fs::remove_file(&partial);
+            return routes::helper::fallback_clone(&config,
+                                                  &session,
+                                                  &auth_response,
+                                                  StatusCode::EXPECTATION_FAILED,
+                                                  error.into()).await.1;
+        }
+        log::info!("File content has been streamed to {:?}", true_path);
     }
+    let missing: Vec<&String> = payload.create.keys().filter(|filepath| !received.contains(*filepath)).collect();
+    if !missing.is_empty() {
+        log::warn!("Backup for '{}' is missing {} file parts", &auth_response.repository, missing.len());
+        return HttpResponse::BadRequest().json(serde_json::json!({"missing": missing}));
+    }
+    finish(&auth_response, &payload, &session, &config, started, &[]).await
+}
+
+/// Regular file extracted from a tar archive.
+struct TarEntry {
+    filepath: String,
+    mode: u32,
+    content: Vec<u8>,
+}
+
+/// Extracts the regular files from a tar archive in memory.
+///
+/// # Arguments
+///
+/// * `archive` - Raw bytes of the archive.
+/// * `gzip` - Boolean flag to decompress the archive with gzip first.
+/// * `limit` - Maximum size (in bytes) of the extracted content.
+///
+/// ## See Also
+///
+/// - Directories are implied by the file paths, so they are skipped silently.
+/// - Symbolic links, hard links and devices are logged and skipped.
+/// - Extracted size is limited separately, since a small compressed archive can expand to a large one.
+///
+/// # Returns
+///
+/// Returns the extracted files, or the status code and the reason for rejection.
+fn read_tar(archive: &[u8], gzip: bool, limit: usize) -> Result<Vec<TarEntry>, (StatusCode, String)> {
+    let reader: Box<dyn Read + '_> = if gzip {
+        Box::new(flate2::read::GzDecoder::new(archive))
+    } else {
+        Box::new(archive)
+    };
+    let invalid = |err: std::io::Error| (StatusCode::BAD_REQUEST, format!("invalid archive: {}", err));
+    let mut tar = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    let mut extracted = 0;
+    for entry in tar.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let filepath = entry.path().map_err(invalid)?.to_string_lossy().to_string();
+        let filepath = filepath.strip_prefix("./").unwrap_or(&filepath).to_string();
+        match entry.header().entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => (),
+            tar::EntryType::Directory => continue,
+            other => {
+                log::warn!("Skipping '{}' in the archive, since {:?} entries are not supported", filepath, other);
+                continue;
+            }
+        }
+        extracted += entry.size() as usize;
+        if extracted > limit {
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, format!("extracted archive exceeds {} bytes", limit)));
+        }
+        let mode = entry.header().mode().map_err(invalid)? & 0o777;
+        let mut content = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut content).map_err(invalid)?;
+        entries.push(TarEntry { filepath, mode, content });
+    }
+    Ok(entries)
+}
+
+/// Extracts a ZIP archive into the directory it is in, and removes the archive.
+///
+/// # Arguments
+///
+/// * `archive` - Absolute path of the ZIP archive.
+/// * `repository` - Repository's root directory.
+///
+/// ## See Also
+///
+/// - All the entries are validated before anything is extracted, so a rejected archive leaves no files behind.
+/// - Entries with `..` or absolute paths, entries that land in `.git`, and entries that resolve outside
+///   the repository through a symbolic link are rejected.
+/// - Symbolic links are logged and skipped.
+///
+/// # Returns
+///
+/// Returns the extracted files relative to the repository, or the reason for failure.
+fn extract_zip(archive: &path::Path, repository: &path::Path) -> Result<Vec<String>, String> {
+    let directory = archive.parent().unwrap_or(repository);
+    let file = fs::File::open(archive).map_err(|err| format!("unable to open archive: {}", err))?;
+    let mut zip = zip::ZipArchive::new(file).map_err(|err| format!("invalid archive: {}", err))?;
+    let mut targets = Vec::with_capacity(zip.len());
+    for index in 0..zip.len() {
+        let entry = zip.by_index(index).map_err(|err| format!("invalid archive: {}", err))?;
+        let target = match entry.enclosed_name() {
+            Some(name) => directory.join(name),
+            None => return Err(format!("entry '{}' is outside the destination", entry.name()))
+        };
+        let relative = target.strip_prefix(repository)
+            .map_err(|_| format!("entry '{}' is outside the repository", entry.name()))?;
+        if relative.components().next().is_some_and(|component| component.as_os_str() == ".git") {
+            return Err(format!("entry '{}' is within the .git directory", entry.name()));
+        }
+        if !routes::helper::resolves_within(repository, &target) {
+            return Err(format!("entry '{}' resolves outside the repository", entry.name()));
+        }
+        let relative = relative.to_string_lossy().to_string();
+        // sample: 0o120777 - file type bits of a symbolic link
+        if entry.unix_mode().is_some_and(|mode| mode & 0o170000 == 0o120000) {
+            log::warn!("Skipping '{}' in the archive, since symbolic links are not supported", entry.name());
+            continue;
+        }
+        targets.push((index, target, relative));
+    }
+    let mut extracted = Vec::new();
+    for (index, target, relative) in targets {
+        let mut entry = zip.by_index(index).map_err(|err| format!("invalid archive: {}", err))?;
+        if entry.is_dir() {
+            fs::create_dir_all(&target).map_err(|err| format!("Error creating directories: {}", err))?;
+            continue;
+        }
+        let partial = squire::partial::partial_path(&target);
+        let written = target.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| squire::partial::create(&partial))
+            .and_then(|mut file| std::io::copy(&mut entry, &mut file))
+            .and_then(|_| entry.unix_mode().map_or(Ok(()), |mode| routes::helper::set_permissions(&partial, mode & 0o777)))
+            .and_then(|_| fs::rename(&partial, &target));
+        if let Err(err) = written {
+            let _ = fs::remove_file(&partial);
+            return Err(format!("Error extracting '{}': {}", relative, err));
+        }
+        extracted.push(relative);
+    }
+    fs::remove_file(archive).map_err(|err| format!("Error removing archive: {}", err))?;
+    Ok(extracted)
+}
+
+/// Creates the files from a tar archive, instead of embedding them in a JSON body.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `archive` - Request body with the archive, as `application/x-tar` or `application/gzip`
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+///
+/// ## See Also
+///
+/// - Each regular file in the archive is applied as a `create` operation, with the permissions from the archive.
+/// - The archive is limited by `max_payload_size`, both before and after decompression.
+///
+/// # Returns
+///
+/// Returns the same `HttpResponse` as `/backup`
+#[post("/backup/tar")]
+pub async fn backup_tar_endpoint(request: HttpRequest,
+                                 archive: web::Bytes,
+                                 session: web::Data<sync::Arc<constant::Session>>,
+                                 config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let started = time::Instant::now();
+    let _guard = session.track_request();
+    squire::custom::log_connection(&request, &session, &config);
+    let auth_response = match routes::auth::authorize(&request, &config).await {
+        Ok(auth_response) => auth_response,
+        Err(response) => return *response
+    };
+    let gzip = match request.content_type() {
+        "application/x-tar" => false,
+        "application/gzip" | "application/x-gzip" => true,
+        other => {
+            log::warn!("Unsupported content type '{}' for a tar archive", other);
+            return HttpResponse::UnsupportedMediaType().json("expected 'application/x-tar' or 'application/gzip'");
+        }
+    };
+    if let Err(response) = check_free_space(&config) {
+        return *response;
+    }
+    let entries = match read_tar(&archive, gzip, config.max_payload_size) {
+        Ok(entries) => entries,
+        Err((status, error)) => {
+            log::warn!("{}", error);
+            return HttpResponse::build(status).json(error);
+        }
+    };
+    let mut payload = Payload::default();
+    for entry in &entries {
+        payload.create.insert(entry.filepath.clone(), String::new());
+        payload.create_permissions.insert(entry.filepath.clone(), entry.mode);
+    }
+    let _lock = match routes::helper::acquire_repo_lock(&request, &session, &auth_response.repository).await {
+        Ok(guard) => guard,
+        Err(response) => return *response
+    };
+    // Content is not part of the payload, so identical file lists do not imply a duplicate backup
+    if let Err(response) = prepare(&auth_response, &payload, None, &session, &config).await {
+        return *response;
+    }
+    let repository = config.github_source.join(&auth_response.repository);
+    for entry in &entries {
+        let true_path = repository.join(&entry.filepath);
+        let partial = squire::partial::partial_path(&true_path);
+        let written = match true_path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| squire::partial::create(&partial))
+            .and_then(|mut file| file.write_all(&entry.content)) {
+            Ok(()) => finalize_file(&payload, &entry.filepath, &partial, &true_path, config.io_retry_count).await,
+            Err(err) => Err(format!("Error writing file: {}", err))
+        };
+        if let Err(error) = written {
+            log::error!("{}", error);
+            let _ = fs::remove_file(&partial);
+            return routes::helper::fallback_clone(&config,
+                                                  &session,
+                                                  &auth_response,
+                                                  StatusCode::EXPECTATION_FAILED,
+                                                  error.into()).await.1;
+        }
+        log::info!("File content has been extracted to {:?}", true_path);
+    }
+    finish(&auth_response, &payload, &session, &config, started, &[]).await
+}
+
+/// Moves and copies files within the repository, before any of the files are created.
+///
+/// # Arguments
+///
+/// * `auth_response` - Authentication response with the repository information.
+/// * `payload` - Payload received in the request.
+/// * `session` - Session struct that holds the cached repository sizes.
+/// * `config` - Configuration data for the application.
+///
+/// ## See Also
+///
+/// - Files are moved first, so that a copy can be made from the new location.
+/// - Copies are written to a partial file that replaces the destination, so a destination that is
+///   hard-linked to another file, e.g. a download cache blob, is never written through.
+///
+/// # Returns
+///
+/// Returns the boxed `HttpResponse` to return early, if any of the operations fail.
+async fn relocate(auth_response: &routes::auth::AuthResponse,
+                  payload: &Payload,
+                  session: &constant::Session,
+                  config: &squire::settings::Config) -> Result<(), Box<HttpResponse>> {
+    let repository = &config.github_source.join(&auth_response.repository);
     for (old_name, new_name) in &payload.modify {
-        let src = &config.github_source
-            .join(&auth_response.repository)
-            .join(old_name);
-        let dst = &config.github_source
-            .join(&auth_response.repository)
-            .join(new_name);
-        match fs::rename(src, dst) {
-            Ok(()) => log::info!("File [{:?}] has been moved to [{:?}]", src, dst),
+        let (src, dst) = (&repository.join(old_name), &repository.join(new_name));
+        // Destination can be in a directory that is new in the same commit
+        let renamed = routes::helper::retry_transient(config.io_retry_count, || {
+            dst.parent().map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::rename(src, dst))
+        }).await;
+        match renamed {
+            Ok(()) => {
+                log::info!("File [{:?}] has been moved to [{:?}]", src, dst);
+                routes::helper::delete_empty_folders(src, &config.github_source);
+            }
             Err(err) => {
                 let error = format!("Failed to move file [{:?}] to [{:?}] - {}", src, dst, err);
                 log::error!("{}", error);
-                return routes::helper::fallback_clone(&config.github_source,
-                                                      &auth_response.repository,
-                                                      HttpResponse::ExpectationFailed().json(error));
+                return Err(Box::new(routes::helper::fallback_clone(config,
+                                                                   session,
+                                                                   auth_response,
+                                                                   StatusCode::EXPECTATION_FAILED,
+                                                                   error.into()).await.1));
             }
         }
     }
+    for (source, destination) in &payload.copy {
+        let (src, dst) = (&repository.join(source), &repository.join(destination));
+        if !src.is_file() {
+            let error = format!("copy source not found: {}", source);
+            log::error!("{}", error);
+            return Err(Box::new(HttpResponse::UnprocessableEntity().json(error)));
+        }
+        let partial = squire::partial::partial_path(dst);
+        let copied = routes::helper::retry_transient(config.io_retry_count, || {
+            dst.parent().map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::File::open(src))
+                .and_then(|mut source| squire::partial::create(&partial)
+                    .and_then(|mut file| std::io::copy(&mut source, &mut file)))
+                .and_then(|_| fs::metadata(src))
+                .and_then(|metadata| fs::set_permissions(&partial, metadata.permissions()))
+                .and_then(|_| fs::rename(&partial, dst))
+        }).await;
+        match copied {
+            Ok(()) => log::info!("File [{:?}] has been copied to [{:?}]", src, dst),
+            Err(err) => {
+                let _ = fs::remove_file(&partial);
+                let error = format!("Failed to copy file [{:?}] to [{:?}] - {}", src, dst, err);
+                log::error!("{}", error);
+                return Err(Box::new(routes::helper::fallback_clone(config,
+                                                                   session,
+                                                                   auth_response,
+                                                                   StatusCode::EXPECTATION_FAILED,
+                                                                   error.into()).await.1));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies the operations that follow the file creation, and records them in the transaction log.
+///
+/// # Arguments
+///
+/// * `auth_response` - Authentication response with the repository information.
+/// * `payload` - Payload received in the request.
+/// * `session` - Session struct that holds the transaction log.
+/// * `config` - Configuration data for the application.
+/// * `started` - Time when the request was received, to report the duration.
+/// * `unchanged` - Files in `create` that were skipped, since their content was unchanged.
+///
+/// # Returns
+///
+/// Returns the `HttpResponse` for the backup, with a summary of the operations on success.
+async fn finish(auth_response: &routes::auth::AuthResponse,
+                payload: &Payload,
+                session: &constant::Session,
+                config: &squire::settings::Config,
+                started: time::Instant,
+                unchanged: &[&String]) -> HttpResponse {
+    let repository = &config.github_source.join(&auth_response.repository);
+    let mut removables = Vec::new();
+    let mut skipped = unchanged.to_vec();
     for removable in &payload.remove {
-        let destination = &config.github_source
-            .join(&auth_response.repository)
-            .join(removable);
-        let (code, out) = routes::helper::delete_file(destination, &config.github_source);
+        if !is_glob(removable) {
+            removables.push(repository.join(removable));
+            continue;
+        }
+        match expand_glob(repository, removable) {
+            Ok(matches) if matches.is_empty() && payload.remove_strict => {
+                let error = format!("remove pattern has no matches: {}", removable);
+                log::warn!("{}", error);
+                return HttpResponse::BadRequest().json(error);
+            }
+            Ok(matches) if matches.is_empty() => {
+                log::info!("Pattern '{}' matched nothing, skipping", removable);
+                skipped.push(removable);
+            }
+            Ok(matches) => {
+                log::info!("Pattern '{}' matched {} paths", removable, matches.len());
+                // Matches within a directory that was already removed by an earlier match are skipped
+                for matched in matches {
+                    if !removables.iter().any(|removed| matched.starts_with(removed)) {
+                        removables.push(matched);
+                    }
+                }
+            }
+            Err(error) => {
+                log::warn!("{}", error);
+                return HttpResponse::BadRequest().json(error);
+            }
+        }
+    }
+    for destination in &removables {
+        let (code, out) = routes::helper::delete_file(destination, repository, &config.github_source);
+        // Rejected paths leave the repository untouched, so there is nothing to recover from
+        if code == 400 {
+            return HttpResponse::BadRequest().json(out);
+        }
         if code != 200 {
-            return routes::helper::fallback_clone(&config.github_source,
-                                                  &auth_response.repository,
-                                                  HttpResponse::build(StatusCode::from_u16(code).unwrap()).json(out));
+            return routes::helper::fallback_clone(config,
+                                                  session,
+                                                  auth_response,
+                                                  StatusCode::from_u16(code).unwrap(),
+                                                  out.into()).await.1;
+        }
+    }
+    for (link, target) in &payload.symlink {
+        let link_path = &config.github_source
+            .join(&auth_response.repository)
+            .join(link);
+        // Targets are stored as-is, since relative links are resolved from the link's location
+        if let Err(err) = routes::helper::create_symlink(target, link_path) {
+            let error = format!("Error creating symlink [{:?}] -> [{}]: {}", link_path, target, err);
+            log::error!("{}", error);
+            return routes::helper::fallback_clone(config,
+                                                  session,
+                                                  auth_response,
+                                                  StatusCode::EXPECTATION_FAILED,
+                                                  error.into()).await.1;
+        }
+    }
+    // Downloads run concurrently, limited to max_concurrent_downloads at a time
+    let downloads = payload.download.iter().map(|downloadable| (downloadable, None))
+        .chain(payload.download_urls.iter().map(|(downloadable, url)| (downloadable, Some(url))));
+    for downloadable in payload.download.iter().chain(payload.download_urls.keys()) {
+        if repository.join(downloadable).exists() {
+            log::debug!("Download will overwrite {}", downloadable);
         }
     }
-    for downloadable in &payload.download {
-        match routes::helper::download_file(&auth_response, &config, downloadable).await {
-            Ok(_) => log::info!("Download successful: {}", downloadable),
+    let results: Vec<(&String, Result<routes::helper::Downloaded, DownloadError>)> = stream::iter(downloads)
+        .map(|(downloadable, url)| async move {
+            let checksum = payload.download_checksums.get(downloadable);
+            (downloadable, routes::helper::download_file(auth_response, config, downloadable, url, checksum,
+                                                         &payload.download_tokens, session,
+                                                         payload.link_to_latest).await)
+        })
+        .buffered(config.max_concurrent_downloads)
+        .collect().await;
+    let mut downloaded = Vec::new();
+    let mut downloaded_bytes: u64 = 0;
+    let mut cache_hits: usize = 0;
+    let mut failed = collections::HashMap::new();
+    let mut mismatched = Vec::new();
+    for (downloadable, result) in results {
+        match result {
+            Ok(routes::helper::Downloaded { bytes, cache_hit }) => {
+                log::info!("Download successful: {} [{} bytes]", downloadable, bytes);
+                downloaded.push(downloadable);
+                downloaded_bytes += bytes;
+                cache_hits += usize::from(cache_hit);
+            }
+            Err(DownloadError::Checksum { expected, actual }) => {
+                log::error!("Checksum mismatch for '{}', expected {} but received {}", downloadable, expected, actual);
+                mismatched.push(serde_json::json!({
+                    "file": downloadable,
+                    "expected": expected,
+                    "actual": actual
+                }));
+            }
             Err(err) => {
-                let error = format!("Error downloading file: {}", err);
-                log::error!("{}", error);
-                return routes::helper::fallback_clone(&config.github_source,
-                                                      &auth_response.repository,
-                                                      HttpResponse::ExpectationFailed().json(error));
+                log::error!("Error downloading file '{}': {}", downloadable, err);
+                failed.insert(downloadable, err.to_string());
+            }
+        }
+    }
+    let mut unzipped = collections::HashMap::new();
+    for archive in payload.download_unzip.iter().filter(|archive| downloaded.contains(archive)) {
+        // Extracted content can be much larger than the archive
+        if let Err(response) = check_free_space(config) {
+            return *response;
+        }
+        match extract_zip(&repository.join(archive), repository) {
+            Ok(files) => {
+                log::info!("Extracted {} files from '{}'", files.len(), archive);
+                unzipped.insert(archive, files);
+            }
+            Err(error) => {
+                log::error!("Error extracting '{}': {}", archive, error);
+                failed.insert(archive, error);
+            }
+        }
+    }
+    if !mismatched.is_empty() {
+        return HttpResponse::UnprocessableEntity().json(serde_json::json!({
+            "error": "checksum_mismatch",
+            "files": mismatched
+        }));
+    }
+    if !failed.is_empty() {
+        return HttpResponse::build(StatusCode::MULTI_STATUS).json(serde_json::json!({
+            "succeeded": downloaded,
+            "failed": failed
+        }));
+    }
+    // Partial failures are reported to the client, and only complete backups are logged as transactions
+    let request_id = uuid::Uuid::new_v4().to_string();
+    let operations: Vec<(&str, String)> = payload.create.keys()
+        .filter(|path| !unchanged.contains(path))
+        .map(|path| ("create", path.to_string()))
+        .chain(payload.modify.iter().map(|(old, new)| ("modify", format!("{} -> {}", old, new))))
+        .chain(payload.copy.iter().map(|(source, destination)| ("copy", format!("{} -> {}", source, destination))))
+        .chain(payload.remove.iter().map(|path| ("remove", path.to_string())))
+        .chain(payload.symlink.keys().map(|path| ("symlink", path.to_string())))
+        .chain(downloaded.iter().map(|path| ("download", path.to_string())))
+        .chain(unzipped.keys().map(|path| ("unzip", path.to_string())))
+        .collect();
+    if let Err(err) = squire::transaction::append(session, &config.github_source,
+                                                  &auth_response.repository, &request_id, &operations) {
+        log::error!("Failed to write transaction log: {}", err);
+    }
+    if let Some(hook) = &config.post_backup_hook {
+        run_post_backup_hook(hook, &auth_response.repository);
+    }
+    let removed: Vec<&path::Path> = removables.iter()
+        .filter_map(|removed| removed.strip_prefix(repository).ok())
+        .collect();
+    // Paths that no longer exist are dropped from the manifest, so sources and removed paths are included
+    let touched: Vec<String> = payload.create.keys()
+        .filter(|path| !unchanged.contains(path))
+        .chain(payload.modify.keys())
+        .chain(payload.modify.values())
+        .chain(payload.copy.values())
+        .chain(downloaded.iter().copied())
+        .map(String::to_string)
+        .chain(removed.iter().map(|path| path.to_string_lossy().replace('\\', "/")))
+        .chain(unzipped.values().flatten().cloned())
+        .collect();
+    if let Err(err) = squire::manifest::record(repository, &touched) {
+        log::error!("Failed to write the manifest for '{}': {}", &auth_response.repository, err);
+    }
+    let mut summary = serde_json::json!({
+        "created": payload.create.keys().filter(|filepath| !unchanged.contains(filepath)).collect::<Vec<_>>(),
+        "renamed": payload.modify,
+        "removed": removed,
+        "downloaded": downloaded,
+        "downloaded_bytes": downloaded_bytes,
+        "unzipped": unzipped,
+        "copied": payload.copy,
+        "symlinked": payload.symlink,
+        "skipped": skipped,
+        "duration_ms": started.elapsed().as_millis()
+    });
+    if payload.link_to_latest && !downloaded.is_empty() {
+        summary["download_cache"] = serde_json::json!({
+            "hits": cache_hits,
+            "misses": downloaded.len() - cache_hits,
+            "hit_rate": cache_hits as f64 / downloaded.len() as f64
+        });
+    }
+    if let Some(url) = &config.backup_notification_url {
+        send_notification(session, config, url, &auth_response.repository, &summary);
+    }
+    HttpResponse::Ok().json(summary)
+}
+
+/// Sends the notification for a successful backup in the background, so that the response is not held up.
+///
+/// # Arguments
+///
+/// * `session` - Session struct that holds the shared HTTP client.
+/// * `config` - Configuration data for the application.
+/// * `url` - URL to send the notification to.
+/// * `repository` - Name of the repository that was backed up.
+/// * `summary` - Summary of the operations, as sent in the backup response.
+///
+/// ## See Also
+///
+/// - The body is signed with `backup_notification_secret`, and sent in the `X-Hub-Signature-256` header.
+/// - Network errors are retried with an exponential backoff, error responses are not.
+fn send_notification(session: &constant::Session,
+                     config: &squire::settings::Config,
+                     url: &str,
+                     repository: &str,
+                     summary: &serde_json::Value) {
+    let body = serde_json::json!({
+        "repository": repository,
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "operations_summary": summary
+    }).to_string();
+    let signature = match config.backup_notification_secret.as_deref().map(|secret| sign_body(secret, &body)) {
+        Some(Ok(signature)) => Some(signature),
+        Some(Err(err)) => {
+            log::error!("Unable to sign the backup notification: {}", err);
+            return;
+        }
+        None => None
+    };
+    let (client, url, repository) = (session.http_client.clone(), url.to_string(), repository.to_string());
+    tokio::spawn(async move {
+        let mut delay = time::Duration::from_secs(1);
+        let mut attempt = 1;
+        loop {
+            let mut builder = client.post(&url)
+                .header("content-type", "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                builder = builder.header("x-hub-signature-256", signature);
+            }
+            match builder.send().await.and_then(|response| response.error_for_status()) {
+                Ok(response) => {
+                    log::info!("Backup notification for '{}' delivered [status={}]", repository, response.status());
+                    return;
+                }
+                Err(err) if err.status().is_none() && attempt < NOTIFICATION_ATTEMPTS => {
+                    log::warn!("Retrying backup notification [attempt {}/{}] in {:?}: {}",
+                        attempt, NOTIFICATION_ATTEMPTS, delay, err);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    log::error!("Backup notification for '{}' failed, backup is unaffected: {}", repository, err);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Signs the body with HMAC-SHA256, in the format used by GitHub webhooks.
+///
+/// # Arguments
+///
+/// * `secret` - Secret shared with the receiver.
+/// * `body` - Body of the request.
+///
+/// # Returns
+///
+/// Returns the signature as `sha256={hex}`
+fn sign_body(secret: &str, body: &str) -> Result<String, openssl::error::ErrorStack> {
+    let key = openssl::pkey::PKey::hmac(secret.as_bytes())?;
+    let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key)?;
+    signer.update(body.as_bytes())?;
+    let digest = signer.sign_to_vec()?;
+    Ok(format!("sha256={}", digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()))
+}
+
+/// Runs the post backup hook in the background, so that the response is not held up.
+///
+/// # Arguments
+///
+/// * `hook` - Shell command with placeholders.
+/// * `repository` - Name of the repository that was backed up.
+fn run_post_backup_hook(hook: &str, repository: &str) {
+    let cmd = hook
+        .replace("{repository}", repository)
+        .replace("{timestamp}", &chrono::Utc::now().to_rfc3339());
+    tokio::spawn(async move {
+        if squire::command::run_async(&cmd).await {
+            log::info!("Post backup hook completed successfully");
+        } else {
+            log::info!("Post backup hook failed, backup is unaffected");
+        }
+    });
+}
+
+/// Query parameters for the transaction log endpoint.
+#[derive(Deserialize)]
+pub struct LogQuery {
+    date: String,
+}
+
+/// Streams the transaction log for a given date.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `query` - Query parameters with the date in `YYYY-MM-DD` format.
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the transaction log as newline delimited JSON.
+#[get("/backup/log", wrap = "squire::middleware::AdminIpFilter")]
+pub async fn backup_log_endpoint(request: HttpRequest,
+                                 query: web::Query<LogQuery>,
+                                 session: web::Data<sync::Arc<constant::Session>>,
+                                 config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let _guard = session.track_request();
+    squire::custom::log_connection(&request, &session, &config);
+    let auth_response = routes::auth::verify_token(&request, &config).await;
+    if !auth_response.ok {
+        return HttpResponse::Unauthorized().finish();
+    }
+    // Installation tokens are limited to their repositories, while these entries span all of them
+    if auth_response.repositories.is_some() {
+        return HttpResponse::Forbidden().json("installation tokens cannot access this endpoint");
+    }
+    let date = match NaiveDate::parse_from_str(&query.date, "%Y-%m-%d") {
+        Ok(date) => date,
+        Err(_) => {
+            log::warn!("Invalid date '{}' received for transaction log", &query.date);
+            return HttpResponse::BadRequest().json("date should be in the format YYYY-MM-DD");
+        }
+    };
+    let filepath = squire::transaction::log_file(&config.github_source, &date);
+    match actix_files::NamedFile::open(&filepath) {
+        Ok(file) => file
+            .set_content_type("application/x-ndjson".parse::<mime::Mime>().unwrap())
+            .into_response(&request),
+        Err(_) => {
+            log::warn!("Transaction log {:?} not found", filepath);
+            HttpResponse::NotFound().json(format!("no transaction log found for {}", date))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::squire::command;
+
+    /// Lists the files in a directory recursively with their content, leaving out the manifest.
+    fn snapshot(directory: &path::Path) -> collections::BTreeMap<String, String> {
+        let mut files = collections::BTreeMap::new();
+        let mut pending = vec![directory.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            for entry in fs::read_dir(current).unwrap().flatten() {
+                let filepath = entry.path();
+                if filepath.is_dir() {
+                    pending.push(filepath);
+                } else if entry.file_name() != squire::manifest::MANIFEST_FILE {
+                    let relative = filepath.strip_prefix(directory).unwrap().to_string_lossy().to_string();
+                    files.insert(relative, fs::read_to_string(&filepath).unwrap());
+                }
             }
         }
+        files
+    }
+
+    /// Temporary directory for the repositories, removed when dropped so that failing assertions leave nothing behind.
+    struct TempDir(path::PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let directory = std::env::temp_dir().join(format!("github-test-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&directory).unwrap();
+            TempDir(directory)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = path::Path;
+
+        fn deref(&self) -> &path::Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Output of `git rev-parse --git-dir` for a repository that is already cloned.
+    fn git_dir_ok() -> command::CommandOutput {
+        command::CommandOutput { success: true, exit_code: 0, stdout: ".git".to_string(), stderr: String::new() }
+    }
+
+    /// Builds the app with the backup endpoint, that stores the repositories in `source`.
+    fn backup_app(source: &path::Path) -> actix_web::App<impl actix_web::dev::ServiceFactory<
+        actix_web::dev::ServiceRequest,
+        Config = (),
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+        InitError = ()
+    >> {
+        let config = sync::Arc::new(squire::settings::test_config(source.to_path_buf()));
+        actix_web::App::new()
+            .app_data(web::Data::new(config))
+            .app_data(web::Data::new(constant::session_info()))
+            .app_data(web::JsonConfig::default().error_handler(squire::custom::json_error_handler))
+            .service(backup_endpoint)
+    }
+
+    /// Builds an authorized backup request for the repository and branch in `location`, e.g. `org/repo;main`
+    fn backup_request(location: &str) -> actix_web::test::TestRequest {
+        actix_web::test::TestRequest::post()
+            .uri("/backup")
+            .insert_header(("Authorization", "Bearer test-token"))
+            .insert_header(("Content-Location", location))
+    }
+
+    #[actix_web::test]
+    async fn test_backup_msgpack_matches_json() {
+        let source = TempDir::new();
+        for repository in ["org/json", "org/msgpack"] {
+            fs::create_dir_all(source.join(repository).join("docs")).unwrap();
+            fs::write(source.join(repository).join("docs/old.md"), "old").unwrap();
+        }
+        let mock = sync::Arc::new(command::MockGitRunner::default()
+            .expect(&["rev-parse", "--git-dir"], git_dir_ok())
+            .expect(&["rev-parse", "--git-dir"], git_dir_ok()));
+        let _guard = command::set_mock_runner(mock.clone());
+        let app = actix_web::test::init_service(backup_app(&source)).await;
+        let payload = serde_json::json!({
+            "create": {"README.md": "# Title\n", "src/main.rs": "fn main() {}\n"},
+            "modify": {"docs/old.md": "docs/new.md"},
+            "create_encoding": {"README.md": "crlf"}
+        });
+        let request = backup_request("org/json;main")
+            .set_json(&payload)
+            .to_request();
+        assert_eq!(actix_web::test::call_service(&app, request).await.status(), StatusCode::OK);
+        let payload: Payload = serde_json::from_value(payload).unwrap();
+        let request = backup_request("org/msgpack;main")
+            .insert_header(("Content-Type", squire::extractor::MSGPACK_CONTENT_TYPE))
+            .set_payload(rmp_serde::to_vec_named(&payload).unwrap())
+            .to_request();
+        assert_eq!(actix_web::test::call_service(&app, request).await.status(), StatusCode::OK);
+        assert!(mock.is_done());
+        let json = snapshot(&source.join("org/json"));
+        assert_eq!(json.get("README.md").map(String::as_str), Some("# Title\r\n"));
+        assert!(json.contains_key("docs/new.md"));
+        assert!(!json.contains_key("docs/old.md"));
+        assert_eq!(json, snapshot(&source.join("org/msgpack")));
+    }
+
+    #[actix_web::test]
+    async fn test_rename_into_new_directory() {
+        let source = TempDir::new();
+        let repository = source.join("org/repo");
+        fs::create_dir_all(repository.join("old/dir")).unwrap();
+        fs::create_dir_all(repository.join("shared/moved")).unwrap();
+        fs::write(repository.join("old/dir/f.txt"), "moved").unwrap();
+        fs::write(repository.join("shared/moved/g.txt"), "moved").unwrap();
+        fs::write(repository.join("shared/keep.txt"), "kept").unwrap();
+        let mock = sync::Arc::new(command::MockGitRunner::default()
+            .expect(&["rev-parse", "--git-dir"], git_dir_ok()));
+        let _guard = command::set_mock_runner(mock.clone());
+        let app = actix_web::test::init_service(backup_app(&source)).await;
+        let request = backup_request("org/repo;main")
+            .set_json(serde_json::json!({
+                "modify": {"old/dir/f.txt": "new/sub/f.txt", "shared/moved/g.txt": "g.txt"}
+            }))
+            .to_request();
+        assert_eq!(actix_web::test::call_service(&app, request).await.status(), StatusCode::OK);
+        assert!(mock.is_done());
+        assert_eq!(fs::read_to_string(repository.join("new/sub/f.txt")).unwrap(), "moved");
+        assert_eq!(fs::read_to_string(repository.join("g.txt")).unwrap(), "moved");
+        // Emptied source directories are removed, up to the first one that still has content
+        assert!(!repository.join("old").exists());
+        assert!(!repository.join("shared/moved").exists());
+        assert!(repository.join("shared/keep.txt").exists());
+    }
+
+    #[actix_web::test]
+    async fn test_modify_and_copy_run_before_create() {
+        let source = TempDir::new();
+        let repository = source.join("org/repo");
+        fs::create_dir_all(&repository).unwrap();
+        fs::write(repository.join("old.txt"), "moved").unwrap();
+        fs::write(repository.join("template.txt"), "template").unwrap();
+        // Destination shares its inode with a file outside the repository, like a download cache blob
+        let blob = source.join("blob");
+        fs::write(&blob, "blob").unwrap();
+        fs::hard_link(&blob, repository.join("linked.txt")).unwrap();
+        let mock = sync::Arc::new(command::MockGitRunner::default()
+            .expect(&["rev-parse", "--git-dir"], git_dir_ok()));
+        let _guard = command::set_mock_runner(mock.clone());
+        let app = actix_web::test::init_service(backup_app(&source)).await;
+        let request = backup_request("org/repo;main")
+            .set_json(serde_json::json!({
+                "modify": {"old.txt": "new/moved.txt"},
+                "copy": {"new/moved.txt": "copy/moved.txt", "template.txt": "linked.txt"},
+                "create": {"template.txt": "created"}
+            }))
+            .to_request();
+        assert_eq!(actix_web::test::call_service(&app, request).await.status(), StatusCode::OK);
+        assert!(mock.is_done());
+        assert_eq!(fs::read_to_string(repository.join("copy/moved.txt")).unwrap(), "moved");
+        // Copy is made before the source is overwritten by create
+        assert_eq!(fs::read_to_string(repository.join("linked.txt")).unwrap(), "template");
+        assert_eq!(fs::read_to_string(repository.join("template.txt")).unwrap(), "created");
+        assert_eq!(fs::read_to_string(&blob).unwrap(), "blob");
+    }
+
+    #[actix_web::test]
+    async fn test_create_does_not_write_through_hard_links() {
+        let source = TempDir::new();
+        let repository = source.join("org/repo");
+        fs::create_dir_all(&repository).unwrap();
+        let blob = source.join("blob");
+        fs::write(&blob, "blob").unwrap();
+        // Both the destination and a partial file left behind by an interrupted link share the blob's inode,
+        // and a new partial file is created for the write instead of reusing the leftover
+        fs::hard_link(&blob, repository.join("linked.txt")).unwrap();
+        fs::hard_link(&blob, squire::partial::partial_path(&repository.join("linked.txt"))).unwrap();
+        let mock = sync::Arc::new(command::MockGitRunner::default()
+            .expect(&["rev-parse", "--git-dir"], git_dir_ok()));
+        let _guard = command::set_mock_runner(mock.clone());
+        let app = actix_web::test::init_service(backup_app(&source)).await;
+        let request = backup_request("org/repo;main")
+            .set_json(serde_json::json!({"create": {"linked.txt": "created"}}))
+            .to_request();
+        assert_eq!(actix_web::test::call_service(&app, request).await.status(), StatusCode::OK);
+        assert!(mock.is_done());
+        assert_eq!(fs::read_to_string(repository.join("linked.txt")).unwrap(), "created");
+        assert_eq!(fs::read_to_string(&blob).unwrap(), "blob");
+    }
+
+    #[actix_web::test]
+    async fn test_msgpack_invalid_and_unsupported() {
+        let app = actix_web::test::init_service(backup_app(&std::env::temp_dir())).await;
+        let request = backup_request("org/repo;main")
+            .insert_header(("Content-Type", squire::extractor::MSGPACK_CONTENT_TYPE))
+            .set_payload(vec![0xc1])
+            .to_request();
+        assert_eq!(actix_web::test::call_service(&app, request).await.status(), StatusCode::BAD_REQUEST);
+        let request = backup_request("org/repo;main")
+            .insert_header(("Content-Type", "text/plain"))
+            .set_payload("{}")
+            .to_request();
+        assert_eq!(actix_web::test::call_service(&app, request).await.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[actix_web::test]
+    async fn test_check_operation_limit() {
+        let create: collections::HashMap<String, String> = (0..5)
+            .map(|index| (format!("file-{}.txt", index), "content".to_string()))
+            .collect();
+        let payload: Payload = serde_json::from_value(serde_json::json!({
+            "create": create,
+            "remove": ["old.txt"]
+        })).unwrap();
+        assert_eq!(operation_count(&payload), 6);
+        assert!(check_operation_limit(&payload, "org/repo", 7).is_ok());
+        assert!(check_operation_limit(&payload, "org/repo", 6).is_ok());
+        let response = check_operation_limit(&payload, "org/repo", 5).unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, serde_json::json!({"error": "too_many_operations", "count": 6, "limit": 5}));
+    }
+
+    #[actix_web::test]
+    async fn test_exceeds_max_depth() {
+        assert!(!exceeds_max_depth("a/b/c", 4));
+        assert!(!exceeds_max_depth("a/b/c/d", 4));
+        assert!(exceeds_max_depth("a/b/c/d/e", 4));
+        // Trailing and repeated separators are not counted as components
+        assert!(!exceeds_max_depth("a//b/c/d/", 4));
+        let deep = vec!["dir"; 200].join("/");
+        assert!(exceeds_max_depth(&deep, 20));
+    }
+
+    #[actix_web::test]
+    async fn test_duplicate_is_recorded_only_on_success() {
+        let session = constant::session_info();
+        let window = time::Duration::from_secs(60);
+        let payload: Payload = serde_json::from_str(r#"{"create":{"a.txt":"a","b.txt":"b"}}"#).unwrap();
+        let reordered: Payload = serde_json::from_str(r#"{"create":{"b.txt":"b","a.txt":"a"}}"#).unwrap();
+        let hash = payload_hash(&payload);
+        assert_eq!(hash, payload_hash(&reordered));
+        // Checking a payload never records it, so failed attempts can be retried
+        assert!(!is_duplicate(&hash, "org/repo", &session, window));
+        assert!(!is_duplicate(&hash, "org/repo", &session, window));
+        record_payload(hash.clone(), "org/repo", &session);
+        assert!(is_duplicate(&hash, "org/repo", &session, window));
+        assert!(!is_duplicate(&hash, "org/other", &session, window));
+        assert!(!is_duplicate(&hash, "org/repo", &session, time::Duration::ZERO));
+    }
+
+    #[actix_web::test]
+    async fn test_validate_templates() {
+        let payload = |value: serde_json::Value| serde_json::from_value::<Payload>(value).unwrap();
+        // Built-ins are available without template_vars, and other braces are not placeholders
+        assert!(validate_templates(&payload(serde_json::json!({
+            "create": {"a.txt": "{{REPOSITORY}} {{TIMESTAMP}} {{ name }} {{lower}}"}
+        }))).is_ok());
+        assert!(validate_templates(&payload(serde_json::json!({
+            "create": {"a.txt": "{{BUILD_URL}}"},
+            "template_vars": {"BUILD_URL": "https://ci.example.com/42"}
+        }))).is_ok());
+        let response = validate_templates(&payload(serde_json::json!({
+            "create": {"a.txt": "{{BUILD_URL}}"}
+        }))).unwrap_err();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let too_long = format!("A{}", "B".repeat(MAX_TEMPLATE_VAR_LENGTH));
+        for name in ["REPOSITORY", "lower", "1ST", too_long.as_str()] {
+            let response = validate_templates(&payload(serde_json::json!({
+                "template_vars": {name: "value"}
+            }))).unwrap_err();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", name);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_render_template() {
+        let variables = collections::HashMap::from([
+            ("REPOSITORY".to_string(), "org/repo".to_string()),
+            ("NESTED".to_string(), "{{REPOSITORY}}".to_string()),
+        ]);
+        assert_eq!(render_template("{{REPOSITORY}}@{{NESTED}} {{ name }}", &variables), "org/repo@{{REPOSITORY}} {{ name }}");
+        assert_eq!(render_template("no placeholders", &variables), "no placeholders");
     }
-    HttpResponse::Ok().finish()
 }
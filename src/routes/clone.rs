@@ -1,6 +1,7 @@
 use std::{fs, sync};
 
 use actix_web::{HttpRequest, HttpResponse, web};
+use actix_web::http::header;
 
 use crate::{constant, routes, squire};
 use crate::routes::helper::validate_repo;
@@ -9,15 +10,17 @@ use crate::routes::helper::validate_repo;
 pub async fn clone_endpoint(request: HttpRequest,
                             session: web::Data<sync::Arc<constant::Session>>,
                             config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
-    squire::custom::log_connection(&request, &session);
-    let auth_response = routes::auth::verify_token(&request, &config);
-    if !auth_response.ok {
-        return HttpResponse::Unauthorized().finish();
-    }
-    if auth_response.repository.is_empty() {
-        log::warn!("'content-location' header is invalid");
-        return HttpResponse::BadRequest().json("'content-location' header is invalid");
-    }
+    let _guard = session.track_request();
+    squire::custom::log_connection(&request, &session, &config);
+    let auth_response = match routes::auth::authorize(&request, &config).await {
+        Ok(auth_response) => auth_response,
+        Err(response) => return *response
+    };
+    // Concurrent clones would delete the directory from under each other, so they wait for the repository lock
+    let _lock = match routes::helper::acquire_repo_lock(&request, &session, &auth_response.repository).await {
+        Ok(guard) => guard,
+        Err(response) => return *response
+    };
     let destination = &&config.github_source.join(&auth_response.repository);
     if destination.exists() {
         log::warn!("Repository {} exists!", &auth_response.repository);
@@ -30,10 +33,18 @@ pub async fn clone_endpoint(request: HttpRequest,
         }
     }
     let repo_validation = validate_repo(
-        &auth_response.repository, &config.github_source,
-    );
+        &auth_response, &config, &session,
+    ).await;
     if repo_validation.ok && repo_validation.cloned {
-        return HttpResponse::Ok().finish();
+        // Path relative to the data source, so that the server's filesystem layout is not exposed
+        let local_path = destination.strip_prefix(&config.github_source).unwrap_or(destination);
+        return HttpResponse::Ok()
+            .insert_header((header::LOCATION, format!("/repos/{}", &auth_response.repository)))
+            .json(serde_json::json!({
+                "repository": &auth_response.repository,
+                "cloned": true,
+                "local_path": local_path
+            }));
     }
     let error = format!("Error deleting repo: {:?}", repo_validation.response);
     log::error!("{}", error);
@@ -0,0 +1,20 @@
+use actix_web::HttpResponse;
+
+use crate::squire;
+
+/// Lists the environment variables supported by the server.
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the name, type and default value of each environment variable.
+#[get("/config/schema", wrap = "squire::middleware::AdminIpFilter")]
+pub async fn schema_endpoint() -> HttpResponse {
+    let schema: Vec<serde_json::Value> = squire::settings::ENV_VAR_DOCS.iter()
+        .map(|(name, data_type, default)| serde_json::json!({
+            "name": name,
+            "type": data_type,
+            "default": default
+        }))
+        .collect();
+    HttpResponse::Ok().json(schema)
+}
@@ -0,0 +1,28 @@
+use std::sync;
+use std::sync::atomic;
+
+use actix_web::{HttpResponse, web};
+
+use crate::{constant, squire};
+
+/// Health check endpoint that identifies the instance serving the request.
+///
+/// # Arguments
+///
+/// * `session` - Session struct that holds the instance information.
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the instance information as JSON.
+#[get("/health", wrap = "squire::middleware::AdminIpFilter")]
+pub async fn health_endpoint(session: web::Data<sync::Arc<constant::Session>>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "healthy",
+        "instance_id": session.instance_id,
+        "hostname": session.hostname,
+        "uptime_seconds": session.start_time.elapsed().as_secs(),
+        "active_requests": session.active_requests.load(atomic::Ordering::SeqCst),
+        "skipped_unchanged_files": session.skipped_unchanged.load(atomic::Ordering::SeqCst),
+        "fallback_clones": session.fallback_clones.load(atomic::Ordering::SeqCst)
+    }))
+}
@@ -1,60 +1,237 @@
-use std::{fs, io, path};
+use std::{collections, fs, io, path, time};
+use std::sync::atomic;
 
-use actix_web::HttpResponse;
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::StatusCode;
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
 
-use crate::{routes, squire};
+use crate::{constant, routes, squire};
 use crate::squire::command;
 
+/// Duration for which the repository size fetched from the GitHub API is cached.
+const REPO_SIZE_TTL: time::Duration = time::Duration::from_secs(300);
+
+/// Maximum duration (in milliseconds) a request can wait for the repository lock.
+const MAX_LOCK_WAIT_MS: u64 = 30_000;
+
+/// Number of bytes after which the progress of a download is logged.
+const DOWNLOAD_PROGRESS_BYTES: u64 = 10 * 1024 * 1024;
+
 pub struct Status {
     pub ok: bool,
     pub cloned: bool,
+    pub revalidated: bool,
     pub response: String
 }
 
-pub fn fallback_clone(github_source: &path::Path,
-                      repository: &String,
-                      default_response: HttpResponse) -> HttpResponse {
-    let dest = github_source.join(repository);
-    if let Err(err) = fs::remove_dir_all(&dest) {
-        log::error!("Error deleting out of sync repo: {:?}", err);
-        return default_response;
-    } else {
-        log::info!("Deleted out of sync repo: {:?}", &dest);
+/// Re-clones a repository that is out of sync, after an operation on it failed.
+///
+/// # Arguments
+///
+/// * `config` - Configuration data for the application.
+/// * `session` - Session struct that holds the cached repository sizes, and the number of fallback clones.
+/// * `auth_response` - Authentication response with the repository information.
+/// * `status` - Status code of the operation that failed.
+/// * `error` - Body of the operation that failed.
+///
+/// ## See Also
+///
+/// The error is returned with `recovery_attempted` when the clone fails, and is wrapped as `error` if it isn't an object.
+///
+/// # Returns
+///
+/// Returns a boolean flag to indicate if the repository was recovered, along with the `HttpResponse` for the client.
+pub async fn fallback_clone(config: &squire::settings::Config,
+                            session: &constant::Session,
+                            auth_response: &routes::auth::AuthResponse,
+                            status: StatusCode,
+                            error: serde_json::Value) -> (bool, HttpResponse) {
+    // Tagged so that log aggregators can count the occurrences per repository
+    log::warn!("[event=fallback_clone] repository={}", &auth_response.repository);
+    session.fallback_clones.fetch_add(1, atomic::Ordering::SeqCst);
+    let dest = config.github_source.join(&auth_response.repository);
+    let recovered = match fs::remove_dir_all(&dest) {
+        Ok(()) => {
+            log::info!("Deleted out of sync repo: {:?}", &dest);
+            let repo_validation = validate_repo(
+                auth_response, config, session,
+            ).await;
+            repo_validation.ok && repo_validation.cloned
+        }
+        Err(err) => {
+            log::error!("Error deleting out of sync repo: {:?}", err);
+            false
+        }
+    };
+    if recovered {
+        return (true, HttpResponse::Ok().json(serde_json::json!({
+            "recovered": true,
+            "message": "operation failed but repository was re-cloned successfully"
+        })));
     }
-    let repo_validation = validate_repo(
-        repository, github_source,
-    );
-    if repo_validation.ok && repo_validation.cloned {
-        return HttpResponse::Ok().finish();
+    let body = match error {
+        serde_json::Value::Object(mut body) => {
+            body.insert("recovery_attempted".to_string(), serde_json::Value::Bool(true));
+            serde_json::Value::Object(body)
+        }
+        error => serde_json::json!({"error": error, "recovery_attempted": true})
+    };
+    (false, HttpResponse::build(status).json(body))
+}
+
+/// Acquires the lock for a repository, honoring the `X-Wait-For-Lock-Ms` header.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `session` - Session struct that holds the repository locks.
+/// * `repository` - Name of the repository.
+///
+/// ## See Also
+///
+/// - Without the header, the request waits until the lock is released.
+/// - The wait time is capped at 30 seconds.
+///
+/// # Returns
+///
+/// Returns the guard that releases the lock when dropped, or a `409 Conflict` once the wait time expires.
+pub async fn acquire_repo_lock<'a>(request: &HttpRequest,
+                                   session: &'a constant::Session,
+                                   repository: &str) -> Result<constant::RepoGuard<'a>, Box<HttpResponse>> {
+    let wait_ms = match request.headers().get("X-Wait-For-Lock-Ms") {
+        Some(value) => match value.to_str().ok().and_then(|value| value.parse::<u64>().ok()) {
+            Some(wait_ms) => wait_ms.min(MAX_LOCK_WAIT_MS),
+            None => {
+                log::warn!("Invalid X-Wait-For-Lock-Ms header: {:?}", value);
+                return Err(Box::new(HttpResponse::BadRequest().json("X-Wait-For-Lock-Ms should be a number of milliseconds")));
+            }
+        },
+        None => return Ok(session.lock_repo(repository).await)
+    };
+    match tokio::time::timeout(time::Duration::from_millis(wait_ms), session.lock_repo(repository)).await {
+        Ok(guard) => Ok(guard),
+        Err(_) => {
+            // Holder may have released the lock while this request stopped waiting for it
+            session.prune_repo_lock(repository);
+            log::warn!("Timed out after {}ms waiting for the lock on '{}'", wait_ms, repository);
+            Err(Box::new(HttpResponse::Conflict().json(serde_json::json!({
+                "error": "lock_timeout",
+                "repository": repository
+            }))))
+        }
+    }
+}
+
+/// Delay (in milliseconds) between the retries of a filesystem operation that failed with a transient error.
+const IO_RETRY_DELAY_MS: u64 = 100;
+
+/// Device names that are reserved on Windows, with or without an extension.
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Checks that the organization and the repository names can be used as directory names.
+///
+/// # Arguments
+///
+/// * `repository` - Name of the repository in `{org}/{repo}` format.
+///
+/// ## See Also
+///
+/// - Names starting with `.` are rejected, which includes `.` and `..`
+/// - Device names like `CON` and `LPT1` are rejected on Windows.
+///
+/// # Returns
+///
+/// Returns a `Result` with the reason for rejection.
+pub fn validate_repo_name(repository: &str) -> Result<(), String> {
+    for component in repository.split('/') {
+        if component.starts_with('.') {
+            return Err(format!("invalid repository name: {}", repository));
+        }
+        let stem = component.split('.').next().unwrap_or_default();
+        if cfg!(windows) && WINDOWS_RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+            return Err(format!("invalid repository name: {}", repository));
+        }
     }
-    default_response
+    Ok(())
 }
 
 /// Validates the repository in data source, clones repo if unavailable.
 ///
 /// # Arguments
 ///
-/// * `repo` - Repository information.
+/// * `auth_response` - Authentication response with the repository information.
 /// * `config` - Configuration data for the application.
+/// * `session` - Session struct that holds the cached repository sizes.
 ///
 /// # Returns
 ///
 /// Returns a boolean value to indicate results.
-pub fn validate_repo(repository: &String, storage: &path::Path) -> Status {
-    let destination = &storage.join(repository);
-    if destination.exists() {
-        let response = format!("{:?} exists", destination);
-        log::info!("{}", response);
+pub async fn validate_repo(auth_response: &routes::auth::AuthResponse,
+                           config: &squire::settings::Config,
+                           session: &constant::Session) -> Status {
+    let storage = &config.github_source;
+    let repository = &auth_response.repository;
+    if let Err(err) = validate_repo_name(repository) {
+        log::warn!("{}", err);
         return Status {
-            ok: true,
+            ok: false,
             cloned: false,
-            response
+            revalidated: false,
+            response: "invalid repository name".to_string()
         };
     }
-    let (org, repo) = {
-        let mut parts = repository.split('/');
-        (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
-    };
+    let destination = &storage.join(repository);
+    let mut revalidated = false;
+    if destination.exists() {
+        // Repository root resolves the git directory as '.git', anything else is a partial clone or a parent repo
+        let git_dir = command::GitCommand::new("rev-parse")
+            .working_dir(destination)
+            .flag("git-dir")
+            .run_with_output_async().await;
+        if git_dir.success && git_dir.stdout == ".git" {
+            let response = format!("{:?} exists", destination);
+            log::info!("{}", response);
+            return Status {
+                ok: true,
+                cloned: false,
+                revalidated,
+                response
+            };
+        }
+        log::warn!("{:?} is not a valid git repository, possibly an interrupted clone", destination);
+        if let Err(err) = fs::remove_dir_all(destination) {
+            let response = format!("Error deleting invalid repository: {}", err);
+            log::error!("{}", response);
+            return Status {
+                ok: false,
+                cloned: false,
+                revalidated,
+                response
+            };
+        }
+        revalidated = true;
+    }
+    // Repository size can only be looked up from the GitHub API
+    if let (Some(max_clone_size_mb), "github.com") = (config.max_clone_size_mb, config.git_host.as_str()) {
+        if let Some(size_kb) = repository_size(auth_response, config, session).await {
+            if size_kb * 1024 > max_clone_size_mb * 1024 * 1024 {
+                log::warn!("Repository '{}' [{} KB] exceeds the clone limit of {} MB",
+                    repository, size_kb, max_clone_size_mb);
+                return Status {
+                    ok: false,
+                    cloned: false,
+                    revalidated,
+                    response: "repository too large".to_string()
+                };
+            }
+        }
+    }
+    let (org, repo) = (&auth_response.org, &auth_response.repo_name);
     let organization = &storage.join(org);
     log::info!("Creating directory for {:?}", organization);
     if let Err(err) = fs::create_dir_all(organization) {
@@ -63,57 +240,426 @@ pub fn validate_repo(repository: &String, storage: &path::Path) -> Status {
         return Status {
             ok: false,
             cloned: false,
+            revalidated,
             response
         };
     }
     log::info!("Cloning '{}' into {:?}", repository, organization);
-    // cd into {data_source}/{organization} and then clone the repository
-    let cmd = format!("cd {} && git clone https://github.com/{}/{}.git",
-                      organization.to_string_lossy(), org, repo);
-    let clone_result = command::run(&cmd);
+    // Clone the repository from within {data_source}/{organization}
+    let url = format!("{}://{}/{}/{}.git", config.git_clone_scheme, config.git_host, org, repo);
+    let clone_result = command::GitCommand::new("clone")
+        .working_dir(organization)
+        .arg(&url)
+        .envs(git_auth_envs(config))
+        .run_with_output_async().await;
+    let response = if clone_result.success {
+        clone_result.stdout
+    } else {
+        format!("Failed to clone repo: {} - {}", repository, clone_result.stderr)
+    };
     Status {
-        ok: clone_result,
-        cloned: clone_result,
-        response: format!("Failed to clone repo: {}", repository)
+        ok: clone_result.success,
+        cloned: clone_result.success,
+        revalidated,
+        response
     }
 }
 
+/// Builds the environment variables that pass the GitHub token to git as an HTTP header,
+/// and the SSH key to use for SSH remotes.
+///
+/// # Arguments
+///
+/// * `config` - Configuration data for the application.
+///
+/// ## See Also
+///
+/// - The token is neither embedded in the clone URL nor stored in the cloned repository's config,
+///   so it does not show up in the logs or on disk.
+/// - The variables are set on the git process only, so other threads are not affected.
+pub fn git_auth_envs(config: &squire::settings::Config) -> Vec<(String, String)> {
+    let mut envs = Vec::new();
+    if let Some(token) = &config.github_token {
+        let credentials = openssl::base64::encode_block(format!("x-access-token:{}", token).as_bytes());
+        envs.push(("GIT_CONFIG_COUNT".to_string(), "1".to_string()));
+        envs.push(("GIT_CONFIG_KEY_0".to_string(), format!("http.https://{}/.extraheader", config.git_host)));
+        envs.push(("GIT_CONFIG_VALUE_0".to_string(), format!("Authorization: Basic {}", credentials)));
+    }
+    if let Some(ssh_key_file) = &config.ssh_key_file {
+        // GIT_SSH_COMMAND is interpreted by a shell, so the path is quoted
+        let key_file = ssh_key_file.to_string_lossy().replace('\'', "'\\''");
+        envs.push(("GIT_SSH_COMMAND".to_string(),
+                   format!("ssh -i '{}' -o StrictHostKeyChecking=accept-new", key_file)));
+    }
+    envs
+}
 
-/// Deletes empty directories after removing the requested file.
+/// Adds the GitHub token to the request's authorization header, if available.
 ///
 /// # Arguments
 ///
-/// * `path` - Filepath that was removed.
+/// * `builder` - Request builder for the GitHub API or the raw content.
+/// * `config` - Configuration data for the application.
+fn with_token(builder: reqwest::RequestBuilder, config: &squire::settings::Config) -> reqwest::RequestBuilder {
+    match &config.github_token {
+        Some(token) => builder.bearer_auth(token),
+        None => builder
+    }
+}
+
+/// Retrieves the size of a repository from the GitHub API, and caches it in the session.
+///
+/// # Arguments
+///
+/// * `auth_response` - Authentication response with the repository information.
+/// * `config` - Configuration data for the application.
+/// * `session` - Session struct that holds the cached repository sizes, and the shared HTTP client.
+///
+/// # Returns
+///
+/// Returns the size of the repository in KB, if it could be retrieved.
+async fn repository_size(auth_response: &routes::auth::AuthResponse,
+                         config: &squire::settings::Config,
+                         session: &constant::Session) -> Option<u64> {
+    if let Some((fetched, size)) = session.repo_sizes.lock().unwrap().get(&auth_response.repository) {
+        if fetched.elapsed() < REPO_SIZE_TTL {
+            return Some(*size);
+        }
+    }
+    let url = format!("https://api.github.com/repos/{}/{}", auth_response.org, auth_response.repo_name);
+    // GitHub API rejects requests without a user agent
+    let response = with_token(session.http_client.get(&url), config)
+        .header("user-agent", env!("CARGO_PKG_NAME"))
+        .header("accept", "application/vnd.github+json")
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    let body = match response {
+        Ok(res) => res.text().await.unwrap_or_default(),
+        Err(err) => {
+            log::warn!("Unable to fetch repository size for '{}': {}", auth_response.repository, err);
+            return None;
+        }
+    };
+    let size = serde_json::from_str::<serde_json::Value>(&body).ok()
+        .and_then(|value| value["size"].as_u64());
+    match size {
+        Some(size_kb) => {
+            session.repo_sizes.lock().unwrap().insert(
+                auth_response.repository.clone(), (time::Instant::now(), size_kb),
+            );
+            Some(size_kb)
+        }
+        None => {
+            log::warn!("Repository size is missing in the response for '{}'", auth_response.repository);
+            None
+        }
+    }
+}
+
+/// Retrieves the space available to the current user on the filesystem that holds the path.
+///
+/// # Arguments
+///
+/// * `path` - Path on the filesystem to check.
+///
+/// # Returns
+///
+/// Returns a `Result` with the available space in bytes.
+pub fn check_disk_space(path: &path::Path) -> Result<u64, io::Error> {
+    fs2::available_space(path)
+}
+
+/// Verifies that a relative path cannot escape the directory it is joined to.
+///
+/// # Arguments
+///
+/// * `relative` - Relative filepath received in the request.
+///
+/// # Returns
+///
+/// Returns `false` for empty or absolute paths, and paths with parent directory components.
+pub fn is_safe_path(relative: &str) -> bool {
+    !relative.is_empty() && path::Path::new(relative).components().all(|component| {
+        matches!(component, path::Component::Normal(_) | path::Component::CurDir)
+    })
+}
+
+/// Checks if a path within the repository must never be removed.
+///
+/// # Arguments
+///
+/// * `relative` - Path relative to the repository's root directory.
+///
+/// # Returns
+///
+/// Returns `true` for the repository's root directory, and paths within the `.git` directory.
+pub fn is_protected_path(relative: &path::Path) -> bool {
+    let mut components = relative.components()
+        .filter(|component| !matches!(component, path::Component::CurDir))
+        .peekable();
+    components.peek().is_none() || components.any(|component| component.as_os_str() == ".git")
+}
+
+/// Resolves the parent directory of a path, following the symbolic links in its existing ancestors.
+///
+/// Ancestors that do not exist yet cannot be links, so they are appended to the resolved path as-is.
+fn resolve_parent(filepath: &path::Path) -> Option<path::PathBuf> {
+    let parent = filepath.parent()?;
+    let existing = parent.ancestors().find(|ancestor| ancestor.symlink_metadata().is_ok())?;
+    let missing = parent.strip_prefix(existing).ok()?;
+    existing.canonicalize().ok().map(|resolved| resolved.join(missing))
+}
+
+/// Verifies that a path stays within the repository, once the symbolic links in its parents are followed.
+///
+/// # Arguments
+///
+/// * `repository` - Repository's root directory.
+/// * `filepath` - Absolute path of the file to be written or removed.
+///
+/// ## See Also
+///
+/// Parent is resolved instead of the path itself, so that symbolic links are replaced or removed rather than followed.
+///
+/// # Returns
+///
+/// Returns `false` if the parent resolves outside the repository, or cannot be resolved.
+pub fn resolves_within(repository: &path::Path, filepath: &path::Path) -> bool {
+    match (repository.canonicalize(), resolve_parent(filepath)) {
+        (Ok(root), Some(parent)) => parent.starts_with(root),
+        _ => false
+    }
+}
+
+/// Verifies that the target of a symbolic link stays within the repository.
+///
+/// # Arguments
+///
+/// * `repository` - Repository's root directory.
+/// * `link_path` - Absolute path of the link.
+/// * `target` - Path that the link points to, relative to the link's location.
+///
+/// ## See Also
+///
+/// - Parent directory components are only allowed at the start of the target, since `..` after a
+///   symbolic link is resolved from the link's target rather than the path as written.
+/// - Leading parent directory components cannot climb above the root, counted from the link's resolved parent.
+///
+/// # Returns
+///
+/// Returns `false` for empty or absolute targets, and targets that resolve outside the repository.
+pub fn is_safe_link_target(repository: &path::Path, link_path: &path::Path, target: &str) -> bool {
+    let components: Vec<path::Component> = path::Path::new(target).components().collect();
+    let ascends = components.iter()
+        .take_while(|component| matches!(component, path::Component::ParentDir))
+        .count();
+    let descends = components[ascends..].iter().all(|component| {
+        matches!(component, path::Component::Normal(_) | path::Component::CurDir)
+    });
+    if target.is_empty() || !descends {
+        return false;
+    }
+    match (repository.canonicalize(), resolve_parent(link_path)) {
+        (Ok(root), Some(parent)) => parent.strip_prefix(root)
+            .is_ok_and(|relative| relative.components().count() >= ascends),
+        _ => false
+    }
+}
+
+/// Sets the permission bits of a file.
+///
+/// # Arguments
+///
+/// * `path` - Filepath to set the permissions on.
+/// * `mode` - Permission bits, e.g. `0o755`
+///
+/// # Returns
+///
+/// Returns a `Result` object.
+#[cfg(unix)]
+pub fn set_permissions(path: &path::Path, mode: u32) -> Result<(), io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    log::info!("Permissions for {:?} have been set to {:o}", path, mode);
+    Ok(())
+}
+
+/// Sets the permission bits of a file - permission bits are not supported on non-unix targets.
+#[cfg(not(unix))]
+pub fn set_permissions(path: &path::Path, mode: u32) -> Result<(), io::Error> {
+    log::warn!("Skipping permissions {:o} for {:?}, not supported on this platform", mode, path);
+    Ok(())
+}
+
+/// Sets the owner and group of a file, when the server is running as root.
+///
+/// # Arguments
+///
+/// * `path` - Filepath to set the owner on.
+/// * `uid` - User ID of the new owner.
+/// * `gid` - Group ID of the new owner.
+///
+/// ## See Also
+///
+/// Only root can give a file away, so the change is skipped with a warning for other users.
+///
+/// # Returns
+///
+/// Returns a `Result` object.
+#[cfg(unix)]
+pub fn set_owner(path: &path::Path, uid: u32, gid: u32) -> Result<(), io::Error> {
+    use nix::unistd::{chown, getuid, Gid, Uid};
+    if !getuid().is_root() {
+        log::warn!("Skipping owner {}:{} for {:?}, the server is not running as root", uid, gid, path);
+        return Ok(());
+    }
+    chown(path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))?;
+    log::info!("Owner for {:?} has been set to {}:{}", path, uid, gid);
+    Ok(())
+}
+
+/// Sets the owner and group of a file - ownership is not supported on non-unix targets.
+#[cfg(not(unix))]
+pub fn set_owner(path: &path::Path, uid: u32, gid: u32) -> Result<(), io::Error> {
+    log::warn!("Skipping owner {}:{} for {:?}, not supported on this platform", uid, gid, path);
+    Ok(())
+}
+
+/// Creates a symbolic link, replacing any existing file or link at the same path.
+///
+/// # Arguments
+///
+/// * `target` - Path that the link points to.
+/// * `link_path` - Filepath of the link.
+///
+/// ## See Also
+///
+/// Target is not checked here, so it has to be validated with `is_safe_link_target` before the link is created.
+///
+/// # Returns
+///
+/// Returns a `Result` object.
+#[cfg(unix)]
+pub fn create_symlink(target: &str, link_path: &path::Path) -> Result<(), io::Error> {
+    if let Some(parent) = link_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if link_path.symlink_metadata().is_ok() {
+        fs::remove_file(link_path)?;
+    }
+    std::os::unix::fs::symlink(target, link_path)?;
+    log::info!("Symlink {:?} has been created for {}", link_path, target);
+    Ok(())
+}
+
+/// Creates a symbolic link - symbolic links are not supported on non-unix targets.
+#[cfg(not(unix))]
+pub fn create_symlink(target: &str, link_path: &path::Path) -> Result<(), io::Error> {
+    log::warn!("Skipping symlink {:?} -> {}, not supported on this platform", link_path, target);
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Deletes empty directories after removing or moving the requested file.
+///
+/// # Arguments
+///
+/// * `path` - Filepath that was removed or moved.
 /// * `root` - GitHub source directory that has to be retained.
-fn delete_empty_folders(path: &path::Path, root: &path::Path) {
-    if let Some(parent) = path.parent() {
-        // Recursively delete empty directories starting from the parent directory
-        if parent.is_dir() && fs::read_dir(parent).map_or(false, |mut dir| dir.next().is_none()) {
-            if parent == root {
-                return;
-            }
-            if let Err(err) = fs::remove_dir(parent) {
-                log::error!("Error deleting empty directory: {}", err);
-            } else {
-                log::info!("Deleted empty directory {:?}", parent);
-                // Check recursively for more empty directories
-                delete_empty_folders(parent, root);
-            }
+///
+/// ## See Also
+///
+/// Ancestors are collected up to the `root` and removed from the deepest to the shallowest,
+/// stopping at the first directory that is not empty, since its ancestors cannot be empty either.
+pub fn delete_empty_folders(path: &path::Path, root: &path::Path) {
+    let ancestors: Vec<&path::Path> = path.ancestors()
+        .skip(1)
+        .take_while(|ancestor| *ancestor != root && ancestor.starts_with(root))
+        .collect();
+    for directory in ancestors {
+        if !directory.is_dir() || !fs::read_dir(directory).is_ok_and(|mut dir| dir.next().is_none()) {
+            break;
+        }
+        if let Err(err) = fs::remove_dir(directory) {
+            log::error!("Error deleting empty directory: {}", err);
+            break;
         }
+        log::info!("Deleted empty directory {:?}", directory);
     }
 }
 
-/// Deletes a file.
+/// Counts the files within a directory, recursively.
+///
+/// # Arguments
+///
+/// * `directory` - Directory to count the files in.
+fn count_files(directory: &path::Path) -> usize {
+    match fs::read_dir(directory) {
+        Ok(entries) => entries.flatten().map(|entry| {
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => count_files(&entry.path()),
+                _ => 1
+            }
+        }).sum(),
+        Err(_) => 0
+    }
+}
+
+/// Deletes a file, or a directory within the repository.
 ///
 /// # Arguments
 ///
 /// * `destination` - Filepath that has to be removed.
+/// * `repository` - Repository's root directory, that cannot be removed.
 /// * `source` - GitHub source directory.
 ///
+/// ## See Also
+///
+/// The repository's root directory and the `.git` directory are rejected with `400`, without touching the disk.
+///
 /// # Returns
 ///
 /// Returns a tuple of response code (as `u16`) and response message (as `String`)
-pub fn delete_file(destination: &path::PathBuf, source: &path::Path) -> (u16, String) {
+pub fn delete_file(destination: &path::PathBuf, repository: &path::Path, source: &path::Path) -> (u16, String) {
+    match destination.strip_prefix(repository) {
+        Ok(relative) if is_protected_path(relative) => {
+            let error = format!("Path {:?} cannot be removed", destination);
+            log::warn!("{}", error);
+            return (400, error);
+        }
+        Ok(_) if !resolves_within(repository, destination) => {
+            let error = format!("Path {:?} resolves outside the repository", destination);
+            log::warn!("{}", error);
+            return (400, error);
+        }
+        Err(_) => {
+            let error = format!("Path {:?} is not within the repository", destination);
+            log::warn!("{}", error);
+            return (400, error);
+        }
+        Ok(_) => ()
+    }
+    // symlink_metadata is used to remove links themselves, instead of the directories they point to
+    let is_directory = destination.symlink_metadata().is_ok_and(|metadata| metadata.is_dir());
+    if is_directory {
+        let file_count = count_files(destination);
+        return match fs::remove_dir_all(destination) {
+            Ok(_) => {
+                log::info!("Deleted directory {:?} with {} files", destination, file_count);
+                delete_empty_folders(destination, source);
+                let out = serde_json::json!({
+                    "deleted_path": destination,
+                    "was_directory": true
+                });
+                (200, out.to_string())
+            }
+            Err(err) => {
+                let error = format!("Error deleting directory: {}", err);
+                log::error!("{}", error);
+                (417, error)
+            }
+        };
+    }
     if destination.exists() {
         return match fs::remove_file(destination) {
             Ok(_) => {
@@ -134,6 +680,155 @@ pub fn delete_file(destination: &path::PathBuf, source: &path::Path) -> (u16, St
     (404, error)
 }
 
+/// Checks if an I/O error is transient, like the ones raised by network filesystems (NFS/CIFS)
+///
+/// # Arguments
+///
+/// * `err` - Error raised by the filesystem operation.
+///
+/// ## See Also
+///
+/// On Linux, `ESTALE`, `EIO` and `ENOMEM` are also treated as transient.
+///
+/// # Returns
+///
+/// Returns a boolean flag to indicate if the operation can be retried.
+pub fn is_transient_io_error(err: &io::Error) -> bool {
+    if matches!(err.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock) {
+        return true;
+    }
+    // ESTALE (116), EIO (5) and ENOMEM (12)
+    cfg!(target_os = "linux") && matches!(err.raw_os_error(), Some(116 | 5 | 12))
+}
+
+/// Runs a filesystem operation, and retries it with a short delay when it fails with a transient error.
+///
+/// # Arguments
+///
+/// * `retries` - Number of times the operation is retried.
+/// * `operation` - Filesystem operation to run.
+///
+/// ## See Also
+///
+/// - Errors that are not transient are returned immediately.
+/// - The delay between attempts is awaited, so the worker keeps serving other requests in the meantime.
+///
+/// # Returns
+///
+/// Returns the result of the last attempt.
+pub async fn retry_transient<T>(retries: u32, mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(err) if attempt < retries && is_transient_io_error(&err) => {
+                attempt += 1;
+                log::warn!("Retrying filesystem operation [attempt {}/{}] after a transient error: {}",
+                    attempt, retries, err);
+                tokio::time::sleep(time::Duration::from_millis(IO_RETRY_DELAY_MS)).await;
+            }
+            result => return result
+        }
+    }
+}
+
+/// Sends a GET request, and retries transient failures with an exponential backoff.
+///
+/// # Arguments
+///
+/// * `client` - Shared HTTP client, to reuse the connections across attempts.
+/// * `url` - URL to send the request to.
+/// * `config` - Configuration data for the application.
+/// * `token` - Bearer token to send with the request.
+///
+/// ## See Also
+///
+/// - Connection errors, timeouts, `429` and `503` responses are retried.
+/// - `Retry-After` header (in seconds) is honored for `429` responses.
+/// - Each wait is limited to `response_timeout_ms`, so a misbehaving server cannot hold the request indefinitely.
+/// - Any other error status (e.g. `403`, `404`) fails immediately.
+///
+/// # Returns
+///
+/// Returns a `Result` object with the successful response.
+async fn get_with_retry(client: &reqwest::Client,
+                        url: &str,
+                        config: &squire::settings::Config,
+                        token: Option<&str>) -> Result<reqwest::Response, io::Error> {
+    let max_wait = time::Duration::from_millis(config.response_timeout_ms);
+    let mut delay = time::Duration::from_millis(config.download_retry_delay_ms);
+    let mut attempt = 0;
+    loop {
+        let builder = client.get(url);
+        let builder = match token {
+            Some(token) => builder.bearer_auth(token),
+            None => builder
+        };
+        let result = builder.send().await;
+        let retryable = match &result {
+            Ok(response) => match response.status() {
+                reqwest::StatusCode::TOO_MANY_REQUESTS => Some(
+                    response.headers().get("retry-after")
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.trim().parse::<u64>().ok())
+                        .map(time::Duration::from_secs)
+                ),
+                reqwest::StatusCode::SERVICE_UNAVAILABLE => Some(None),
+                _ => None
+            },
+            Err(err) if err.is_connect() || err.is_timeout() => Some(None),
+            Err(_) => None
+        };
+        match retryable {
+            Some(retry_after) if attempt < config.download_retry_count => {
+                attempt += 1;
+                let requested = retry_after.unwrap_or(delay);
+                if requested > max_wait {
+                    log::warn!("Limiting the wait of {:?} requested for {} to {:?}", requested, url, max_wait);
+                }
+                let wait = requested.min(max_wait);
+                log::warn!("Retrying download [attempt {}/{}] for {} in {:?}",
+                    attempt, config.download_retry_count, url, wait);
+                tokio::time::sleep(wait).await;
+                delay *= 2;
+            }
+            _ => return result
+                .and_then(|response| response.error_for_status())
+                .map_err(io::Error::other)
+        }
+    }
+}
+
+/// Errors that can occur while downloading a file.
+pub enum DownloadError {
+    /// Failed to fetch or write the file.
+    Io(io::Error),
+    /// Downloaded content does not match the expected checksum.
+    Checksum { expected: String, actual: String },
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::Io(err) => write!(f, "{}", err),
+            DownloadError::Checksum { expected, actual } => {
+                write!(f, "checksum mismatch, expected {} but received {}", expected, actual)
+            }
+        }
+    }
+}
+
+/// Size of a downloaded file, and whether its content was already in the blob store.
+pub struct Downloaded {
+    pub bytes: u64,
+    pub cache_hit: bool,
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(err: io::Error) -> Self {
+        DownloadError::Io(err)
+    }
+}
+
 /// Downloads a file.
 ///
 /// # Arguments
@@ -141,33 +836,481 @@ pub fn delete_file(destination: &path::PathBuf, source: &path::Path) -> (u16, St
 /// * `auth_response` - Authentication response.
 /// * `config` - Configuration data for the application.
 /// * `downloadable` - File that has to be downloaded.
+/// * `url` - URL to download the file from, defaults to the file's raw content on GitHub.
+/// * `checksum` - Expected checksum of the file as `sha256:{hex}`, verified before the file is moved into place.
+/// * `tokens` - Bearer tokens for the URLs that start with the prefix in the key.
+/// * `session` - Session struct that holds the shared HTTP client, and the index of the blob store.
+/// * `link_to_latest` - Boolean flag to store the content in the blob store, and link the file to it.
+///
+/// ## See Also
+///
+/// - The token with the longest matching prefix is sent, when there is one.
+/// - Otherwise, the GitHub token is sent only for the raw content on GitHub, never to the other hosts.
+/// - With `link_to_latest`, the file is a hard link to the blob with the same content, which is downloaded only once.
+/// - A checksum in the payload identifies the blob before the download, so cached content is not fetched again.
 ///
 /// # Returns
 ///
-/// Returns a `Result` object.
+/// Returns a `Result` with the number of bytes, and whether the content was already in the cache.
+#[allow(clippy::too_many_arguments)]
 pub async fn download_file(auth_response: &routes::auth::AuthResponse,
                            config: &squire::settings::Config,
-                           downloadable: &String) -> Result<(), io::Error> {
+                           downloadable: &String,
+                           url: Option<&String>,
+                           checksum: Option<&String>,
+                           tokens: &collections::HashMap<String, String>,
+                           session: &constant::Session,
+                           link_to_latest: bool) -> Result<Downloaded, DownloadError> {
+    let cache = link_to_latest.then_some(session);
     let destination = &config.github_source
         .join(&auth_response.repository)
         .join(downloadable);
-    let url = format!("https://raw.githubusercontent.com/{}/{}/{}",
-                      auth_response.repository, auth_response.branch, downloadable);
-    let response = match reqwest::get(url).await {
-        Ok(res) => res,
-        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
-    };
-    let response = match response.error_for_status() {
-        Ok(res) => res,
-        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+    let expected_digest = checksum
+        .and_then(|checksum| checksum.get("sha256:".len()..))
+        .map(str::to_lowercase);
+    if let (Some(session), Some(digest)) = (cache, &expected_digest) {
+        if let Some(blob) = squire::blobs::lookup(session, &config.github_source, digest) {
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            squire::blobs::link(&blob, destination)?;
+            log::debug!("Linked {} to the cached blob {}", downloadable, digest);
+            return Ok(Downloaded { bytes: fs::metadata(&blob)?.len(), cache_hit: true });
+        }
+    }
+    let (url, default_token) = match url {
+        Some(url) => (url.to_string(), None),
+        None => (format!("https://raw.githubusercontent.com/{}/{}/{}",
+                         auth_response.repository, auth_response.branch, downloadable),
+                 config.github_token.as_deref())
     };
-    let mut dest_file = match fs::File::create(destination) {
-        Ok(file) => file,
-        Err(err) => return Err(err),
+    let token = tokens.iter()
+        .filter(|(prefix, _)| url.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, token)| token.as_str())
+        .or(default_token);
+    let response = get_with_retry(&session.http_client, &url, config, token).await?;
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Downloaded content is moved into place only when complete, so the existing file is never truncated
+    let partial = squire::partial::partial_path(destination);
+    let written = match write_stream(response, &partial, downloadable).await {
+        Ok(written) => written,
+        Err(err) => {
+            let _ = fs::remove_file(&partial);
+            return Err(err.into());
+        }
     };
-    let bytes = response.bytes().await.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-    match io::copy(&mut bytes.as_ref(), &mut dest_file) {
-        Ok(_) => Ok(()),
-        Err(err) => Err(err),
+    if let Some(expected) = checksum {
+        let actual = format!("sha256:{:x}", written.1);
+        if !expected.eq_ignore_ascii_case(&actual) {
+            let _ = fs::remove_file(&partial);
+            return Err(DownloadError::Checksum { expected: expected.to_string(), actual });
+        }
+    }
+    if let Some(session) = cache {
+        let digest = format!("{:x}", written.1);
+        // Blob is moved into the store first, so that a failure never leaves the partial file behind
+        let (blob, cache_hit) = match squire::blobs::lookup(session, &config.github_source, &digest) {
+            Some(blob) => {
+                let _ = fs::remove_file(&partial);
+                (blob, true)
+            }
+            None => match squire::blobs::store(session, &config.github_source, &digest, &partial) {
+                Ok(blob) => (blob, false),
+                Err(err) => {
+                    let _ = fs::remove_file(&partial);
+                    return Err(err.into());
+                }
+            }
+        };
+        squire::blobs::link(&blob, destination)?;
+        return Ok(Downloaded { bytes: written.0, cache_hit });
+    }
+    if let Err(err) = fs::rename(&partial, destination) {
+        let _ = fs::remove_file(&partial);
+        return Err(err.into());
+    }
+    Ok(Downloaded { bytes: written.0, cache_hit: false })
+}
+
+/// Writes the body of a response to a file as it arrives, instead of buffering it in memory.
+///
+/// # Arguments
+///
+/// * `response` - Response with the content to write.
+/// * `partial` - File to write the content into.
+/// * `downloadable` - File that is being downloaded, used in the progress logs.
+///
+/// # Returns
+///
+/// Returns a `Result` with the number of bytes written and the SHA256 digest of the content.
+async fn write_stream(response: reqwest::Response,
+                      partial: &path::Path,
+                      downloadable: &String) -> io::Result<(u64, sha2::digest::Output<Sha256>)> {
+    let total = response.content_length()
+        .map_or("unknown".to_string(), |length| length.to_string());
+    let mut dest_file = io::BufWriter::new(squire::partial::create(partial)?);
+    let mut hasher = Sha256::new();
+    let mut written: u64 = 0;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        io::Write::write_all(&mut dest_file, &chunk)?;
+        hasher.update(&chunk);
+        let previous = written;
+        written += chunk.len() as u64;
+        if written / DOWNLOAD_PROGRESS_BYTES > previous / DOWNLOAD_PROGRESS_BYTES {
+            log::debug!("Downloaded {}/{} bytes for {}", written, total, downloadable);
+        }
+    }
+    io::Write::flush(&mut dest_file)?;
+    Ok((written, hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory for a test, that is unique to each run.
+    fn temp_root() -> path::PathBuf {
+        let root = std::env::temp_dir().join(format!("github-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[actix_web::test]
+    async fn test_delete_file_removes_empty_ancestors() {
+        let source = temp_root();
+        let repository = source.join("org/repo");
+        let filepath = repository.join("a/b/c/d/file.txt");
+        fs::create_dir_all(filepath.parent().unwrap()).unwrap();
+        fs::write(&filepath, "content").unwrap();
+        let (code, _) = delete_file(&filepath, &repository, &source);
+        assert_eq!(code, 200);
+        assert!(!repository.join("a").exists());
+        // Repository's directories are empty now, and are removed up to the source
+        assert!(!source.join("org").exists());
+        assert!(source.is_dir());
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_delete_file_stops_at_non_empty_directory() {
+        let source = temp_root();
+        let repository = source.join("org/repo");
+        let filepath = repository.join("a/b/c/d/file.txt");
+        fs::create_dir_all(filepath.parent().unwrap()).unwrap();
+        fs::create_dir_all(repository.join("a/b/sibling")).unwrap();
+        fs::write(repository.join("a/b/sibling/keep.txt"), "content").unwrap();
+        fs::write(&filepath, "content").unwrap();
+        let (code, _) = delete_file(&filepath, &repository, &source);
+        assert_eq!(code, 200);
+        assert!(!repository.join("a/b/c").exists());
+        assert!(repository.join("a/b/sibling/keep.txt").is_file());
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_delete_file_not_found() {
+        let source = temp_root();
+        let repository = source.join("org/repo");
+        fs::create_dir_all(&repository).unwrap();
+        let (code, _) = delete_file(&repository.join("missing.txt"), &repository, &source);
+        assert_eq!(code, 404);
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    /// Returns the output of a `git` command that succeeded, for the mock runner.
+    fn succeeded(stdout: &str) -> command::CommandOutput {
+        command::CommandOutput { success: true, exit_code: 0, stdout: stdout.to_string(), stderr: String::new() }
+    }
+
+    /// Returns the authentication response for a repository, as built from the `content-location` header.
+    fn auth_response(org: &str, repo: &str) -> routes::auth::AuthResponse {
+        routes::auth::AuthResponse {
+            ok: true,
+            repository: format!("{}/{}", org, repo),
+            org: org.to_string(),
+            repo_name: repo.to_string(),
+            branch: "main".to_string(),
+            repositories: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_validate_repo_existing() {
+        let source = temp_root();
+        fs::create_dir_all(source.join("org/repo")).unwrap();
+        let mock = std::sync::Arc::new(command::MockGitRunner::default()
+            .expect(&["rev-parse", "--git-dir"], succeeded(".git")));
+        let _guard = command::set_mock_runner(mock.clone());
+        let config = squire::settings::test_config(source.clone());
+        let status = validate_repo(&auth_response("org", "repo"), &config, &constant::session_info()).await;
+        assert!(status.ok);
+        assert!(!status.cloned);
+        assert!(!status.revalidated);
+        assert!(mock.is_done());
+        assert_eq!(mock.calls().len(), 1);
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_validate_repo_clones_missing() {
+        let source = temp_root();
+        let mock = std::sync::Arc::new(command::MockGitRunner::default()
+            .expect(&["clone", "https://github.com/org/repo.git"], succeeded("")));
+        let _guard = command::set_mock_runner(mock.clone());
+        let config = squire::settings::test_config(source.clone());
+        let status = validate_repo(&auth_response("org", "repo"), &config, &constant::session_info()).await;
+        assert!(status.ok);
+        assert!(status.cloned);
+        assert!(mock.is_done());
+        // Clone runs within the organization's directory, which is created first
+        assert!(source.join("org").is_dir());
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_validate_repo_recovers_partial_clone() {
+        let source = temp_root();
+        // Interrupted clone leaves the .git directory behind, without the HEAD file
+        fs::create_dir_all(source.join("org/repo/.git/objects")).unwrap();
+        let not_a_repository = command::CommandOutput {
+            success: false,
+            exit_code: 128,
+            stdout: String::new(),
+            stderr: "fatal: not a git repository (or any of the parent directories): .git".to_string(),
+        };
+        let mock = std::sync::Arc::new(command::MockGitRunner::default()
+            .expect(&["rev-parse", "--git-dir"], not_a_repository)
+            .expect(&["clone", "https://github.com/org/repo.git"], succeeded("")));
+        let _guard = command::set_mock_runner(mock.clone());
+        let config = squire::settings::test_config(source.clone());
+        let status = validate_repo(&auth_response("org", "repo"), &config, &constant::session_info()).await;
+        assert!(status.ok);
+        assert!(status.cloned);
+        assert!(status.revalidated);
+        assert!(mock.is_done());
+        // Mock does not create the clone, so the partial one is gone
+        assert!(!source.join("org/repo").exists());
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_validate_repo_invalid_name() {
+        let mock = std::sync::Arc::new(command::MockGitRunner::default());
+        let _guard = command::set_mock_runner(mock.clone());
+        let config = squire::settings::test_config(std::env::temp_dir());
+        let status = validate_repo(&auth_response("..", "repo"), &config, &constant::session_info()).await;
+        assert!(!status.ok);
+        assert!(mock.calls().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_fallback_clone_recovered() {
+        let source = temp_root();
+        fs::create_dir_all(source.join("org/repo")).unwrap();
+        let mock = std::sync::Arc::new(command::MockGitRunner::default()
+            .expect(&["clone", "https://github.com/org/repo.git"], succeeded("")));
+        let _guard = command::set_mock_runner(mock.clone());
+        let config = squire::settings::test_config(source.clone());
+        let session = constant::session_info();
+        let (recovered, response) = fallback_clone(&config, &session, &auth_response("org", "repo"),
+                                                   StatusCode::EXPECTATION_FAILED, "write failed".into()).await;
+        assert!(recovered);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(session.fallback_clones.load(atomic::Ordering::SeqCst), 1);
+        assert!(mock.is_done());
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_fallback_clone_failed() {
+        let source = temp_root();
+        fs::create_dir_all(source.join("org/repo")).unwrap();
+        let mock = std::sync::Arc::new(command::MockGitRunner::default());
+        let _guard = command::set_mock_runner(mock.clone());
+        let config = squire::settings::test_config(source.clone());
+        let session = constant::session_info();
+        for attempt in 1..=2 {
+            let (recovered, response) = fallback_clone(&config, &session, &auth_response("org", "repo"),
+                                                       StatusCode::EXPECTATION_FAILED, "write failed".into()).await;
+            assert!(!recovered);
+            assert_eq!(response.status(), StatusCode::EXPECTATION_FAILED);
+            let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, serde_json::json!({"error": "write failed", "recovery_attempted": true}));
+            assert_eq!(session.fallback_clones.load(atomic::Ordering::SeqCst), attempt);
+            fs::create_dir_all(source.join("org/repo")).unwrap();
+        }
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    /// Serves the same content over plain HTTP for every connection, and returns the URL to fetch it.
+    fn serve(content: &'static str) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.txt", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut stream = stream;
+                let mut request = [0; 1024];
+                let _ = io::Read::read(&mut stream, &mut request);
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                       content.len(), content);
+                let _ = io::Write::write_all(&mut stream, response.as_bytes());
+            }
+        });
+        url
+    }
+
+    #[actix_web::test]
+    async fn test_download_file_checksum() {
+        let source = temp_root();
+        fs::create_dir_all(source.join("org/repo")).unwrap();
+        let config = squire::settings::test_config(source.clone());
+        let session = constant::session_info();
+        let url = serve("downloaded content");
+        let digest = format!("{:x}", Sha256::digest(b"downloaded content"));
+        let (valid, invalid) = (format!("sha256:{}", digest), format!("sha256:{}", "0".repeat(64)));
+        let auth = auth_response("org", "repo");
+        let tokens = collections::HashMap::new();
+
+        let downloadable = "valid.txt".to_string();
+        let downloaded = download_file(&auth, &config, &downloadable, Some(&url), Some(&valid),
+                                       &tokens, &session, false).await;
+        assert!(matches!(downloaded, Ok(Downloaded { bytes: 18, cache_hit: false })));
+        assert_eq!(fs::read_to_string(source.join("org/repo/valid.txt")).unwrap(), "downloaded content");
+
+        let downloadable = "invalid.txt".to_string();
+        let mismatched = download_file(&auth, &config, &downloadable, Some(&url), Some(&invalid),
+                                       &tokens, &session, false).await;
+        match mismatched {
+            Err(DownloadError::Checksum { expected, actual }) => {
+                assert_eq!(expected, invalid);
+                assert_eq!(actual, valid);
+            }
+            _ => panic!("expected a checksum mismatch")
+        }
+        // Neither the destination nor the partial file is left behind
+        let destination = source.join("org/repo/invalid.txt");
+        assert!(!destination.exists());
+        assert!(fs::read_dir(destination.parent().unwrap()).unwrap().flatten()
+            .all(|entry| !squire::partial::is_partial(&entry.file_name().to_string_lossy())));
+
+        let downloadable = "unverified.txt".to_string();
+        let unverified = download_file(&auth, &config, &downloadable, Some(&url), None,
+                                       &tokens, &session, false).await;
+        assert!(unverified.is_ok());
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_acquire_repo_lock() {
+        let session = constant::session_info();
+        let waiting = actix_web::test::TestRequest::default()
+            .insert_header(("X-Wait-For-Lock-Ms", "50"))
+            .to_http_request();
+        let held = acquire_repo_lock(&waiting, &session, "org/repo").await.ok().unwrap();
+        let response = acquire_repo_lock(&waiting, &session, "org/repo").await.err().unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        // Locks are held per repository
+        assert!(acquire_repo_lock(&waiting, &session, "org/other").await.is_ok());
+        drop(held);
+        assert!(acquire_repo_lock(&waiting, &session, "org/repo").await.is_ok());
+        let invalid = actix_web::test::TestRequest::default()
+            .insert_header(("X-Wait-For-Lock-Ms", "soon"))
+            .to_http_request();
+        let response = acquire_repo_lock(&invalid, &session, "org/repo").await.err().unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        // Locks are removed once they are released, so the map does not grow with every repository
+        assert!(session.repo_locks.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_repo_lock_is_kept_while_waited_for() {
+        let session = constant::session_info();
+        let request = actix_web::test::TestRequest::default().to_http_request();
+        let held = acquire_repo_lock(&request, &session, "org/repo").await.ok().unwrap();
+        // Waiting request is polled first, so the lock is released while it is waited for
+        let (guard, _) = tokio::join!(session.lock_repo("org/repo"), async { drop(held) });
+        assert_eq!(session.repo_locks.lock().unwrap().len(), 1);
+        drop(guard);
+        assert!(session.repo_locks.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_is_protected_path() {
+        assert!(is_protected_path(path::Path::new("")));
+        assert!(is_protected_path(path::Path::new(".")));
+        assert!(is_protected_path(path::Path::new("./")));
+        assert!(is_protected_path(path::Path::new(".git")));
+        assert!(is_protected_path(path::Path::new("./.git/config")));
+        assert!(is_protected_path(path::Path::new("vendor/.git")));
+        assert!(!is_protected_path(path::Path::new("src/main.rs")));
+        assert!(!is_protected_path(path::Path::new(".github/workflows")));
+        assert!(!is_protected_path(path::Path::new(".gitignore")));
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn test_resolves_within() {
+        let source = temp_root();
+        let repository = source.join("org/repo");
+        fs::create_dir_all(repository.join("src")).unwrap();
+        std::os::unix::fs::symlink(&source, repository.join("escape")).unwrap();
+        std::os::unix::fs::symlink("src", repository.join("inner")).unwrap();
+        assert!(resolves_within(&repository, &repository.join("src/main.rs")));
+        assert!(resolves_within(&repository, &repository.join("new/nested/file.txt")));
+        assert!(resolves_within(&repository, &repository.join("inner/main.rs")));
+        // Link itself is replaced or removed, so only its parent has to resolve within
+        assert!(resolves_within(&repository, &repository.join("escape")));
+        assert!(!resolves_within(&repository, &repository.join("escape/file.txt")));
+        assert!(!resolves_within(&repository, &repository.join("escape/new/file.txt")));
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn test_is_safe_link_target() {
+        let source = temp_root();
+        let repository = source.join("org/repo");
+        fs::create_dir_all(repository.join("src")).unwrap();
+        std::os::unix::fs::symlink(".", repository.join("root")).unwrap();
+        assert!(is_safe_link_target(&repository, &repository.join("link"), "src/main.rs"));
+        assert!(is_safe_link_target(&repository, &repository.join("src/link"), "../README.md"));
+        assert!(is_safe_link_target(&repository, &repository.join("new/dir/link"), "../../src"));
+        assert!(!is_safe_link_target(&repository, &repository.join("link"), ""));
+        assert!(!is_safe_link_target(&repository, &repository.join("link"), "/etc/passwd"));
+        assert!(!is_safe_link_target(&repository, &repository.join("link"), ".."));
+        assert!(!is_safe_link_target(&repository, &repository.join("src/link"), "../../other/repo"));
+        // Parent directory after a link is resolved from the link's target, not the path as written
+        assert!(!is_safe_link_target(&repository, &repository.join("link"), "root/src/../.."));
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_retry_transient() {
+        let mut attempts = 0;
+        let result = retry_transient(3, || {
+            attempts += 1;
+            if attempts < 3 { Err(io::Error::from(io::ErrorKind::Interrupted)) } else { Ok(attempts) }
+        }).await;
+        assert_eq!(result.unwrap(), 3);
+        let mut attempts = 0;
+        let result: io::Result<()> = retry_transient(3, || {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::Interrupted))
+        }).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(attempts, 4);
+        let mut attempts = 0;
+        let result: io::Result<()> = retry_transient(3, || {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        }).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(attempts, 1);
     }
 }
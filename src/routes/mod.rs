@@ -4,5 +4,15 @@ mod helper;
 pub mod backup;
 /// Clone endpoint to re-clone the repository.
 pub mod clone;
+/// Status endpoint to share the server's target with clients.
+pub mod status;
+/// Health check endpoint to identify the running instance.
+pub mod health;
+/// Version endpoint to share the build metadata with clients.
+pub mod version;
+/// Configuration endpoint to describe the supported environment variables.
+pub mod config;
+/// Endpoints that operate on the local mirror of a repository.
+pub mod repos;
 /// Module to validate authentication.
 mod auth;
@@ -0,0 +1,442 @@
+use std::{collections, path, sync};
+use std::sync::OnceLock;
+
+use actix_web::{HttpRequest, HttpResponse, web};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{constant, routes, squire};
+use crate::squire::command;
+
+/// Request body to create an annotated tag.
+#[derive(Deserialize)]
+pub struct TagRequest {
+    tag: String,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    commit: String,
+}
+
+/// Request body to delete files in bulk.
+#[derive(Deserialize)]
+pub struct DeleteRequest {
+    paths: Vec<String>,
+}
+
+/// Request body to configure sparse checkout.
+#[derive(Deserialize)]
+pub struct SparseCheckoutRequest {
+    patterns: Vec<String>,
+}
+
+/// Minimum `git` version with the `sparse-checkout` subcommand.
+const SPARSE_CHECKOUT_MIN_VERSION: (u32, u32, u32) = (2, 25, 0);
+
+/// Returns the pattern that tag names have to match, e.g. `v1.2.3`
+fn tag_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"^v?[0-9]+\.[0-9]+\.[0-9]+.*$").unwrap())
+}
+
+/// Returns the pattern that matches a ref update in the output of `git fetch`, e.g. `abc123..def456  main -> origin/main`
+fn fetch_update_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"^\s*\+?\s*([0-9a-f]+)\.{2,3}([0-9a-f]+)\s+\S+\s+->\s+(\S+)").unwrap())
+}
+
+/// Returns the pattern that matches a new or deleted ref in the output of `git fetch`, e.g. `* [new branch]  dev -> origin/dev`
+fn fetch_change_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"^\s*[*\-x]\s+\[(new [a-z ]+|deleted)]\s+\S+\s+->\s+(\S+)").unwrap())
+}
+
+/// Verifies the token and resolves the local mirror of the repository in the URL.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `path` - Organization and repository name from the URL.
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+///
+/// # Returns
+///
+/// A `Result` with the repository name and its directory, or the boxed `HttpResponse` to return for failures.
+async fn resolve_repository(request: &HttpRequest,
+                            path: web::Path<(String, String)>,
+                            session: &constant::Session,
+                            config: &web::Data<sync::Arc<squire::settings::Config>>) -> Result<(String, path::PathBuf), Box<HttpResponse>> {
+    squire::custom::log_connection(request, session, config);
+    let auth_response = routes::auth::verify_token(request, config).await;
+    if !auth_response.ok {
+        return Err(Box::new(HttpResponse::Unauthorized().finish()));
+    }
+    let (org, repo) = path.into_inner();
+    let repository = format!("{}/{}", org, repo);
+    if let Err(error) = routes::auth::sanitize_repo_name(&repository, config.max_repo_name_length) {
+        log::warn!("{}", error);
+        return Err(Box::new(HttpResponse::BadRequest().json(error)));
+    }
+    if !auth_response.allows(&repository) {
+        log::warn!("Repository '{}' is not covered by the installation token", repository);
+        return Err(Box::new(HttpResponse::Forbidden().json("repository is not covered by the installation")));
+    }
+    let destination = config.github_source.join(&repository);
+    if !destination.is_dir() {
+        log::warn!("Repository '{}' not found", repository);
+        return Err(Box::new(HttpResponse::NotFound().json(format!("repository not found: {}", repository))));
+    }
+    Ok((repository, destination))
+}
+
+/// Returns the branch and the commit checked out in the local mirror of a repository.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `path` - Organization and repository name from the URL.
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+///
+/// ## See Also
+///
+/// This is the resource that `/clone` points to in the `Location` header.
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the repository, branch and commit, or `404` if it is not cloned.
+#[get("/repos/{org}/{repo}")]
+pub async fn get_repo(request: HttpRequest,
+                      path: web::Path<(String, String)>,
+                      session: web::Data<sync::Arc<constant::Session>>,
+                      config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let _guard = session.track_request();
+    let (repository, destination) = match resolve_repository(&request, path, &session, &config).await {
+        Ok(resolved) => resolved,
+        Err(response) => return *response
+    };
+    let branch = command::GitCommand::new("rev-parse")
+        .working_dir(&destination)
+        .flag("--abbrev-ref")
+        .arg("HEAD")
+        .run_with_output_async().await;
+    let head = command::GitCommand::new("rev-parse")
+        .working_dir(&destination)
+        .arg("HEAD")
+        .run_with_output_async().await;
+    if !branch.success || !head.success {
+        log::warn!("Unable to read the HEAD of '{}': {}", repository, head.stderr);
+        return HttpResponse::ExpectationFailed().json(format!("unable to read the HEAD of {}", repository));
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "repository": repository,
+        "branch": branch.stdout,
+        "head": head.stdout
+    }))
+}
+
+/// Creates an annotated tag on the local mirror of a repository.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `path` - Organization and repository name from the URL.
+/// * `payload` - Tag name, message and the commit to tag, defaults to `HEAD`
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the tag and the commit it points to.
+#[post("/repos/{org}/{repo}/tag")]
+pub async fn create_tag(request: HttpRequest,
+                        path: web::Path<(String, String)>,
+                        payload: web::Json<TagRequest>,
+                        session: web::Data<sync::Arc<constant::Session>>,
+                        config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let _guard = session.track_request();
+    let (repository, destination) = match resolve_repository(&request, path, &session, &config).await {
+        Ok(resolved) => resolved,
+        Err(response) => return *response
+    };
+    if !tag_pattern().is_match(&payload.tag) {
+        log::warn!("Invalid tag '{}'", payload.tag);
+        return HttpResponse::BadRequest().json(format!("invalid tag: {}", payload.tag));
+    }
+    let commit = if payload.commit.is_empty() { "HEAD" } else { &payload.commit };
+    if !commit.chars().all(|c| c.is_ascii_alphanumeric()) {
+        log::warn!("Invalid commit '{}'", commit);
+        return HttpResponse::BadRequest().json(format!("invalid commit: {}", commit));
+    }
+    let message = if payload.message.is_empty() { &payload.tag } else { &payload.message };
+
+    let _lock = match routes::helper::acquire_repo_lock(&request, &session, &repository).await {
+        Ok(guard) => guard,
+        Err(response) => return *response
+    };
+    let output = command::GitCommand::new("tag")
+        .working_dir(&destination)
+        .flag("-a")
+        .arg(&payload.tag)
+        .flag("-m")
+        .arg(message)
+        .arg(commit)
+        .run_with_output_async().await;
+    if !output.success {
+        if output.stderr.contains("already exists") {
+            return HttpResponse::Conflict().json(format!("tag already exists: {}", payload.tag));
+        }
+        return HttpResponse::ExpectationFailed().json(output.stderr);
+    }
+    let sha = command::GitCommand::new("rev-parse")
+        .working_dir(&destination)
+        .arg(&format!("{}^{{commit}}", payload.tag))
+        .run_with_output_async().await.stdout;
+    HttpResponse::Created().json(serde_json::json!({
+        "tag": payload.tag,
+        "sha": sha
+    }))
+}
+
+/// Deletes multiple files or directories from the local mirror of a repository.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `path` - Organization and repository name from the URL.
+/// * `payload` - List of paths to delete, relative to the repository.
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+///
+/// ## See Also
+///
+/// All the paths are processed, so a failure for one path does not prevent deleting the rest.
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the paths that were deleted, not found, or failed with an error.
+#[delete("/repos/{org}/{repo}/files")]
+pub async fn delete_files(request: HttpRequest,
+                          path: web::Path<(String, String)>,
+                          payload: web::Json<DeleteRequest>,
+                          session: web::Data<sync::Arc<constant::Session>>,
+                          config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let _guard = session.track_request();
+    let (repository, destination) = match resolve_repository(&request, path, &session, &config).await {
+        Ok(resolved) => resolved,
+        Err(response) => return *response
+    };
+    if payload.paths.len() > config.max_operations_per_backup {
+        log::warn!("Delete request for '{}' has {} paths, exceeding the limit of {}",
+            repository, payload.paths.len(), config.max_operations_per_backup);
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "too_many_operations",
+            "count": payload.paths.len(),
+            "limit": config.max_operations_per_backup
+        }));
+    }
+
+    let _lock = match routes::helper::acquire_repo_lock(&request, &session, &repository).await {
+        Ok(guard) => guard,
+        Err(response) => return *response
+    };
+    let mut deleted = Vec::new();
+    let mut not_found = Vec::new();
+    let mut errors = collections::HashMap::new();
+    for filepath in &payload.paths {
+        if !routes::helper::is_safe_path(filepath) {
+            log::warn!("Path '{}' is outside the repository", filepath);
+            errors.insert(filepath, format!("invalid path: {}", filepath));
+            continue;
+        }
+        match routes::helper::delete_file(&destination.join(filepath), &destination, &config.github_source) {
+            (200, _) => deleted.push(filepath),
+            (404, _) => not_found.push(filepath),
+            (_, error) => {
+                errors.insert(filepath, error);
+            }
+        }
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "deleted": deleted,
+        "not_found": not_found,
+        "errors": errors
+    }))
+}
+
+/// Fetches the remote-tracking refs of a repository, without modifying the working tree.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `path` - Organization and repository name from the URL.
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+///
+/// ## See Also
+///
+/// Refs that no longer exist on the remote are pruned, and reported separately.
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the refs that were updated, added and pruned.
+#[post("/repos/{org}/{repo}/fetch")]
+pub async fn fetch_repo(request: HttpRequest,
+                        path: web::Path<(String, String)>,
+                        session: web::Data<sync::Arc<constant::Session>>,
+                        config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let _guard = session.track_request();
+    let (repository, destination) = match resolve_repository(&request, path, &session, &config).await {
+        Ok(resolved) => resolved,
+        Err(response) => return *response
+    };
+
+    let _lock = match routes::helper::acquire_repo_lock(&request, &session, &repository).await {
+        Ok(guard) => guard,
+        Err(response) => return *response
+    };
+    let output = command::GitCommand::new("fetch")
+        .working_dir(&destination)
+        .flag("prune")
+        .arg("origin")
+        .envs(routes::helper::git_auth_envs(&config))
+        .run_with_output_async().await;
+    if !output.success {
+        return HttpResponse::ExpectationFailed().json(output.stderr);
+    }
+    // Progress and ref updates are written to stderr
+    let mut updated_refs = Vec::new();
+    let mut added_refs = Vec::new();
+    let mut pruned_refs = Vec::new();
+    for line in output.stderr.lines() {
+        if let Some(captures) = fetch_update_pattern().captures(line) {
+            updated_refs.push(serde_json::json!({
+                "ref": &captures[3],
+                "from": &captures[1],
+                "to": &captures[2]
+            }));
+        } else if let Some(captures) = fetch_change_pattern().captures(line) {
+            if &captures[1] == "deleted" {
+                pruned_refs.push(captures[2].to_string());
+            } else {
+                added_refs.push(captures[2].to_string());
+            }
+        }
+    }
+    log::info!("Fetched '{}' with {} updated, {} added and {} pruned refs",
+        repository, updated_refs.len(), added_refs.len(), pruned_refs.len());
+    HttpResponse::Ok().json(serde_json::json!({
+        "updated_refs": updated_refs,
+        "added_refs": added_refs,
+        "pruned_refs": pruned_refs
+    }))
+}
+
+/// Restricts the working tree of a repository to the given patterns, using sparse checkout.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `path` - Organization and repository name from the URL.
+/// * `payload` - Patterns to check out, e.g. `["src/", "tests/"]`
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+///
+/// ## See Also
+///
+/// Requires git 2.25 or later, older versions are answered with `501`
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the patterns that are active after the update.
+#[post("/repos/{org}/{repo}/sparse-checkout")]
+pub async fn sparse_checkout(request: HttpRequest,
+                             path: web::Path<(String, String)>,
+                             payload: web::Json<SparseCheckoutRequest>,
+                             session: web::Data<sync::Arc<constant::Session>>,
+                             config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let _guard = session.track_request();
+    let (repository, destination) = match resolve_repository(&request, path, &session, &config).await {
+        Ok(resolved) => resolved,
+        Err(response) => return *response
+    };
+    if payload.patterns.is_empty() {
+        return HttpResponse::BadRequest().json("at least one pattern is required");
+    }
+    // Patterns are passed as arguments, so anything that looks like an option is rejected
+    if let Some(pattern) = payload.patterns.iter()
+        .find(|pattern| pattern.is_empty() || pattern.starts_with('-') || pattern.contains('\n')) {
+        log::warn!("Invalid sparse checkout pattern '{}'", pattern);
+        return HttpResponse::BadRequest().json(format!("invalid pattern: {}", pattern));
+    }
+    match tokio::task::spawn_blocking(command::git_version).await.ok().flatten() {
+        Some(version) if version >= SPARSE_CHECKOUT_MIN_VERSION => (),
+        version => {
+            log::warn!("Sparse checkout requires git 2.25 or later, found {:?}", version);
+            return HttpResponse::NotImplemented().json("sparse checkout requires git 2.25 or later");
+        }
+    }
+
+    let _lock = match routes::helper::acquire_repo_lock(&request, &session, &repository).await {
+        Ok(guard) => guard,
+        Err(response) => return *response
+    };
+    let mut set = command::GitCommand::new("sparse-checkout")
+        .working_dir(&destination)
+        .arg("set");
+    for pattern in &payload.patterns {
+        set = set.arg(pattern);
+    }
+    let output = set.run_with_output_async().await;
+    if !output.success {
+        return HttpResponse::ExpectationFailed().json(output.stderr);
+    }
+    let output = command::GitCommand::new("checkout")
+        .working_dir(&destination)
+        .run_with_output_async().await;
+    if !output.success {
+        return HttpResponse::ExpectationFailed().json(output.stderr);
+    }
+    let output = command::GitCommand::new("sparse-checkout")
+        .working_dir(&destination)
+        .arg("list")
+        .run_with_output_async().await;
+    if !output.success {
+        return HttpResponse::ExpectationFailed().json(output.stderr);
+    }
+    let patterns: Vec<&str> = output.stdout.lines().collect();
+    log::info!("Sparse checkout for '{}' set to {} patterns", repository, patterns.len());
+    HttpResponse::Ok().json(serde_json::json!({
+        "patterns": patterns
+    }))
+}
+
+/// Returns the manifest of the files written by backups to the local mirror of a repository.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `path` - Organization and repository name from the URL.
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the manifest, or `404` if no backup has written one yet.
+#[get("/repos/{org}/{repo}/manifest")]
+pub async fn get_manifest(request: HttpRequest,
+                          path: web::Path<(String, String)>,
+                          session: web::Data<sync::Arc<constant::Session>>,
+                          config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let _guard = session.track_request();
+    let (repository, destination) = match resolve_repository(&request, path, &session, &config).await {
+        Ok(resolved) => resolved,
+        Err(response) => return *response
+    };
+    // Manifest is moved into place only when complete, so it can be read without the repository lock
+    match std::fs::read(destination.join(squire::manifest::MANIFEST_FILE)) {
+        Ok(content) => HttpResponse::Ok().content_type("application/json").body(content),
+        Err(_) => HttpResponse::NotFound().json(format!("manifest not found: {}", repository))
+    }
+}
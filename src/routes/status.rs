@@ -0,0 +1,29 @@
+use std::sync;
+
+use actix_web::{HttpRequest, HttpResponse, web};
+
+use crate::{constant, squire};
+
+/// Shares the server's configuration that clients can use to confirm the target.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the status information as JSON.
+#[get("/status")]
+pub async fn status_endpoint(request: HttpRequest,
+                             session: web::Data<sync::Arc<constant::Session>>,
+                             config: web::Data<sync::Arc<squire::settings::Config>>) -> HttpResponse {
+    let _guard = session.track_request();
+    squire::custom::log_connection(&request, &session, &config);
+    HttpResponse::Ok().json(serde_json::json!({
+        "git_host": config.git_host,
+        "instance_id": session.instance_id,
+        "hostname": session.hostname
+    }))
+}
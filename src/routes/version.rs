@@ -0,0 +1,33 @@
+use std::sync;
+
+use actix_web::{HttpResponse, web};
+use actix_web::http::header;
+
+use crate::constant;
+
+/// Shares the build metadata, so that clients can identify the server version without the startup logs.
+///
+/// # Arguments
+///
+/// * `metadata` - Struct containing metadata of the application.
+///
+/// ## See Also
+///
+/// The `ETag` header is set to the version, so that clients can detect upgrades.
+///
+/// # Returns
+///
+/// Returns an `HTTPResponse` with the build metadata as JSON.
+#[get("/version")]
+pub async fn version_endpoint(metadata: web::Data<sync::Arc<constant::MetaData>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::ETAG, format!("\"{}\"", metadata.pkg_version)))
+        .json(serde_json::json!({
+            "name": metadata.pkg_name,
+            "version": metadata.pkg_version,
+            "git_sha": metadata.git_sha,
+            "build_timestamp": metadata.build_timestamp,
+            "rust_version": metadata.rust_version,
+            "tls_backend": "openssl"
+        }))
+}
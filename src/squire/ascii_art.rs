@@ -1,3 +1,5 @@
+use std::{fs, path};
+
 use rand::prelude::SliceRandom;
 
 /// Prints random ASCII art of a horse, dog or a dolphin.
@@ -149,3 +151,18 @@ pub fn random() {
 
     println!("{}", [dog, dolphin, horse].choose(&mut rand::thread_rng()).unwrap())
 }
+
+/// Prints custom ASCII art from a file, and falls back to the bundled art if the file cannot be read.
+///
+/// # Arguments
+///
+/// * `filepath` - Path to the file with the ASCII art.
+pub fn from_file(filepath: &path::Path) {
+    match fs::read_to_string(filepath) {
+        Ok(art) => println!("{}", art),
+        Err(err) => {
+            log::warn!("Failed to read ASCII art from {:?}: {}", filepath, err);
+            random()
+        }
+    }
+}
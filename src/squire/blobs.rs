@@ -0,0 +1,111 @@
+use std::{fs, io, path};
+
+use crate::{constant, squire};
+
+/// Name of the directory within `github_source` that stores the downloaded content by its hash.
+const BLOB_DIRECTORY: &str = ".cache/blobs";
+
+/// Returns the path of a blob in the content-addressed store.
+///
+/// # Arguments
+///
+/// * `github_source` - GitHub source directory.
+/// * `digest` - SHA256 of the content as lowercase hex.
+///
+/// ## See Also
+///
+/// Blobs are sharded by the first two characters of the digest, to keep the directories small.
+fn blob_path(github_source: &path::Path, digest: &str) -> path::PathBuf {
+    github_source
+        .join(BLOB_DIRECTORY)
+        .join(&digest[..2])
+        .join(digest)
+}
+
+/// Checks if a digest is a SHA256 in lowercase hex, so that it can be used as a filename.
+fn is_digest(digest: &str) -> bool {
+    digest.len() == 64 && digest.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// Looks up a blob in the store, through the in-memory index first.
+///
+/// # Arguments
+///
+/// * `session` - Session struct that holds the index of the blobs.
+/// * `github_source` - GitHub source directory.
+/// * `digest` - SHA256 of the content as lowercase hex.
+///
+/// ## See Also
+///
+/// Blobs from an earlier run are found on disk and added to the index, and deleted blobs are dropped from it.
+///
+/// # Returns
+///
+/// Returns the path of the blob, if it is in the store.
+pub fn lookup(session: &constant::Session, github_source: &path::Path, digest: &str) -> Option<path::PathBuf> {
+    if !is_digest(digest) {
+        return None;
+    }
+    let blob = session.blob_index.get(digest)
+        .map(|entry| entry.value().clone())
+        .unwrap_or_else(|| blob_path(github_source, digest));
+    if blob.is_file() {
+        session.blob_index.insert(digest.to_string(), blob.clone());
+        Some(blob)
+    } else {
+        session.blob_index.remove(digest);
+        None
+    }
+}
+
+/// Moves a completely downloaded file into the store.
+///
+/// # Arguments
+///
+/// * `session` - Session struct that holds the index of the blobs.
+/// * `github_source` - GitHub source directory.
+/// * `digest` - SHA256 of the content as lowercase hex.
+/// * `partial` - Partial file with the complete content.
+///
+/// # Returns
+///
+/// Returns a `Result` with the path of the blob.
+pub fn store(session: &constant::Session,
+             github_source: &path::Path,
+             digest: &str,
+             partial: &path::Path) -> io::Result<path::PathBuf> {
+    if !is_digest(digest) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid digest: {}", digest)));
+    }
+    let blob = blob_path(github_source, digest);
+    if let Some(parent) = blob.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(partial, &blob)?;
+    session.blob_index.insert(digest.to_string(), blob.clone());
+    Ok(blob)
+}
+
+/// Creates a hard link to a blob at the destination, replacing any existing file.
+///
+/// # Arguments
+///
+/// * `blob` - Path of the blob in the store.
+/// * `destination` - Absolute path of the file.
+///
+/// ## See Also
+///
+/// The link is created as a partial file and then moved into place, since `hard_link` fails if the destination exists.
+///
+/// # Returns
+///
+/// Returns a `Result` object.
+pub fn link(blob: &path::Path, destination: &path::Path) -> io::Result<()> {
+    let partial = squire::partial::partial_path(destination);
+    fs::hard_link(blob, &partial)?;
+    if let Err(err) = fs::rename(&partial, destination) {
+        let _ = fs::remove_file(&partial);
+        return Err(err);
+    }
+    Ok(())
+}
@@ -1,4 +1,17 @@
+use std::path;
 use std::process::Command;
+use std::sync::{Arc, OnceLock};
+#[cfg(test)]
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Struct to store the results of a shell command.
+#[derive(Clone)]
+pub struct CommandOutput {
+    pub success: bool,
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
 
 /// Runs shell commands, and validates the result.
 ///
@@ -8,42 +21,326 @@ use std::process::Command;
 ///
 /// Returns a boolean value to indicate results.
 pub fn run(cmd: &str) -> bool {
+    run_exit_code(cmd) == 0
+}
+
+/// Runs shell commands, and returns the exit code.
+///
+/// * `cmd` - Takes the command as an argument.
+///
+/// ## See Also
+///
+/// Useful for commands that use non-zero exit codes to convey a result, like `git diff --exit-code`
+///
+/// # Returns
+///
+/// Returns the exit code of the command, or `-1` if the command could not be executed or was terminated by a signal.
+pub fn run_exit_code(cmd: &str) -> i32 {
+    execute(cmd).exit_code
+}
+
+/// Runs shell commands, and captures the trimmed output.
+///
+/// * `cmd` - Takes the command as an argument.
+///
+/// # Returns
+///
+/// Returns a `CommandOutput` with the status, standard output and standard error.
+fn execute(cmd: &str) -> CommandOutput {
     log::info!("Executing '{}'", cmd);
-    match Command::new("sh")
-        .arg("-c")
-        .arg(cmd)
-        .output()
-    {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    capture(&mut command)
+}
+
+/// Runs a command to completion, and captures the trimmed output.
+///
+/// * `command` - Command with the arguments, working directory and environment variables set.
+///
+/// # Returns
+///
+/// Returns a `CommandOutput` with the status, standard output and standard error.
+fn capture(command: &mut Command) -> CommandOutput {
+    match command.output() {
         Ok(output) => {
             log::debug!("Status Code: {}", output.status);
-            if output.status.success() {
-                if let Some(stdout) = String::from_utf8(output.stdout)
-                    .ok()
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                {
+            let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            let success = output.status.success();
+            let exit_code = output.status.code().unwrap_or(-1);
+            if success {
+                if !stdout.is_empty() {
                     log::info!("Output: '{}'", stdout);
                 }
-                // if let Ok(stdout) = String::from_utf8(output.stdout) {
-                //     if !stdout.trim().is_empty() {
-                //         log::info!("Output: '{}'", stdout.trim());
-                //     }
-                // }
-                true
-            } else {
-                if let Some(stderr) = String::from_utf8(output.stderr)
-                    .ok()
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                {
-                    log::error!("Error: '{}'", stderr);
-                }
-                false
+            } else if !stderr.is_empty() {
+                log::error!("Error: '{}'", stderr);
             }
+            CommandOutput { success, exit_code, stdout, stderr }
         }
         Err(err) => {
             log::error!("Failed to execute command: {}", err);
+            CommandOutput { success: false, exit_code: -1, stdout: String::new(), stderr: err.to_string() }
+        }
+    }
+}
+
+/// Runs shell commands in a blocking thread, so the Actix worker is not held up.
+///
+/// * `cmd` - Takes the command as an argument.
+///
+/// # Returns
+///
+/// Returns a boolean value to indicate results.
+pub async fn run_async(cmd: &str) -> bool {
+    // spawn_blocking requires a 'static closure, so the command has to be owned
+    let cmd = cmd.to_string();
+    match tokio::task::spawn_blocking(move || run(&cmd)).await {
+        Ok(result) => result,
+        Err(err) => {
+            log::error!("Failed to join blocking task: {}", err);
             false
         }
     }
 }
+
+/// Minimum `git` version required at startup, for features like sparse checkout.
+pub const MIN_GIT_VERSION: (u32, u32, u32) = (2, 25, 0);
+
+/// Retrieves the version of the installed `git` command line.
+///
+/// ## See Also
+///
+/// Suffixes like `.windows.1` or `(Apple Git-137.1)` are ignored, and a missing patch version is read as `0`
+///
+/// # Returns
+///
+/// Returns the version as `(major, minor, patch)` if the output of `git version` could be parsed.
+pub fn git_version() -> Option<(u32, u32, u32)> {
+    let output = GitCommand::new("version").run_with_output();
+    if !output.success {
+        return None;
+    }
+    // sample: git version 2.39.5
+    let version = output.stdout.split_whitespace().nth(2)?;
+    let mut parts = version.split('.').map(|part| part.parse::<u32>());
+    let major = parts.next()?.ok()?;
+    let minor = parts.next()?.ok()?;
+    let patch = parts.next().and_then(Result::ok).unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+/// Executes the `git` commands built with `GitCommand`, so the execution can be swapped out.
+///
+/// ## See Also
+///
+/// Implementations are shared across the workers, so they have to be `Send` and `Sync`
+pub trait GitRunner: Send + Sync {
+    /// Runs the command, and validates the result.
+    fn run(&self, command: &GitCommand) -> bool {
+        self.run_with_output(command).success
+    }
+
+    /// Runs the command, and captures the trimmed output.
+    fn run_with_output(&self, command: &GitCommand) -> CommandOutput;
+}
+
+/// Runs the `git` commands as a child process, using the `git` binary in `PATH`
+pub struct ShellGitRunner;
+
+impl GitRunner for ShellGitRunner {
+    fn run_with_output(&self, git: &GitCommand) -> CommandOutput {
+        let mut command = Command::new("git");
+        command.args(&git.args).envs(git.envs.iter().map(|(key, value)| (key, value)));
+        match &git.dir {
+            Some(dir) => {
+                log::info!("Executing 'git {}' in {:?}", git.args.join(" "), dir);
+                command.current_dir(dir);
+            }
+            None => log::info!("Executing 'git {}'", git.args.join(" "))
+        }
+        capture(&mut command)
+    }
+}
+
+/// Runner that executes all the `git` commands, initialized on first use.
+static RUNNER: OnceLock<Arc<dyn GitRunner>> = OnceLock::new();
+
+/// Returns the runner for the `git` commands, which defaults to `ShellGitRunner`
+fn runner() -> Arc<dyn GitRunner> {
+    #[cfg(test)]
+    if let Some(mock) = MOCK_RUNNER.lock().unwrap_or_else(PoisonError::into_inner).as_ref() {
+        return mock.clone();
+    }
+    RUNNER.get_or_init(|| Arc::new(ShellGitRunner)).clone()
+}
+
+/// Runner that replaces `ShellGitRunner` in tests, while the guard from `set_mock_runner` is held.
+#[cfg(test)]
+static MOCK_RUNNER: Mutex<Option<Arc<dyn GitRunner>>> = Mutex::new(None);
+
+/// Serializes the tests that use a mock runner, since the runner is shared by all the threads.
+#[cfg(test)]
+static MOCK_LOCK: Mutex<()> = Mutex::new(());
+
+/// Runner for tests, that answers the expected `git` commands in order, without running them.
+///
+/// ## See Also
+///
+/// Unexpected commands fail with exit code `-1`, and are recorded along with the expected ones.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockGitRunner {
+    expected: Mutex<std::collections::VecDeque<(Vec<String>, CommandOutput)>>,
+    calls: Mutex<Vec<Vec<String>>>,
+}
+
+#[cfg(test)]
+impl MockGitRunner {
+    /// Adds a command that is expected next, along with the output to return for it.
+    ///
+    /// * `args` - Subcommand and arguments, e.g. `["rev-parse", "--git-dir"]`
+    /// * `output` - Output returned for the command.
+    pub fn expect(self, args: &[&str], output: CommandOutput) -> Self {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        self.expected.lock().unwrap().push_back((args, output));
+        self
+    }
+
+    /// Returns the arguments of all the commands that were run.
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Checks if all the expected commands were run.
+    pub fn is_done(&self) -> bool {
+        self.expected.lock().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+impl GitRunner for MockGitRunner {
+    fn run_with_output(&self, command: &GitCommand) -> CommandOutput {
+        self.calls.lock().unwrap().push(command.args.clone());
+        let mut expected = self.expected.lock().unwrap();
+        match expected.front() {
+            Some((args, _)) if *args == command.args => expected.pop_front().unwrap().1,
+            _ => CommandOutput {
+                success: false,
+                exit_code: -1,
+                stdout: String::new(),
+                stderr: format!("unexpected command: git {}", command.args.join(" ")),
+            }
+        }
+    }
+}
+
+/// Guard that restores `ShellGitRunner` when dropped.
+#[cfg(test)]
+pub struct MockGuard {
+    _lock: MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl Drop for MockGuard {
+    fn drop(&mut self) {
+        *MOCK_RUNNER.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+/// Replaces the runner for all the `git` commands with a mock, until the returned guard is dropped.
+///
+/// # Arguments
+///
+/// * `mock` - Mock runner to answer the commands.
+///
+/// ## See Also
+///
+/// Tests that set a mock runner wait for each other, so they do not replace the runner from under one another.
+#[cfg(test)]
+pub fn set_mock_runner(mock: Arc<MockGitRunner>) -> MockGuard {
+    let lock = MOCK_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    *MOCK_RUNNER.lock().unwrap_or_else(PoisonError::into_inner) = Some(mock);
+    MockGuard { _lock: lock }
+}
+
+/// Builder for `git` commands, that passes each argument to the process without a shell.
+///
+/// ## See Also
+///
+/// - Arguments are never interpreted by a shell, so user input needs no quoting or escaping.
+/// - Environment variables are not logged, so they can carry credentials.
+pub struct GitCommand {
+    args: Vec<String>,
+    dir: Option<path::PathBuf>,
+    envs: Vec<(String, String)>,
+}
+
+impl GitCommand {
+    /// Creates a `git` command for the given subcommand.
+    ///
+    /// * `subcommand` - Subcommand to run, like `clone` or `tag`.
+    pub fn new(subcommand: &str) -> Self {
+        GitCommand { args: vec![subcommand.to_string()], dir: None, envs: Vec::new() }
+    }
+
+    /// Sets the directory to run the command in, defaults to the current working directory.
+    pub fn working_dir(mut self, path: &path::Path) -> Self {
+        self.dir = Some(path.to_path_buf());
+        self
+    }
+
+    /// Adds a positional argument, passed to the process as-is.
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    /// Adds an option, prefixed with `--` when given as a bare name like `git-dir`.
+    pub fn flag(mut self, flag: &str) -> Self {
+        if flag.starts_with('-') {
+            self.args.push(flag.to_string());
+        } else {
+            self.args.push(format!("--{}", flag));
+        }
+        self
+    }
+
+    /// Adds environment variables for the command, without exposing them in the logs.
+    pub fn envs(mut self, envs: Vec<(String, String)>) -> Self {
+        self.envs.extend(envs);
+        self
+    }
+
+    /// Runs the command, and validates the result.
+    ///
+    /// # Returns
+    ///
+    /// Returns a boolean value to indicate results.
+    pub fn run(self) -> bool {
+        runner().run(&self)
+    }
+
+    /// Runs the command, and captures the trimmed output.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CommandOutput` with the status, standard output and standard error.
+    pub fn run_with_output(self) -> CommandOutput {
+        runner().run_with_output(&self)
+    }
+
+    /// Runs the command in a blocking thread, so the Actix worker is not held up.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CommandOutput` with the status, standard output and standard error.
+    pub async fn run_with_output_async(self) -> CommandOutput {
+        match tokio::task::spawn_blocking(move || self.run_with_output()).await {
+            Ok(output) => output,
+            Err(err) => {
+                log::error!("Failed to join blocking task: {}", err);
+                CommandOutput { success: false, exit_code: -1, stdout: String::new(), stderr: err.to_string() }
+            }
+        }
+    }
+}
@@ -1,6 +1,49 @@
-use actix_web::HttpRequest;
+use std::{fs, io, path};
+use std::io::Write;
+use std::net::IpAddr;
+use std::str::FromStr;
 
-use crate::constant;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::StatusCode;
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::{constant, squire};
+
+/// Extracts the client's IP address from an incoming HTTP request.
+///
+/// # Arguments
+///
+/// * `request` - A reference to the Actix web `HttpRequest` object.
+/// * `trust_proxy` - Boolean flag to read the address forwarded by a reverse proxy.
+///
+/// # Returns
+///
+/// Returns the first IP in `X-Forwarded-For` or `X-Real-IP` when the proxy is trusted,
+/// falling back to the direct peer address.
+pub fn client_ip(request: &HttpRequest, trust_proxy: bool) -> String {
+    let direct = request.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    if !trust_proxy {
+        return direct;
+    }
+    let headers = request.headers();
+    let forwarded = headers.get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .or_else(|| headers.get("x-real-ip").and_then(|value| value.to_str().ok()));
+    match forwarded.map(str::trim) {
+        Some(address) => match IpAddr::from_str(address) {
+            Ok(ip) => ip.to_string(),
+            Err(_) => {
+                log::warn!("Unable to parse forwarded address '{}', using {}", address, direct);
+                direct
+            }
+        },
+        None => direct
+    }
+}
 
 /// Logs connection information for an incoming HTTP request.
 ///
@@ -8,10 +51,13 @@ use crate::constant;
 ///
 /// * `request` - A reference to the Actix web `HttpRequest` object.
 /// * `session` - Session struct that holds the `session_mapping` and `session_tracker` to handle sessions.
+/// * `config` - Configuration data for the application.
 ///
-/// This function logs the host and user agent information of the incoming connection.
-pub fn log_connection(request: &HttpRequest, session: &constant::Session) {
-    let host = request.connection_info().host().to_string();
+/// This function logs the client address and user agent information of the incoming connection.
+pub fn log_connection(request: &HttpRequest,
+                      session: &constant::Session,
+                      config: &squire::settings::Config) {
+    let host = client_ip(request, config.trust_proxy);
     let mut tracker = session.tracker.lock().unwrap();
     if tracker.get(&host).is_none() {
         tracker.insert(host.clone(), "".to_string());
@@ -21,3 +67,93 @@ pub fn log_connection(request: &HttpRequest, session: &constant::Session) {
         }
     }
 }
+
+/// Appends a line for the request to the access log, in the combined log format.
+///
+/// # Arguments
+///
+/// * `session` - Session struct that holds the writer for the access log.
+/// * `access_log_file` - Path of the access log.
+/// * `host` - IP address of the client.
+/// * `request_line` - Method, path and HTTP version of the request.
+/// * `status` - Status code of the response sent to the client.
+///
+/// ## See Also
+///
+/// The size of the response is written as `-`, since the body can be streamed.
+/// When the date changes, the existing file is renamed to `{access_log_file}.YYYY-MM-DD` and a new one is opened.
+///
+/// # Returns
+///
+/// Returns a `Result` object.
+pub fn write_access_log(session: &constant::Session,
+                        access_log_file: &path::Path,
+                        host: &str,
+                        request_line: &str,
+                        status: StatusCode) -> Result<(), io::Error> {
+    let now = Utc::now();
+    let today = now.date_naive();
+    let mut access_log = session.access_log.lock().unwrap();
+    if !matches!(access_log.as_ref(), Some((date, _)) if *date == today) {
+        // Flushes and closes the previous writer before the file is renamed
+        *access_log = None;
+        if let Some(created) = file_date(access_log_file).filter(|date| *date != today) {
+            let mut rotated = access_log_file.as_os_str().to_owned();
+            rotated.push(format!(".{}", created.format("%Y-%m-%d")));
+            fs::rename(access_log_file, &rotated)?;
+            log::info!("Rotated access log to {:?}", rotated);
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).open(access_log_file)?;
+        *access_log = Some((today, io::BufWriter::new(file)));
+    }
+    if let Some((_, writer)) = access_log.as_mut() {
+        writeln!(writer, "{} - - [{}] \"{}\" {} -",
+                 host, now.format("%d/%b/%Y:%H:%M:%S %z"), request_line, status.as_u16())?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Returns the date on which a file was created, falling back to the modification date.
+///
+/// # Arguments
+///
+/// * `filepath` - Path of the file.
+///
+/// # Returns
+///
+/// Returns an `Option` of the date, if the file exists.
+fn file_date(filepath: &path::Path) -> Option<NaiveDate> {
+    let metadata = fs::metadata(filepath).ok()?;
+    let time = metadata.created().or_else(|_| metadata.modified()).ok()?;
+    Some(DateTime::<Utc>::from(time).date_naive())
+}
+
+/// Builds a JSON error response for payloads that could not be extracted.
+///
+/// # Arguments
+///
+/// * `err` - Error raised by the JSON extractor.
+/// * `_request` - A reference to the Actix web `HttpRequest` object.
+///
+/// ## See Also
+///
+/// Replaces the default plain text errors, so that all the error responses are JSON.
+///
+/// # Returns
+///
+/// Returns an `Error` with `415` for an unsupported content type, `413` for oversized payloads, and `400` otherwise.
+pub fn json_error_handler(err: JsonPayloadError, _request: &HttpRequest) -> Error {
+    let status = match &err {
+        JsonPayloadError::ContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        _ => StatusCode::BAD_REQUEST
+    };
+    log::warn!("Invalid JSON payload: {}", err);
+    let response = HttpResponse::build(status).json(serde_json::json!({
+        "error": "invalid_json",
+        "detail": err.to_string(),
+        "field": null
+    }));
+    InternalError::from_response(err, response).into()
+}
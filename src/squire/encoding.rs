@@ -0,0 +1,75 @@
+/// Line ending modes that can be requested for a file.
+pub const MODES: &[&str] = &["lf", "crlf", "auto"];
+
+/// Normalizes the line endings in the content based on the mode requested.
+///
+/// # Arguments
+///
+/// * `content` - File content to be normalized.
+/// * `mode` - Line ending mode, one of `lf`, `crlf` or `auto`.
+///
+/// ## See Also
+///
+/// - `auto` detects the line ending used by majority of the lines and normalizes the rest to it.
+/// - Unknown modes leave the content unchanged.
+///
+/// # Returns
+///
+/// Returns the content with normalized line endings.
+pub fn normalize_line_endings(content: &str, mode: &str) -> String {
+    match mode {
+        "lf" => to_lf(content),
+        "crlf" => to_lf(content).replace('\n', "\r\n"),
+        "auto" => {
+            let crlf = content.matches("\r\n").count();
+            let lf = content.matches('\n').count() - crlf;
+            if crlf > lf {
+                normalize_line_endings(content, "crlf")
+            } else {
+                to_lf(content)
+            }
+        }
+        _ => content.to_string()
+    }
+}
+
+/// Converts all the `CRLF` line endings to `LF`.
+fn to_lf(content: &str) -> String {
+    content.replace("\r\n", "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_lf() {
+        assert_eq!(normalize_line_endings("a\r\nb\r\nc", "lf"), "a\nb\nc");
+        assert_eq!(normalize_line_endings("a\nb\r\nc\n", "lf"), "a\nb\nc\n");
+        assert_eq!(normalize_line_endings("a\nb", "lf"), "a\nb");
+    }
+
+    #[actix_web::test]
+    async fn test_crlf() {
+        assert_eq!(normalize_line_endings("a\nb\nc", "crlf"), "a\r\nb\r\nc");
+        // Existing CRLF line endings are not doubled
+        assert_eq!(normalize_line_endings("a\r\nb\nc\r\n", "crlf"), "a\r\nb\r\nc\r\n");
+    }
+
+    #[actix_web::test]
+    async fn test_auto() {
+        assert_eq!(normalize_line_endings("a\r\nb\r\nc\n", "auto"), "a\r\nb\r\nc\r\n");
+        assert_eq!(normalize_line_endings("a\nb\nc\r\n", "auto"), "a\nb\nc\n");
+        // A tie is resolved to LF
+        assert_eq!(normalize_line_endings("a\r\nb\n", "auto"), "a\nb\n");
+        assert_eq!(normalize_line_endings("no line endings", "auto"), "no line endings");
+    }
+
+    #[actix_web::test]
+    async fn test_unknown_mode_and_empty_content() {
+        assert_eq!(normalize_line_endings("a\r\nb\n", "utf-8"), "a\r\nb\n");
+        for mode in MODES {
+            assert_eq!(normalize_line_endings("", mode), "");
+        }
+    }
+}
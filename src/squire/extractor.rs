@@ -0,0 +1,52 @@
+use std::ops;
+
+use actix_web::{dev, web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::error::InternalError;
+use futures_util::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+
+/// Content type of request bodies encoded as MessagePack.
+pub const MSGPACK_CONTENT_TYPE: &str = "application/x-msgpack";
+
+/// Extractor that deserializes the request body as MessagePack or JSON, based on the `Content-Type` header.
+///
+/// ## See Also
+///
+/// - `application/x-msgpack` is decoded as MessagePack, within the limit set by `PayloadConfig`
+/// - Everything else is handed over to the JSON extractor, so `JsonConfig` applies and other content types get a `415`
+/// - MessagePack trims the quotes, separators and escapes of JSON, which saves about 30% for payloads with
+///   many short paths, while file contents take about the same space in either encoding.
+pub struct MsgpackOrJson<T>(pub T);
+
+impl<T> ops::Deref for MsgpackOrJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for MsgpackOrJson<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        if request.content_type() != MSGPACK_CONTENT_TYPE {
+            let json = web::Json::<T>::from_request(request, payload);
+            return Box::pin(async move { json.await.map(|json| MsgpackOrJson(json.into_inner())) });
+        }
+        let bytes = web::Bytes::from_request(request, payload);
+        Box::pin(async move {
+            let bytes = bytes.await?;
+            rmp_serde::from_slice(&bytes).map(MsgpackOrJson).map_err(|err| {
+                log::warn!("Invalid MessagePack payload: {}", err);
+                let response = HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "invalid_msgpack",
+                    "detail": err.to_string(),
+                    "field": null
+                }));
+                InternalError::from_response(err, response).into()
+            })
+        })
+    }
+}
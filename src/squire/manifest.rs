@@ -0,0 +1,151 @@
+use std::{collections, fs, io, path};
+use std::io::Read;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::squire;
+
+/// Name of the manifest file within each repository.
+pub const MANIFEST_FILE: &str = ".backup_manifest.json";
+
+/// Version of the manifest's structure, manifests with a different version are discarded.
+const MANIFEST_VERSION: u32 = 1;
+
+/// Files larger than this are recorded without their hash.
+const MAX_HASH_BYTES: u64 = 100 * 1024 * 1024;
+
+/// Files that were last written by a backup, along with the time of the backup.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub last_backup: String,
+    pub files: collections::BTreeMap<String, Entry>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest { version: MANIFEST_VERSION, last_backup: String::new(), files: collections::BTreeMap::new() }
+    }
+}
+
+/// Hash, size and modification time of a file when it was recorded.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub sha256: Option<String>,
+    pub size: u64,
+    pub modified: String,
+}
+
+/// Returns the modification time of a file in the format stored in the manifest.
+fn modified(metadata: &fs::Metadata) -> Option<String> {
+    metadata.modified().ok()
+        .map(|modified| DateTime::<Utc>::from(modified).to_rfc3339_opts(SecondsFormat::Nanos, true))
+}
+
+/// Computes the SHA256 of a file without reading it into memory at once.
+fn hash_file(filepath: &path::Path) -> io::Result<String> {
+    let mut file = fs::File::open(filepath)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Loads the manifest of a repository.
+///
+/// # Arguments
+///
+/// * `repository` - Repository's root directory.
+///
+/// # Returns
+///
+/// Returns the `Manifest`, which is empty if the file is missing, unreadable or of a different version.
+pub fn load(repository: &path::Path) -> Manifest {
+    let manifest = fs::read(repository.join(MANIFEST_FILE)).ok()
+        .and_then(|content| serde_json::from_slice::<Manifest>(&content).ok());
+    match manifest {
+        Some(manifest) if manifest.version == MANIFEST_VERSION => manifest,
+        _ => Manifest::default()
+    }
+}
+
+/// Returns the recorded hash of a file, if the file has not changed on disk since it was recorded.
+///
+/// # Arguments
+///
+/// * `manifest` - Manifest of the repository.
+/// * `filepath` - Path of the file relative to the repository.
+/// * `destination` - Absolute path of the file.
+///
+/// ## See Also
+///
+/// A file is considered unchanged if its size and modification time match the entry in the manifest.
+pub fn recorded_hash<'a>(manifest: &'a Manifest, filepath: &str, destination: &path::Path) -> Option<&'a str> {
+    let entry = manifest.files.get(filepath)?;
+    let metadata = fs::metadata(destination).ok()?;
+    if metadata.len() == entry.size && modified(&metadata).as_ref() == Some(&entry.modified) {
+        entry.sha256.as_deref()
+    } else {
+        None
+    }
+}
+
+/// Records the current state of the files touched by a backup, and writes the manifest.
+///
+/// # Arguments
+///
+/// * `repository` - Repository's root directory.
+/// * `touched` - Paths relative to the repository that were written, moved or removed.
+///
+/// ## See Also
+///
+/// - Paths that no longer exist are dropped from the manifest, along with everything under them.
+/// - The manifest is written to a partial file first, and then moved into place.
+///
+/// # Returns
+///
+/// Returns a `Result` object.
+pub fn record(repository: &path::Path, touched: &[String]) -> io::Result<()> {
+    let mut manifest = load(repository);
+    for filepath in touched {
+        let destination = repository.join(filepath);
+        match fs::symlink_metadata(&destination) {
+            Ok(metadata) if metadata.is_file() => {
+                let sha256 = if metadata.len() > MAX_HASH_BYTES {
+                    None
+                } else {
+                    Some(hash_file(&destination)?)
+                };
+                manifest.files.insert(filepath.to_string(), Entry {
+                    sha256,
+                    size: metadata.len(),
+                    modified: modified(&metadata).unwrap_or_default(),
+                });
+            }
+            _ => {
+                let prefix = format!("{}/", filepath.trim_end_matches('/'));
+                manifest.files.retain(|recorded, _| recorded != filepath && !recorded.starts_with(&prefix));
+            }
+        }
+    }
+    manifest.last_backup = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let destination = repository.join(MANIFEST_FILE);
+    let partial = squire::partial::partial_path(&destination);
+    let content = serde_json::to_vec_pretty(&manifest).map_err(io::Error::other)?;
+    let written = squire::partial::create(&partial)
+        .and_then(|mut file| io::Write::write_all(&mut file, &content))
+        .and_then(|_| fs::rename(&partial, &destination));
+    if let Err(err) = written {
+        let _ = fs::remove_file(&partial);
+        return Err(err);
+    }
+    Ok(())
+}
@@ -1,31 +1,442 @@
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::{sync, time};
+
 use actix_cors::Cors;
+use actix_web::{Error, HttpResponse, web};
+use actix_web::body::EitherBody;
+use actix_web::error::InternalError;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
 use actix_web::http::header;
+use futures_util::future::LocalBoxFuture;
+
+use crate::{constant, squire};
 
 /// Configures and returns a CORS middleware based on provided website origins.
 ///
 /// # Arguments
 ///
-/// * `websites` - A vector of allowed website origins for CORS.
+/// * `config` - Configuration data with the allowed origins, exposed headers and the preflight cache duration.
 ///
 /// # Returns
 ///
 /// A configured `Cors` middleware instance.
-pub fn get_cors(websites: Vec<String>) -> Cors {
-    let mut origins = vec!["http://localhost.com".to_string(), "https://localhost.com".to_string()];
-    if !websites.is_empty() {
-        origins.extend_from_slice(&websites);
-    }
-    // Create a clone to append /* to each endpoint, and further extend the same vector
-    let cloned = origins.clone().into_iter().map(|x| format!("{}/{}", x, "*"));
-    origins.extend(cloned);
+pub fn get_cors(config: &squire::settings::Config) -> Cors {
     let mut cors = Cors::default()
+        .allowed_origin("http://localhost.com")
+        .allowed_origin("https://localhost.com")
         .allowed_methods(vec!["GET", "POST", "DELETE"])
         .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT, header::CONTENT_TYPE, header::CONTENT_LOCATION])
         .allowed_header("status")
         .allowed_header("path")
-        .max_age(3600);  // Maximum time (in seconds) for which this CORS request may be cached
-    for origin in origins {
-        cors = cors.allowed_origin(&origin);
+        .allowed_header("x-api-key")
+        .expose_headers(config.cors_expose_headers.iter().map(String::as_str))
+        .max_age(config.cors_preflight_cache_seconds as usize);  // Maximum time (in seconds) for which this CORS request may be cached
+    let mut patterns = Vec::new();
+    for website in &config.websites {
+        match website {
+            squire::settings::CorsOrigin::Exact(origin) => cors = cors.allowed_origin(origin),
+            squire::settings::CorsOrigin::Pattern(pattern) => patterns.push(pattern.clone()),
+        }
+    }
+    if !patterns.is_empty() {
+        cors = cors.allowed_origin_fn(move |origin, _| {
+            origin.to_str().is_ok_and(|origin| patterns.iter().any(|pattern| pattern.is_match(origin)))
+        });
     }
     cors
 }
+
+/// Middleware that restricts the admin endpoints to the IP addresses in `admin_allowed_ips`
+///
+/// ## See Also
+///
+/// All the clients are allowed when `admin_allowed_ips` is not set.
+pub struct AdminIpFilter;
+
+impl<S, B> Transform<S, ServiceRequest> for AdminIpFilter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AdminIpFilterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AdminIpFilterMiddleware { service }))
+    }
+}
+
+/// Service created by `AdminIpFilter` for each route it wraps.
+pub struct AdminIpFilterMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for AdminIpFilterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        if let Some(config) = request.app_data::<web::Data<sync::Arc<squire::settings::Config>>>() {
+            if let Some(allowed) = &config.admin_allowed_ips {
+                let host = squire::custom::client_ip(request.request(), config.trust_proxy);
+                let permitted = IpAddr::from_str(&host)
+                    .is_ok_and(|ip| allowed.iter().any(|network| network.contains(&ip)));
+                if !permitted {
+                    log::warn!("Admin endpoint {} requested from {}, which is not allowed", request.path(), host);
+                    let response = HttpResponse::Forbidden().json("access denied").map_into_right_body();
+                    return Box::pin(async move { Ok(request.into_response(response)) });
+                }
+            }
+        }
+        let future = self.service.call(request);
+        Box::pin(async move { Ok(future.await?.map_into_left_body()) })
+    }
+}
+
+/// Middleware that writes every request to the access log, when `access_log_file` is set.
+///
+/// ## See Also
+///
+/// The line is written after the response is built, so it has the status code sent to the client,
+/// including the requests that do not match any route or fail before reaching a handler.
+pub struct AccessLog;
+
+impl<S, B> Transform<S, ServiceRequest> for AccessLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AccessLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AccessLogMiddleware { service }))
+    }
+}
+
+/// Service created by `AccessLog` for the application.
+pub struct AccessLogMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for AccessLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        // Request is moved into the service, so the parts of the line known upfront are captured here
+        let access_log = request.app_data::<web::Data<sync::Arc<squire::settings::Config>>>()
+            .and_then(|config| config.access_log_file.clone().map(|filepath| {
+                let host = squire::custom::client_ip(request.request(), config.trust_proxy);
+                let request_line = format!("{} {} {:?}", request.method(), request.path(), request.version());
+                (filepath, host, request_line)
+            }));
+        let session = request.app_data::<web::Data<sync::Arc<constant::Session>>>().cloned();
+        let future = self.service.call(request);
+        Box::pin(async move {
+            let result = future.await;
+            if let (Some((access_log_file, host, request_line)), Some(session)) = (access_log, session) {
+                // Errors are turned into responses after the middleware, with the status of the error
+                let status = match &result {
+                    Ok(response) => response.status(),
+                    Err(err) => err.as_response_error().status_code()
+                };
+                if let Err(err) = squire::custom::write_access_log(&session, &access_log_file, &host, &request_line, status) {
+                    log::warn!("Unable to write to the access log {:?}: {}", access_log_file, err);
+                }
+            }
+            result
+        })
+    }
+}
+
+/// Paths that modify the repository despite being requested with `GET`
+const TIMEOUT_EXEMPT: &[&str] = &["/clone"];
+
+/// Checks if a request can be cancelled midway, without leaving a repository half-written.
+///
+/// # Arguments
+///
+/// * `method` - HTTP method of the request.
+/// * `path` - Path of the request.
+///
+/// # Returns
+///
+/// Returns `true` for the read-only requests, except the paths in `TIMEOUT_EXEMPT`
+fn is_cancellable(method: &actix_web::http::Method, path: &str) -> bool {
+    matches!(method.as_str(), "GET" | "HEAD" | "OPTIONS") && !TIMEOUT_EXEMPT.contains(&path)
+}
+
+/// Middleware that responds with `504 Gateway Timeout` when a handler exceeds the response timeout.
+///
+/// ## See Also
+///
+/// - Only the read-only requests are limited, since dropping a handler releases the repository lock
+///   while its blocking git or filesystem work is still running.
+/// - Requests that modify a repository, like `/backup`, `/backup/tar` and `/clone`, always run to completion.
+pub struct ResponseTimeout {
+    pub duration: time::Duration,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ResponseTimeout
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ResponseTimeoutMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ResponseTimeoutMiddleware { service, duration: self.duration }))
+    }
+}
+
+/// Service created by `ResponseTimeout` for the application.
+pub struct ResponseTimeoutMiddleware<S> {
+    service: S,
+    duration: time::Duration,
+}
+
+impl<S, B> Service<ServiceRequest> for ResponseTimeoutMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let exempt = !is_cancellable(request.method(), request.path());
+        let path = request.path().to_string();
+        let duration = self.duration;
+        let future = self.service.call(request);
+        if exempt {
+            return Box::pin(future);
+        }
+        Box::pin(async move {
+            match tokio::time::timeout(duration, future).await {
+                Ok(response) => response,
+                Err(_) => {
+                    log::error!("Request to {} exceeded the response timeout of {:?}", path, duration);
+                    let response = HttpResponse::GatewayTimeout()
+                        .json(format!("request timed out after {} ms", duration.as_millis()));
+                    Err(InternalError::from_response("response timeout", response).into())
+                }
+            }
+        })
+    }
+}
+
+/// Middleware that adds the security headers to all the responses.
+///
+/// ## See Also
+///
+/// Headers that are already set on the response, like the ones from CORS, are left as-is.
+pub struct SecurityHeaders {
+    pub content_security_policy: String,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SecurityHeaders
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SecurityHeadersMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        // Value is verified in validate_vars, so the policy is never dropped silently
+        let mut headers = vec![
+            (header::X_CONTENT_TYPE_OPTIONS, header::HeaderValue::from_static("nosniff")),
+            (header::X_FRAME_OPTIONS, header::HeaderValue::from_static("DENY")),
+            (header::REFERRER_POLICY, header::HeaderValue::from_static("no-referrer")),
+            (header::HeaderName::from_static("permissions-policy"),
+             header::HeaderValue::from_static("geolocation=(), microphone=()")),
+        ];
+        if let Ok(value) = header::HeaderValue::from_str(&self.content_security_policy) {
+            headers.push((header::CONTENT_SECURITY_POLICY, value));
+        }
+        ready(Ok(SecurityHeadersMiddleware { service, headers: sync::Arc::new(headers) }))
+    }
+}
+
+/// Service created by `SecurityHeaders` for the application.
+pub struct SecurityHeadersMiddleware<S> {
+    service: S,
+    headers: sync::Arc<Vec<(header::HeaderName, header::HeaderValue)>>,
+}
+
+impl<S, B> Service<ServiceRequest> for SecurityHeadersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let headers = self.headers.clone();
+        let future = self.service.call(request);
+        Box::pin(async move {
+            let mut response = future.await?;
+            let response_headers = response.headers_mut();
+            for (name, value) in headers.iter() {
+                if !response_headers.contains_key(name) {
+                    response_headers.insert(name.clone(), value.clone());
+                }
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, http::Method, http::StatusCode, web};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_is_cancellable() {
+        assert!(is_cancellable(&Method::GET, "/status"));
+        assert!(is_cancellable(&Method::GET, "/repos/org/repo/manifest"));
+        assert!(!is_cancellable(&Method::GET, "/clone"));
+        assert!(!is_cancellable(&Method::POST, "/backup"));
+        assert!(!is_cancellable(&Method::POST, "/backup/tar"));
+        assert!(!is_cancellable(&Method::DELETE, "/repos/org/repo/files"));
+    }
+
+    /// Handler that takes longer than the response timeout used in the tests.
+    async fn slow() -> HttpResponse {
+        tokio::time::sleep(time::Duration::from_millis(200)).await;
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_response_timeout_spares_mutating_requests() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(ResponseTimeout { duration: time::Duration::from_millis(50) })
+                .route("/status", web::get().to(slow))
+                .route("/clone", web::get().to(slow))
+                .route("/backup", web::post().to(slow))
+        ).await;
+        let request = actix_web::test::TestRequest::get().uri("/status").to_request();
+        let timed_out = actix_web::test::try_call_service(&app, request).await
+            .err().map(|err| err.as_response_error().status_code());
+        assert_eq!(timed_out, Some(StatusCode::GATEWAY_TIMEOUT));
+        let request = actix_web::test::TestRequest::get().uri("/clone").to_request();
+        assert_eq!(actix_web::test::call_service(&app, request).await.status(), StatusCode::OK);
+        let request = actix_web::test::TestRequest::post().uri("/backup").to_request();
+        assert_eq!(actix_web::test::call_service(&app, request).await.status(), StatusCode::OK);
+    }
+
+    /// Handler that sets its own content security policy.
+    async fn framed() -> HttpResponse {
+        HttpResponse::Ok().insert_header((header::CONTENT_SECURITY_POLICY, "frame-ancestors 'self'")).finish()
+    }
+
+    #[actix_web::test]
+    async fn test_security_headers() {
+        let config = squire::settings::test_config(std::env::temp_dir());
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(get_cors(&config))
+                .wrap(SecurityHeaders { content_security_policy: "default-src 'none'".to_string() })
+                .route("/status", web::get().to(HttpResponse::Ok))
+                .route("/framed", web::get().to(framed))
+        ).await;
+        let request = actix_web::test::TestRequest::get()
+            .uri("/status")
+            .insert_header((header::ORIGIN, "https://localhost.com"))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        let headers = response.headers();
+        assert_eq!(headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        assert_eq!(headers.get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+        assert_eq!(headers.get(header::REFERRER_POLICY).unwrap(), "no-referrer");
+        assert_eq!(headers.get("permissions-policy").unwrap(), "geolocation=(), microphone=()");
+        assert_eq!(headers.get(header::CONTENT_SECURITY_POLICY).unwrap(), "default-src 'none'");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://localhost.com");
+        // Headers set by the handler are not overwritten, and unmatched routes get the headers too
+        let request = actix_web::test::TestRequest::get().uri("/framed").to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.headers().get(header::CONTENT_SECURITY_POLICY).unwrap(), "frame-ancestors 'self'");
+        let request = actix_web::test::TestRequest::get().uri("/missing").to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+    }
+
+    #[actix_web::test]
+    async fn test_access_log_has_response_status() {
+        let access_log_file = std::env::temp_dir().join(format!("github-test-{}.log", uuid::Uuid::new_v4()));
+        let mut config = squire::settings::test_config(std::env::temp_dir());
+        config.access_log_file = Some(access_log_file.clone());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(sync::Arc::new(config)))
+                .app_data(web::Data::new(constant::session_info()))
+                .wrap(ResponseTimeout { duration: time::Duration::from_millis(50) })
+                .wrap(AccessLog)
+                .route("/created", web::post().to(HttpResponse::Created))
+                .route("/status", web::get().to(slow))
+        ).await;
+        for request in [
+            actix_web::test::TestRequest::post().uri("/created"),
+            actix_web::test::TestRequest::get().uri("/missing"),
+            actix_web::test::TestRequest::get().uri("/status"),
+        ] {
+            let _ = actix_web::test::try_call_service(&app, request.to_request()).await;
+        }
+        let lines: Vec<String> = std::fs::read_to_string(&access_log_file).unwrap()
+            .lines().map(str::to_string).collect();
+        assert_eq!(lines.len(), 3, "{:?}", lines);
+        assert!(lines[0].ends_with("\"POST /created HTTP/1.1\" 201 -"), "{}", lines[0]);
+        assert!(lines[1].ends_with("\"GET /missing HTTP/1.1\" 404 -"), "{}", lines[1]);
+        assert!(lines[2].ends_with("\"GET /status HTTP/1.1\" 504 -"), "{}", lines[2]);
+        std::fs::remove_file(&access_log_file).unwrap();
+    }
+}
@@ -4,6 +4,8 @@ pub mod settings;
 pub mod startup;
 /// Module for custom functions that logs connection information and builds custom error responses.
 pub mod custom;
+/// Module for the extractor that accepts request bodies encoded as either MessagePack or JSON.
+pub mod extractor;
 /// Module for the functions that yield an ASCII art to print during startup.
 pub mod ascii_art;
 /// Module for the CORS middleware configuration.
@@ -12,3 +14,13 @@ pub mod middleware;
 pub mod parser;
 /// Module that runs shell commands.
 pub mod command;
+/// Module that records the successful backup operations in an append-only log.
+pub mod transaction;
+/// Module that normalizes the line endings of file contents.
+pub mod encoding;
+/// Module that handles the partial files, written before they are moved to their destination.
+pub mod partial;
+/// Module that records the files written by backups, along with their hashes.
+pub mod manifest;
+/// Module for the content-addressed store that deduplicates downloaded files with hard links.
+pub mod blobs;
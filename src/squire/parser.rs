@@ -1,7 +1,72 @@
-use std::env;
+use std::{env, fs, path};
 use std::process::exit;
 
+use clap::{CommandFactory, FromArgMatches, Parser};
+
 use crate::constant;
+use crate::squire::settings;
+
+/// Command-line arguments supported by the application.
+#[derive(Parser)]
+#[command(about, disable_version_flag = true)]
+struct Arguments {
+    /// Custom filename to load the environment variables. Defaults to '.env'
+    #[arg(long = "env-file", alias = "env_file", value_name = "PATH")]
+    env_file: Option<String>,
+
+    /// TOML file with the configuration, each key is loaded as an environment variable.
+    #[arg(long, value_name = "PATH")]
+    config: Option<path::PathBuf>,
+
+    /// Get the package version.
+    #[arg(short = 'v', short_alias = 'V', long)]
+    version: bool,
+}
+
+/// Generates the environment variables' table, that is displayed at the end of `--help`
+fn env_var_help() -> String {
+    let mut helper = "Environment variables:\n".to_string();
+    for (name, data_type, default) in settings::ENV_VAR_DOCS {
+        helper.push_str(&format!("  {:<26} {:<12} [default: {}]\n", name, data_type, default));
+    }
+    helper
+}
+
+/// Loads the keys from a TOML file as environment variables.
+///
+/// # Arguments
+///
+/// * `filepath` - Path to the TOML file.
+///
+/// ## See Also
+///
+/// - Variables that are already set in the environment take precedence over the TOML file.
+/// - Values that are not strings are loaded in their TOML representation, so arrays match the JSON format of `websites`
+fn load_toml(filepath: &path::Path) {
+    let content = match fs::read_to_string(filepath) {
+        Ok(content) => content,
+        Err(err) => {
+            println!("Failed to read config file {:?}: {}", filepath, err);
+            exit(1)
+        }
+    };
+    let table = match content.parse::<toml::Table>() {
+        Ok(table) => table,
+        Err(err) => {
+            println!("Failed to parse config file {:?}: {}", filepath, err);
+            exit(1)
+        }
+    };
+    for (key, value) in table {
+        if env::var(&key).is_ok() {
+            continue;
+        }
+        match value {
+            toml::Value::String(value) => env::set_var(&key, value),
+            value => env::set_var(&key, value.to_string())
+        }
+    }
+}
 
 /// Parses and returns the command-line arguments.
 ///
@@ -9,44 +74,17 @@ use crate::constant;
 ///
 /// A String notion of the argument, `env_file` if present.
 pub fn arguments(metadata: &constant::MetaData) -> String {
-    let args: Vec<String> = env::args().collect();
-
-    let mut version = false;
-    let mut env_file = String::new();
-
-    // Loop through the command-line arguments and parse them.
-    let mut i = 1; // Start from the second argument (args[0] is the program name).
-    while i < args.len() {
-        match args[i].as_str() {
-            "-h" | "--help" => {
-                let helper = "GitHub takes the arguments, --env_file and --version/-v\n\n\
-                --env_file: Custom filename to load the environment variables. Defaults to '.env'\n\
-                --version: Get the package version.\n".to_string();
-                println!("Usage: {} [OPTIONS]\n\n{}", args[0], helper);
-                exit(0)
-            }
-            "-V" | "-v" | "--version" => {
-                version = true;
-            }
-            "--env_file" => {
-                i += 1; // Move to the next argument.
-                if i < args.len() {
-                    env_file = args[i].clone();
-                } else {
-                    println!("--env_file requires a value.");
-                    exit(1)
-                }
-            }
-            _ => {
-                println!("Unknown argument: {}", args[i]);
-                exit(1)
-            }
-        }
-        i += 1;
-    }
-    if version {
+    let command = Arguments::command().after_help(env_var_help());
+    let args = match Arguments::from_arg_matches(&command.get_matches()) {
+        Ok(args) => args,
+        Err(err) => err.exit()
+    };
+    if args.version {
         println!("{} {}", &metadata.pkg_name, &metadata.pkg_version);
         exit(0)
     }
-    env_file
+    if let Some(filepath) = &args.config {
+        load_toml(filepath);
+    }
+    args.env_file.unwrap_or_default()
 }
@@ -0,0 +1,115 @@
+use std::{fs, io, path, time};
+
+/// Suffix of the files that are still being written.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Returns the path to which a file is written, before it is moved to its destination.
+///
+/// # Arguments
+///
+/// * `destination` - Final path of the file.
+///
+/// ## See Also
+///
+/// Partial files are named `.{name}.{uuid}.partial` in the same directory, so that they never collide with
+/// the files in the repository, or with the partial files of another write to the same destination.
+pub fn partial_path(destination: &path::Path) -> path::PathBuf {
+    let mut filename = std::ffi::OsString::from(".");
+    filename.push(destination.file_name().unwrap_or_default());
+    filename.push(format!(".{}{}", uuid::Uuid::new_v4(), PARTIAL_SUFFIX));
+    destination.with_file_name(filename)
+}
+
+/// Checks if a filename has the reserved form of a partial file, i.e. `.{name}.{uuid}.partial`
+pub fn is_partial(filename: &str) -> bool {
+    filename.strip_prefix('.')
+        .and_then(|name| name.strip_suffix(PARTIAL_SUFFIX))
+        .and_then(|name| name.rsplit_once('.'))
+        .is_some_and(|(name, id)| !name.is_empty() && id.len() == 36 && uuid::Uuid::parse_str(id).is_ok())
+}
+
+/// Creates a partial file to write into.
+///
+/// # Arguments
+///
+/// * `partial` - Path of the partial file.
+///
+/// ## See Also
+///
+/// An existing file is never opened, since it can be a hard link to a blob in the download cache.
+///
+/// # Returns
+///
+/// Returns a `Result` with the newly created file.
+pub fn create(partial: &path::Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().write(true).create_new(true).open(partial)
+}
+
+/// Removes the partial files left behind by interrupted writes, skipping the `.git` directories.
+///
+/// Only the files in the reserved form of `partial_path` are removed, so a user's file named `*.partial` is retained.
+///
+/// # Arguments
+///
+/// * `directory` - Directory to scan recursively.
+/// * `max_age` - Partial files older than this are considered orphaned.
+///
+/// # Returns
+///
+/// Returns the number of partial files removed.
+pub fn remove_partial_files(directory: &path::Path, max_age: time::Duration) -> usize {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return 0
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let filepath = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() && entry.file_name() != ".git" => {
+                removed += remove_partial_files(&filepath, max_age);
+            }
+            Ok(file_type) if file_type.is_file() => {
+                let orphaned = is_partial(&entry.file_name().to_string_lossy())
+                    && entry.metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > max_age));
+                if orphaned {
+                    match fs::remove_file(&filepath) {
+                        Ok(_) => {
+                            log::info!("Removed orphaned partial file {:?}", filepath);
+                            removed += 1;
+                        }
+                        Err(err) => log::error!("Error removing partial file {:?}: {}", filepath, err)
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_remove_partial_files() {
+        let root = std::env::temp_dir().join(format!("github-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(root.join("org/repo/.git")).unwrap();
+        let orphaned = partial_path(&root.join("org/repo/file.txt"));
+        assert!(is_partial(&orphaned.file_name().unwrap().to_string_lossy()));
+        fs::write(&orphaned, "orphaned").unwrap();
+        // User's files that only end with the suffix are never removed
+        fs::write(root.join("org/repo/notes.partial"), "notes").unwrap();
+        fs::write(root.join("org/repo/.file.txt.partial"), "hidden").unwrap();
+        fs::write(partial_path(&root.join("org/repo/.git/index")), "index").unwrap();
+        assert_eq!(remove_partial_files(&root, time::Duration::ZERO), 1);
+        assert!(!orphaned.exists());
+        assert!(root.join("org/repo/notes.partial").exists());
+        assert!(root.join("org/repo/.file.txt.partial").exists());
+        assert_eq!(fs::read_dir(root.join("org/repo/.git")).unwrap().count(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
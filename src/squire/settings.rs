@@ -1,7 +1,8 @@
-use std::{path, thread};
+use std::{fs, path, thread};
 use std::net::ToSocketAddrs;
 
 /// Represents the configuration parameters for GitHub.
+#[derive(Clone)]
 pub struct Config {
     /// Dictionary of key-value pairs for authorization (username and password).
     pub authorization: String,
@@ -17,14 +18,84 @@ pub struct Config {
     /// Server port number.
     pub server_port: u16,
 
+    /// File to write the logs as JSON lines, in addition to stderr.
+    pub log_file: Option<path::PathBuf>,
+    /// File to write an access log line for each request, in the combined log format.
+    pub access_log_file: Option<path::PathBuf>,
+    /// Socket address to bind the server, overrides the host and port.
+    pub bind_address: Option<std::net::SocketAddr>,
+    /// Duration (in milliseconds) after which a request is answered with a gateway timeout.
+    pub response_timeout_ms: u64,
     /// Number of worker threads to spin up the server.
     pub workers: usize,
-    /// Maximum number of concurrent connections.
+    /// Number of worker threads per logical core, used only when `workers` is not set.
+    pub workers_per_core: Option<f64>,
+    /// Maximum number of concurrent connections per worker.
     pub max_connections: usize,
     /// Max payload allowed by the server in request body.
     pub max_payload_size: usize,
-    /// List of websites (supports regex) to add to CORS configuration.
-    pub websites: Vec<String>,
+    /// Hostname of the git server to clone repositories from.
+    pub git_host: String,
+    /// Scheme used in the clone URL.
+    pub git_clone_scheme: String,
+    /// Boolean flag to allow only HTTPS in the clone URL.
+    pub require_https: bool,
+    /// Token to access private repositories on GitHub.
+    pub github_token: Option<String>,
+    /// Private key file used by git to authenticate over SSH.
+    pub ssh_key_file: Option<path::PathBuf>,
+    /// ID of the GitHub App, whose installation tokens are accepted as the bearer token.
+    pub github_app_id: Option<u64>,
+    /// Private key file of the GitHub App in PEM format, used to sign the JWT for the GitHub API.
+    pub github_app_private_key_file: Option<path::PathBuf>,
+    /// Maximum size (in MB) of a repository that can be cloned.
+    pub max_clone_size_mb: Option<u64>,
+    /// Duration (in milliseconds) within which an identical backup payload is ignored.
+    pub dedup_window_ms: u64,
+    /// Number of times a download is retried on transient errors.
+    pub download_retry_count: u32,
+    /// Initial delay (in milliseconds) between download retries, doubled after each attempt.
+    pub download_retry_delay_ms: u64,
+    /// Number of times a filesystem operation is retried on transient errors, e.g. on network filesystems.
+    pub io_retry_count: u32,
+    /// Shell command to run after a successful backup, supports `{repository}` and `{timestamp}` placeholders.
+    pub post_backup_hook: Option<String>,
+    /// URL to notify with a `POST` request after a successful backup.
+    pub backup_notification_url: Option<String>,
+    /// Secret to sign the notification body with, sent in the `X-Hub-Signature-256` header.
+    pub backup_notification_secret: Option<String>,
+    /// Boolean flag to print ASCII art at startup.
+    pub show_ascii_art: bool,
+    /// Path to a file with custom ASCII art, printed instead of the bundled art.
+    pub ascii_art_file: Option<path::PathBuf>,
+    /// Maximum number of components allowed in a file path within the backup payload.
+    pub max_path_depth: usize,
+    /// Maximum length of a repository name in `{org}/{repo}` format, each part is limited to half of it.
+    pub max_repo_name_length: usize,
+    /// Maximum number of files downloaded concurrently in a single backup.
+    pub max_concurrent_downloads: usize,
+    /// Maximum number of file operations allowed in a single backup payload.
+    pub max_operations_per_backup: usize,
+    /// Networks (in CIDR notation) that are allowed to access the admin endpoints.
+    pub admin_allowed_ips: Option<Vec<ipnet::IpNet>>,
+    /// Age (in seconds) after which partial files left by interrupted writes are removed at startup.
+    pub partial_file_max_age_seconds: u64,
+    /// Minimum free space (in bytes) required on the data source's filesystem to accept a backup.
+    pub min_free_disk_bytes: u64,
+    /// Hosts that files in `download_urls` are allowed to be downloaded from.
+    pub download_allowed_domains: Vec<String>,
+    /// List of websites to add to CORS configuration, as exact origins or regex patterns.
+    pub websites: Vec<CorsOrigin>,
+    /// Response headers that browsers are allowed to read in cross-origin requests.
+    pub cors_expose_headers: Vec<String>,
+    /// Duration (in seconds) for which browsers can cache the response to a CORS preflight request.
+    pub cors_preflight_cache_seconds: u32,
+    /// Value of the `Content-Security-Policy` header, added to all the responses.
+    pub content_security_policy: String,
+    /// Header to read the token from, either `authorization` or `x-api-key`
+    pub preferred_auth_header: String,
+    /// Boolean flag to read the client's IP address from the headers set by a reverse proxy.
+    pub trust_proxy: bool,
 
     /// Path to the private key file for SSL certificate
     pub key_file: path::PathBuf,
@@ -32,12 +103,199 @@ pub struct Config {
     pub cert_file: path::PathBuf,
 }
 
+impl Config {
+    /// Returns the address to bind the server, `bind_address` if set, or the host and port combined.
+    pub fn address(&self) -> String {
+        match &self.bind_address {
+            Some(address) => address.to_string(),
+            None => format!("{}:{}", self.server_host, self.server_port)
+        }
+    }
+}
+
+/// Builds a `Config` with the default values, for tests that cannot set the environment variables.
+///
+/// # Arguments
+///
+/// * `github_source` - GitHub source directory.
+#[cfg(test)]
+pub fn test_config(github_source: path::PathBuf) -> Config {
+    Config {
+        authorization: "test-token".to_string(),
+        github_source,
+        debug: default_debug(),
+        utc_logging: default_utc_logging(),
+        server_host: "127.0.0.1".to_string(),
+        server_port: default_server_port(),
+        log_file: None,
+        access_log_file: None,
+        bind_address: None,
+        response_timeout_ms: default_response_timeout_ms(),
+        workers: 1,
+        workers_per_core: None,
+        max_connections: default_max_connections(1),
+        max_payload_size: default_max_payload_size(),
+        git_host: default_git_host(),
+        git_clone_scheme: default_git_clone_scheme(),
+        require_https: default_require_https(),
+        github_token: None,
+        ssh_key_file: None,
+        github_app_id: None,
+        github_app_private_key_file: None,
+        max_clone_size_mb: None,
+        dedup_window_ms: default_dedup_window_ms(),
+        download_retry_count: default_download_retry_count(),
+        download_retry_delay_ms: default_download_retry_delay_ms(),
+        io_retry_count: default_io_retry_count(),
+        post_backup_hook: None,
+        backup_notification_url: None,
+        backup_notification_secret: None,
+        show_ascii_art: false,
+        ascii_art_file: None,
+        max_path_depth: default_max_path_depth(),
+        max_repo_name_length: default_max_repo_name_length(),
+        max_concurrent_downloads: default_max_concurrent_downloads(),
+        max_operations_per_backup: default_max_operations_per_backup(),
+        admin_allowed_ips: None,
+        partial_file_max_age_seconds: default_partial_file_max_age_seconds(),
+        min_free_disk_bytes: 0,
+        download_allowed_domains: default_download_allowed_domains(),
+        websites: default_websites(),
+        cors_expose_headers: default_cors_expose_headers(),
+        cors_preflight_cache_seconds: default_cors_preflight_cache_seconds(),
+        content_security_policy: default_content_security_policy(),
+        preferred_auth_header: default_preferred_auth_header(),
+        trust_proxy: default_trust_proxy(),
+        key_file: default_ssl(),
+        cert_file: default_ssl(),
+    }
+}
+
+// Fails to compile if a field is added to `Config` that does not implement `Clone`
+const _: fn(&Config) -> Config = Config::clone;
+
+/// Documentation for the supported environment variables, as `(name, type, default)`
+///
+/// ## See Also
+///
+/// Used to generate the `--help` output and the `/config/schema` endpoint.
+pub const ENV_VAR_DOCS: &[(&str, &str, &str)] = &[
+    ("authorization", "String", "required"),
+    ("github_source", "PathBuf", "required"),
+    ("env_file", "String", ".env"),
+    ("debug", "bool", "false"),
+    ("utc_logging", "bool", "true"),
+    ("log_file", "PathBuf", "none"),
+    ("access_log_file", "PathBuf", "none"),
+    ("server_host", "String", "IP address of localhost"),
+    ("server_port", "u16", "8000"),
+    ("bind_address", "SocketAddr", "none"),
+    ("workers", "usize", "half of the logical cores, limited by memory"),
+    ("workers_per_core", "f64", "none"),
+    ("max_connections", "usize", "100 per worker, between 10 and 10000"),
+    ("max_payload_size", "String", "100 MB"),
+    ("response_timeout_ms", "u64", "30000"),
+    ("websites", "Vec<String>", "[]"),
+    ("cors_expose_headers", "Vec<String>", "[\"X-Request-ID\"]"),
+    ("cors_preflight_cache_seconds", "u32", "3600"),
+    ("download_allowed_domains", "Vec<String>", "[]"),
+    ("content_security_policy", "String", "default-src 'none'"),
+    ("preferred_auth_header", "String", "authorization"),
+    ("trust_proxy", "bool", "false"),
+    ("admin_allowed_ips", "Vec<String>", "none"),
+    ("git_host", "String", "github.com"),
+    ("git_clone_scheme", "String", "https"),
+    ("require_https", "bool", "true"),
+    ("github_token", "String", "none"),
+    ("ssh_key_file", "PathBuf", "none"),
+    ("github_app_id", "u64", "none"),
+    ("github_app_private_key_file", "PathBuf", "none"),
+    ("max_clone_size_mb", "u64", "none"),
+    ("dedup_window_ms", "u64", "1000"),
+    ("download_retry_count", "u32", "3"),
+    ("download_retry_delay_ms", "u64", "500"),
+    ("io_retry_count", "u32", "3"),
+    ("post_backup_hook", "String", "none"),
+    ("backup_notification_url", "String", "none"),
+    ("backup_notification_secret", "String", "none"),
+    ("max_path_depth", "usize", "20"),
+    ("max_repo_name_length", "usize", "200"),
+    ("max_operations_per_backup", "usize", "1000"),
+    ("max_concurrent_downloads", "usize", "4"),
+    ("partial_file_max_age_seconds", "u64", "3600"),
+    ("min_free_disk_bytes", "u64", "524288000"),
+    ("show_ascii_art", "bool", "true"),
+    ("ascii_art_file", "PathBuf", "none"),
+    ("key_file", "PathBuf", "none"),
+    ("cert_file", "PathBuf", "none"),
+];
+
+/// Placeholders that can be used in the `post_backup_hook` command.
+pub const HOOK_PLACEHOLDERS: &[&str] = &["repository", "timestamp"];
+
+/// Minimum length of a repository name, e.g. `a/b`
+pub const MIN_REPO_NAME_LENGTH: usize = 3;
+
 /// Returns the default value for debug flag.
 pub fn default_debug() -> bool { false }
 
 /// Returns the default value for UTC logging.
 pub fn default_utc_logging() -> bool { true }
 
+/// Returns the default value for the `Content-Security-Policy` header, since the responses are never rendered as pages.
+pub fn default_content_security_policy() -> String { "default-src 'none'".to_string() }
+
+/// Returns the default value for the header to read the token from.
+pub fn default_preferred_auth_header() -> String { "authorization".to_string() }
+
+/// Returns the default value for response timeout.
+pub fn default_response_timeout_ms() -> u64 { 30_000 }
+
+/// Returns the default value for trusting the reverse proxy headers.
+pub fn default_trust_proxy() -> bool { false }
+
+/// Returns the default value for requiring HTTPS in the clone URL.
+pub fn default_require_https() -> bool { true }
+
+/// Returns the default value for maximum path depth.
+pub fn default_max_path_depth() -> usize { 20 }
+
+/// Returns the default value for maximum length of a repository name.
+pub fn default_max_repo_name_length() -> usize { 200 }
+
+/// Returns the default value for maximum operations per backup.
+pub fn default_max_operations_per_backup() -> usize { 1000 }
+
+/// Returns the default value for concurrent downloads.
+pub fn default_max_concurrent_downloads() -> usize { 4 }
+
+/// Returns the default value for the age of orphaned partial files.
+pub fn default_partial_file_max_age_seconds() -> u64 { 3600 }
+
+/// Returns the default minimum free disk space (500 MB)
+pub fn default_min_free_disk_bytes() -> u64 { 500 * 1024 * 1024 }
+
+/// Returns the default value for printing ASCII art at startup.
+pub fn default_show_ascii_art() -> bool { true }
+
+/// Returns the default git host (github.com)
+pub fn default_git_host() -> String { "github.com".to_string() }
+
+/// Returns the default scheme for clone URLs (https)
+pub fn default_git_clone_scheme() -> String { "https".to_string() }
+
+/// Returns the default window for duplicate backup payloads (1000 ms)
+pub fn default_dedup_window_ms() -> u64 { 1000 }
+
+/// Returns the default number of download retries (3)
+pub fn default_download_retry_count() -> u32 { 3 }
+
+/// Returns the default delay between download retries (500 ms)
+pub fn default_download_retry_delay_ms() -> u64 { 500 }
+
+/// Returns the default number of retries for filesystem operations that fail with transient errors.
+pub fn default_io_retry_count() -> u32 { 3 }
+
 /// Returns the default value for SSL files.
 pub fn default_ssl() -> path::PathBuf { path::PathBuf::new() }
 
@@ -60,23 +318,190 @@ pub fn default_server_host() -> String {
 /// Returns the default server port (8000)
 pub fn default_server_port() -> u16 { 8000 }
 
-/// Returns the default number of worker threads (half of logical cores)
-pub fn default_workers() -> usize {
-    let logical_cores = thread::available_parallelism();
-    match logical_cores {
+/// Reads the available memory (in MB) from `/proc/meminfo`
+///
+/// # Returns
+///
+/// Returns an `Option<u64>` if the available memory could be determined.
+pub fn available_memory_mb() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo.lines()
+        .find(|line| line.starts_with("MemAvailable:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb / 1024)
+}
+
+/// Computes the number of worker threads based on the logical cores and the available memory.
+///
+/// ## See Also
+///
+/// - Half of the logical cores are used when memory is not a constraint.
+/// - Each worker is budgeted 64 MB, since git operations are memory intensive.
+/// - Result is clamped between 1 and 32.
+///
+/// # Returns
+///
+/// Returns a tuple of the number of workers and the reasoning behind it.
+pub fn auto_workers() -> (usize, String) {
+    let cpu_workers = match thread::available_parallelism() {
         Ok(cores) => cores.get() / 2,
         Err(err) => {
             log::error!("{}", err);
             3
         }
-    }
+    };
+    let (workers, reason) = match available_memory_mb() {
+        Some(memory_mb) if ((memory_mb / 64) as usize) < cpu_workers => (
+            (memory_mb / 64) as usize,
+            format!("memory-bound with {} MB available", memory_mb)
+        ),
+        _ => (cpu_workers, "cpu-bound with half of the logical cores".to_string())
+    };
+    (workers.clamp(1, 32), reason)
+}
+
+/// Returns the default number of worker threads, based on CPU and memory.
+pub fn default_workers() -> usize { auto_workers().0 }
+
+/// Checks if the number of workers is more than twice the number of logical cores.
+pub fn is_over_provisioned(workers: usize, cores: usize) -> bool {
+    workers > cores.saturating_mul(2)
+}
+
+/// Computes the number of worker threads as a multiple of the logical cores.
+///
+/// # Arguments
+///
+/// * `cores` - Number of logical cores.
+/// * `workers_per_core` - Number of workers per core, which can be fractional.
+///
+/// ## See Also
+///
+/// The result is rounded to the nearest whole number, and clamped between 1 and 1024.
+///
+/// # Returns
+///
+/// Returns the number of workers.
+pub fn workers_for_cores(cores: usize, workers_per_core: f64) -> usize {
+    ((cores as f64 * workers_per_core).round() as usize).clamp(1, 1024)
 }
 
-/// Returns the default maximum number of concurrent connections (3)
-pub fn default_max_connections() -> usize { 3 }
+/// Returns the default maximum number of concurrent connections, 100 per worker between 10 and 10,000
+///
+/// ## See Also
+///
+/// The value is applied to `HttpServer::max_connections`, which is a limit per worker,
+/// so the server accepts up to `max_connections * workers` connections in total.
+pub fn default_max_connections(workers: usize) -> usize { workers.saturating_mul(100).clamp(10, 10_000) }
 
 /// Returns the default max payload size (100 MB)
 pub fn default_max_payload_size() -> usize { 100 * 1024 * 1024 }
 
 /// Returns an empty list as the default website (CORS configuration)
-pub fn default_websites() -> Vec<String> { Vec::new() }
+pub fn default_websites() -> Vec<CorsOrigin> { Vec::new() }
+
+/// Returns the default headers exposed to browsers in cross-origin requests.
+pub fn default_cors_expose_headers() -> Vec<String> { vec!["X-Request-ID".to_string()] }
+
+/// Returns the default duration (in seconds) to cache the CORS preflight responses (1 hour)
+pub fn default_cors_preflight_cache_seconds() -> u32 { 3600 }
+
+/// Returns an empty list as the default allowed download domains, so custom download URLs are rejected
+pub fn default_download_allowed_domains() -> Vec<String> { Vec::new() }
+
+/// Verifies that the certificate and the private key can be loaded, and belong to each other.
+///
+/// # Arguments
+///
+/// * `key_file` - Path to the private key file in PEM format.
+/// * `cert_file` - Path to the certificate chain file in PEM format.
+///
+/// # Returns
+///
+/// Returns a `Result` with the reason as a `String`, if the pair is unusable.
+pub fn validate_tls_pair(key_file: &path::Path, cert_file: &path::Path) -> Result<(), String> {
+    let key_pem = fs::read(key_file)
+        .map_err(|err| format!("unable to read key_file {:?}: {}", key_file, err))?;
+    let cert_pem = fs::read(cert_file)
+        .map_err(|err| format!("unable to read cert_file {:?}: {}", cert_file, err))?;
+    let private_key = openssl::pkey::PKey::private_key_from_pem(&key_pem)
+        .map_err(|err| format!("key_file {:?} is not a valid PEM private key: {}", key_file, err))?;
+    let certificate = openssl::x509::X509::from_pem(&cert_pem)
+        .map_err(|err| format!("cert_file {:?} is not a valid PEM certificate: {}", cert_file, err))?;
+    let public_key = certificate.public_key()
+        .map_err(|err| format!("unable to read the public key from cert_file {:?}: {}", cert_file, err))?;
+    if !public_key.public_eq(&private_key) {
+        return Err(format!("key_file {:?} does not match the certificate in cert_file {:?}", key_file, cert_file));
+    }
+    Ok(())
+}
+
+/// Origin allowed by the CORS configuration.
+#[derive(Clone, Debug)]
+pub enum CorsOrigin {
+    /// Origin compared with string equality, e.g. `https://example.com`
+    Exact(String),
+    /// Origin matched against a regular expression, e.g. `^https://.*\.example\.com$`
+    Pattern(regex::Regex),
+}
+
+impl CorsOrigin {
+    /// Parses an entry from `websites` as an exact origin or a regex pattern.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - Website as it was configured.
+    ///
+    /// ## See Also
+    ///
+    /// Entries starting with `^` or containing `.*` are compiled as patterns, everything else must be
+    /// an `http` or `https` URL without a path.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` with the reason as a `String`, if the entry is unusable.
+    pub fn parse(entry: &str) -> Result<Self, String> {
+        if entry.starts_with('^') || entry.contains(".*") {
+            return regex::Regex::new(entry)
+                .map(CorsOrigin::Pattern)
+                .map_err(|err| format!("'{}' is not a valid regex: {}", entry, err));
+        }
+        let url = reqwest::Url::parse(entry)
+            .map_err(|err| format!("'{}' is not a valid URL: {}", entry, err))?;
+        if !["http", "https"].contains(&url.scheme()) {
+            return Err(format!("'{}' must use the http:// or https:// scheme", entry));
+        }
+        if url.path() != "/" || url.query().is_some() || url.fragment().is_some() {
+            return Err(format!("'{}' must not have a path beyond '/'", entry));
+        }
+        Ok(CorsOrigin::Exact(url.origin().ascii_serialization()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_is_over_provisioned() {
+        assert!(!is_over_provisioned(1, 1));
+        assert!(!is_over_provisioned(2, 1));
+        assert!(is_over_provisioned(3, 1));
+        assert!(!is_over_provisioned(8, 4));
+        assert!(is_over_provisioned(64, 4));
+    }
+
+    #[actix_web::test]
+    async fn test_workers_for_cores() {
+        let cases = [
+            (1, 0.25, 1), (1, 1.0, 1), (1, 2.5, 3), (1, 4.0, 4),
+            (4, 0.25, 1), (4, 0.5, 2), (4, 1.5, 6), (4, 4.0, 16),
+            (8, 0.25, 2), (8, 0.3, 2), (8, 0.75, 6), (8, 1.0, 8), (8, 4.0, 32),
+            (512, 4.0, 1024),
+        ];
+        for (cores, workers_per_core, expected) in cases {
+            assert_eq!(workers_for_cores(cores, workers_per_core), expected, "{} x {}", cores, workers_per_core);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std;
 use std::io::Write;
+use std::net::ToSocketAddrs;
 
 use chrono::{DateTime, Local};
 
@@ -12,7 +13,13 @@ use crate::squire::settings;
 ///
 /// * `debug` - A flag indicating whether to enable debug mode for detailed logging.
 /// * `crate_name` - Name of the crate loaded during compile time.
-pub fn init_logger(debug: bool, utc: bool, crate_name: &String) {
+/// * `log_file` - Optional file to write the logs as JSON lines, in addition to stderr.
+///
+/// ## See Also
+///
+/// - Falls back to stderr only, if the log file cannot be opened.
+/// - Colors are disabled when the `NO_COLOR` environment variable is set.
+pub fn init_logger(debug: bool, utc: bool, crate_name: &String, log_file: Option<&std::path::Path>) {
     if debug {
         std::env::set_var("RUST_LOG", format!(
             "actix_web=debug,actix_server=info,{}=debug", crate_name
@@ -25,11 +32,44 @@ pub fn init_logger(debug: bool, utc: bool, crate_name: &String) {
         ));
         std::env::set_var("RUST_BACKTRACE", "0");
     }
-    if utc {
-        env_logger::init();
-    } else {
-        env_logger::Builder::from_default_env()
-            .format(|buf, record| {
+    let opened = log_file.map(|filepath| {
+        std::fs::OpenOptions::new().create(true).append(true).open(filepath)
+    });
+    let (file_writer, file_error) = match opened {
+        // LineWriter flushes each entry, so the file is complete even if the server crashes
+        Some(Ok(file)) => (Some(std::sync::Mutex::new(std::io::LineWriter::new(file))), None),
+        Some(Err(err)) => (None, Some(err)),
+        None => (None, None)
+    };
+    let mut builder = env_logger::Builder::from_default_env();
+    // https://no-color.org - suppress the escape sequences, even when writing to a terminal
+    if std::env::var("NO_COLOR").is_ok() {
+        builder.write_style(env_logger::WriteStyle::Never);
+    }
+    if !utc || file_writer.is_some() {
+        builder
+            .format(move |buf, record| {
+                if let Some(writer) = &file_writer {
+                    let entry = serde_json::json!({
+                        "timestamp": chrono::Utc::now().to_rfc3339(),
+                        "level": record.level().to_string(),
+                        "target": record.target(),
+                        "message": record.args().to_string()
+                    });
+                    if let Ok(mut writer) = writer.lock() {
+                        let _ = writeln!(writer, "{}", entry);
+                    }
+                }
+                if utc {
+                    return writeln!(
+                        buf,
+                        "[{} {:<5} {}] {}",
+                        buf.timestamp(),
+                        record.level(),
+                        record.target(),
+                        record.args()
+                    );
+                }
                 let local_time: DateTime<Local> = Local::now();
                 writeln!(
                     buf,
@@ -39,8 +79,13 @@ pub fn init_logger(debug: bool, utc: bool, crate_name: &String) {
                     record.target(),
                     record.args()
                 )
-            })
-            .init();
+            });
+    }
+    builder.init();
+    match (log_file, file_error) {
+        (Some(filepath), Some(err)) => log::warn!("Unable to write logs to {:?}, using stderr only: {}", filepath, err),
+        (Some(filepath), None) => log::info!("Writing logs to {:?}", filepath),
+        _ => {}
     }
 }
 
@@ -83,14 +128,20 @@ fn mandatory_vars() -> (String, std::path::PathBuf) {
 ///
 /// Returns an `Option<bool>` if the value is available.
 ///
+/// ## See Also
+///
+/// Values are case-insensitive, `true`, `1`, `yes`, `on` and `enabled` are truthy,
+/// while `false`, `0`, `no`, `off` and `disabled` are falsy.
+///
 /// # Panics
 ///
 /// If the value is present, but it is an invalid data-type.
 fn parse_bool(key: &str) -> Option<bool> {
     match std::env::var(key) {
-        Ok(val) => match val.parse() {
-            Ok(parsed) => Some(parsed),
-            Err(_) => {
+        Ok(val) => match val.trim().to_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" | "enabled" => Some(true),
+            "false" | "0" | "no" | "off" | "disabled" => Some(false),
+            _ => {
                 panic!("\n{}\n\texpected bool, received '{}' [value=invalid]\n", key, val);
             }
         },
@@ -148,6 +199,104 @@ fn parse_usize(key: &str) -> Option<usize> {
     }
 }
 
+/// Extracts the env var by key and parses it as a `usize` within the given bounds
+///
+/// # Arguments
+///
+/// * `key` - Key for the environment variable.
+/// * `min` - Minimum value allowed (inclusive).
+/// * `max` - Maximum value allowed (inclusive).
+///
+/// # Returns
+///
+/// Returns an `Option<usize>` if the value is available.
+///
+/// # Panics
+///
+/// If the value is present, but it is an invalid data-type or outside the bounds.
+fn parse_usize_bounded(key: &str, min: usize, max: usize) -> Option<usize> {
+    let parsed = parse_usize(key)?;
+    if parsed < min || parsed > max {
+        panic!("\n{}\n\texpected value between {} and {}, received '{}' [value=invalid]\n", key, min, max, parsed);
+    }
+    Some(parsed)
+}
+
+/// Extracts the env var by key and parses it as a `f64` within the given bounds
+///
+/// # Arguments
+///
+/// * `key` - Key for the environment variable.
+/// * `min` - Minimum value allowed (inclusive).
+/// * `max` - Maximum value allowed (inclusive).
+///
+/// # Returns
+///
+/// Returns an `Option<f64>` if the value is available.
+///
+/// # Panics
+///
+/// If the value is present, but it is an invalid data-type or outside the bounds.
+fn parse_f64_bounded(key: &str, min: f64, max: f64) -> Option<f64> {
+    let value = std::env::var(key).ok()?;
+    match value.trim().parse::<f64>() {
+        // NaN fails the range check, so it is rejected along with the values out of bounds
+        Ok(parsed) if (min..=max).contains(&parsed) => Some(parsed),
+        Ok(_) => panic!("\n{}\n\texpected value between {} and {}, received '{}' [value=invalid]\n", key, min, max, value),
+        Err(_) => panic!("\n{}\n\texpected f64, received '{}' [value=invalid]\n", key, value)
+    }
+}
+
+/// Extracts the env var by key and parses it as a `u32`
+///
+/// # Arguments
+///
+/// * `key` - Key for the environment variable.
+///
+/// # Returns
+///
+/// Returns an `Option<u32>` if the value is available.
+///
+/// # Panics
+///
+/// If the value is present, but it is an invalid data-type.
+fn parse_u32(key: &str) -> Option<u32> {
+    match std::env::var(key) {
+        Ok(val) => match val.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                panic!("\n{}\n\texpected u32, received '{}' [value=invalid]\n", key, val);
+            }
+        },
+        Err(_) => None,
+    }
+}
+
+/// Extracts the env var by key and parses it as a `u64`
+///
+/// # Arguments
+///
+/// * `key` - Key for the environment variable.
+///
+/// # Returns
+///
+/// Returns an `Option<u64>` if the value is available.
+///
+/// # Panics
+///
+/// If the value is present, but it is an invalid data-type.
+fn parse_u64(key: &str) -> Option<u64> {
+    match std::env::var(key) {
+        Ok(val) => match val.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                panic!("\n{}\n\texpected u64, received '{}' [value=invalid]\n", key, val);
+            }
+        },
+        Err(_) => None,
+    }
+}
+
 /// Extracts the env var by key and parses it as a `Vec<String>`
 ///
 /// # Arguments
@@ -158,6 +307,10 @@ fn parse_usize(key: &str) -> Option<usize> {
 ///
 /// Returns an `Option<Vec<String>>` if the value is available.
 ///
+/// ## See Also
+///
+/// Values that are not a JSON array are read as comma-separated, e.g. `a, b` is the same as `["a","b"]`
+///
 /// # Panics
 ///
 /// If the value is present, but it is an invalid data-type.
@@ -165,14 +318,96 @@ fn parse_vec(key: &str) -> Option<Vec<String>> {
     match std::env::var(key) {
         Ok(val) => match serde_json::from_str::<Vec<String>>(&val) {
             Ok(parsed) => Some(parsed),
-            Err(_) => {
+            // Malformed JSON is rejected, instead of being split into fragments of the array
+            Err(_) if val.trim_start().starts_with('[') => {
                 panic!("\n{}\n\texpected vec, received '{}' [value=invalid]\n", key, val);
             }
+            Err(_) => Some(
+                val.split(',')
+                    .map(|item| item.trim().to_string())
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            ),
         },
         Err(_) => None,
     }
 }
 
+/// Extracts the env var by key and parses it as a `SocketAddr`
+///
+/// # Arguments
+///
+/// * `key` - Key for the environment variable.
+///
+/// # Returns
+///
+/// Returns an `Option<SocketAddr>` if the value is available.
+///
+/// # Panics
+///
+/// If the value is present, but it is not a valid socket address, e.g. `127.0.0.1:8000` or `[::1]:8000`
+fn parse_socket_addr(key: &str) -> Option<std::net::SocketAddr> {
+    match std::env::var(key) {
+        Ok(val) => match val.parse::<std::net::SocketAddr>() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                panic!("\n{}\n\texpected socket address like '[::1]:8000', received '{}' [value=invalid]\n", key, val);
+            }
+        },
+        Err(_) => None,
+    }
+}
+
+/// Extracts the env var by key and parses it as a list of networks in CIDR notation
+///
+/// # Arguments
+///
+/// * `key` - Key for the environment variable.
+///
+/// ## See Also
+///
+/// IP addresses without a prefix length are treated as a network with a single address.
+///
+/// # Returns
+///
+/// Returns an `Option<Vec<IpNet>>` if the value is available.
+///
+/// # Panics
+///
+/// If any of the values is neither a network nor an IP address.
+fn parse_networks(key: &str) -> Option<Vec<ipnet::IpNet>> {
+    parse_vec(key).map(|values| values.iter().map(|value| {
+        value.parse::<ipnet::IpNet>()
+            .or_else(|_| value.parse::<std::net::IpAddr>().map(ipnet::IpNet::from))
+            .unwrap_or_else(|_| panic!("\n{}\n\texpected CIDR notation, received '{}' [value=invalid]\n", key, value))
+    }).collect())
+}
+
+/// Extracts the env var by key and parses it as a list of CORS origins
+///
+/// # Arguments
+///
+/// * `key` - Key for the environment variable.
+///
+/// # Returns
+///
+/// Returns an `Option<Vec<CorsOrigin>>` if the value is available.
+///
+/// # Panics
+///
+/// If any of the values is not a valid origin, with the reasons for all the invalid values.
+fn parse_websites(key: &str) -> Option<Vec<settings::CorsOrigin>> {
+    let parsed: Vec<Result<settings::CorsOrigin, String>> = parse_vec(key)?.iter()
+        .map(|value| settings::CorsOrigin::parse(value))
+        .collect();
+    let reasons: Vec<&String> = parsed.iter().filter_map(|origin| origin.as_ref().err()).collect();
+    if !reasons.is_empty() {
+        let reasons: Vec<&str> = reasons.iter().map(|reason| reason.as_str()).collect();
+        panic!("\n{}\n\t{} [value=invalid]\n", key, reasons.join("\n\t"));
+    }
+    Some(parsed.into_iter().filter_map(Result::ok).collect())
+}
+
 /// Extracts the env var by key and parses it as a `PathBuf`
 ///
 /// # Arguments
@@ -273,12 +508,60 @@ fn load_env_vars() -> settings::Config {
     let utc_logging = parse_bool("utc_logging").unwrap_or(settings::default_utc_logging());
     let server_host = std::env::var("server_host").unwrap_or(settings::default_server_host());
     let server_port = parse_u16("server_port").unwrap_or(settings::default_server_port());
-    let workers = parse_usize("workers").unwrap_or(settings::default_workers());
-    let max_connections = parse_usize("max_connections").unwrap_or(settings::default_max_connections());
-    let websites = parse_vec("websites").unwrap_or(settings::default_websites());
+    let bind_address = parse_socket_addr("bind_address");
+    let log_file = parse_path("log_file");
+    let access_log_file = parse_path("access_log_file");
+    let response_timeout_ms = parse_u64("response_timeout_ms").unwrap_or(settings::default_response_timeout_ms());
+    let workers_per_core = parse_f64_bounded("workers_per_core", 0.25, 4.0);
+    let workers = parse_usize_bounded("workers", 1, 1024)
+        .or_else(|| workers_per_core.and_then(|workers_per_core| {
+            std::thread::available_parallelism().ok()
+                .map(|cores| settings::workers_for_cores(cores.get(), workers_per_core))
+        }))
+        .unwrap_or(settings::default_workers());
+    let max_connections = parse_usize_bounded("max_connections", 1, 100_000).unwrap_or(settings::default_max_connections(workers));
+    let websites = parse_websites("websites").unwrap_or(settings::default_websites());
+    let cors_expose_headers = parse_vec("cors_expose_headers").unwrap_or(settings::default_cors_expose_headers());
+    let cors_preflight_cache_seconds = parse_u32("cors_preflight_cache_seconds")
+        .unwrap_or(settings::default_cors_preflight_cache_seconds());
+    let download_allowed_domains = parse_vec("download_allowed_domains")
+        .unwrap_or(settings::default_download_allowed_domains());
+    let content_security_policy = std::env::var("content_security_policy")
+        .unwrap_or(settings::default_content_security_policy());
+    let preferred_auth_header = std::env::var("preferred_auth_header")
+        .map(|value| value.to_lowercase())
+        .unwrap_or(settings::default_preferred_auth_header());
+    let trust_proxy = parse_bool("trust_proxy").unwrap_or(settings::default_trust_proxy());
+    let admin_allowed_ips = parse_networks("admin_allowed_ips");
     let key_file = parse_path("key_file").unwrap_or(settings::default_ssl());
     let cert_file = parse_path("cert_file").unwrap_or(settings::default_ssl());
     let max_payload_size = parse_max_payload("max_payload_size").unwrap_or(settings::default_max_payload_size());
+    let git_host = std::env::var("git_host").unwrap_or(settings::default_git_host());
+    let git_clone_scheme = std::env::var("git_clone_scheme").unwrap_or(settings::default_git_clone_scheme());
+    let require_https = parse_bool("require_https").unwrap_or(settings::default_require_https());
+    let github_token = std::env::var("github_token").ok();
+    let ssh_key_file = parse_path("ssh_key_file");
+    let github_app_id = parse_u64("github_app_id");
+    let github_app_private_key_file = parse_path("github_app_private_key_file");
+    let max_clone_size_mb = parse_u64("max_clone_size_mb");
+    let dedup_window_ms = parse_u64("dedup_window_ms").unwrap_or(settings::default_dedup_window_ms());
+    let download_retry_count = parse_u32("download_retry_count").unwrap_or(settings::default_download_retry_count());
+    let download_retry_delay_ms = parse_u64("download_retry_delay_ms").unwrap_or(settings::default_download_retry_delay_ms());
+    let io_retry_count = parse_u32("io_retry_count").unwrap_or(settings::default_io_retry_count());
+    let post_backup_hook = std::env::var("post_backup_hook").ok();
+    let backup_notification_url = std::env::var("backup_notification_url").ok();
+    let backup_notification_secret = std::env::var("backup_notification_secret").ok();
+    let max_path_depth = parse_usize("max_path_depth").unwrap_or(settings::default_max_path_depth());
+    let max_repo_name_length = parse_usize("max_repo_name_length").unwrap_or(settings::default_max_repo_name_length());
+    let max_operations_per_backup = parse_usize("max_operations_per_backup")
+        .unwrap_or(settings::default_max_operations_per_backup());
+    let max_concurrent_downloads = parse_usize_bounded("max_concurrent_downloads", 1, 64)
+        .unwrap_or(settings::default_max_concurrent_downloads());
+    let partial_file_max_age_seconds = parse_u64("partial_file_max_age_seconds")
+        .unwrap_or(settings::default_partial_file_max_age_seconds());
+    let min_free_disk_bytes = parse_u64("min_free_disk_bytes").unwrap_or(settings::default_min_free_disk_bytes());
+    let show_ascii_art = parse_bool("show_ascii_art").unwrap_or(settings::default_show_ascii_art());
+    let ascii_art_file = parse_path("ascii_art_file");
     settings::Config {
         authorization,
         github_source,
@@ -286,10 +569,45 @@ fn load_env_vars() -> settings::Config {
         utc_logging,
         server_host,
         server_port,
+        bind_address,
+        log_file,
+        access_log_file,
+        response_timeout_ms,
         workers,
+        workers_per_core,
         max_connections,
         max_payload_size,
+        git_host,
+        git_clone_scheme,
+        require_https,
+        github_token,
+        ssh_key_file,
+        github_app_id,
+        github_app_private_key_file,
+        max_clone_size_mb,
+        dedup_window_ms,
+        download_retry_count,
+        download_retry_delay_ms,
+        io_retry_count,
+        post_backup_hook,
+        backup_notification_url,
+        backup_notification_secret,
+        show_ascii_art,
+        ascii_art_file,
+        max_path_depth,
+        max_repo_name_length,
+        max_operations_per_backup,
+        max_concurrent_downloads,
+        partial_file_max_age_seconds,
+        min_free_disk_bytes,
         websites,
+        cors_expose_headers,
+        cors_preflight_cache_seconds,
+        download_allowed_domains,
+        content_security_policy,
+        preferred_auth_header,
+        trust_proxy,
+        admin_allowed_ips,
         key_file,
         cert_file,
     }
@@ -299,13 +617,49 @@ fn load_env_vars() -> settings::Config {
 ///
 /// # Arguments
 ///
-/// * `metadata` - Struct containing metadata of the application.
+/// * `config` - Configuration loaded from the environment variables.
 ///
 /// # Returns
 ///
 /// Returns the `Config` struct containing the required parameters.
-fn validate_vars() -> settings::Config {
-    let config = load_env_vars();
+fn validate_vars(mut config: settings::Config) -> settings::Config {
+    if std::env::var("workers").is_ok() && config.workers_per_core.is_some() {
+        log::warn!("Both workers and workers_per_core are set, using {} workers and ignoring workers_per_core",
+            config.workers);
+    }
+    if std::env::var("workers").is_err() {
+        match (config.workers_per_core, std::thread::available_parallelism()) {
+            (Some(workers_per_core), Ok(cores)) => log::info!("Using {} workers, {} per logical core for {} cores",
+                config.workers, workers_per_core, cores),
+            (Some(_), Err(err)) => log::warn!("Ignoring workers_per_core, the logical cores are unknown: {}", err),
+            _ => ()
+        }
+        if config.workers_per_core.is_none() || std::thread::available_parallelism().is_err() {
+            let (_, reason) = settings::auto_workers();
+            log::info!("Using {} workers, {}", config.workers, reason);
+        }
+    } else if let Ok(cores) = std::thread::available_parallelism() {
+        if settings::is_over_provisioned(config.workers, cores.get()) {
+            log::warn!("{} workers are more than twice the {} logical cores available, \
+            which can degrade performance due to context switching", config.workers, cores);
+        }
+    }
+    log::info!("Accepting up to {} connections per worker, {} in total",
+        config.max_connections, config.max_connections.saturating_mul(config.workers));
+    if config.max_connections < config.workers {
+        log::warn!("max_connections ({}) is lower than the number of workers ({}), \
+        which is likely a misconfiguration since the limit applies per worker", config.max_connections, config.workers);
+    }
+    // Actix's PayloadConfig::limit applies to each request, so concurrent uploads multiply the memory in use
+    if let Some(available) = settings::available_memory_mb().map(|mb| mb.saturating_mul(1024 * 1024)) {
+        if config.max_payload_size as u64 > available / 2 {
+            log::warn!("max_payload_size ({} bytes) is more than half of the {} bytes of available memory, \
+            consider reducing it", config.max_payload_size, available);
+            let capped = usize::try_from(available / 4).unwrap_or(usize::MAX);
+            log::warn!("max_payload_size has been capped to {} bytes, a quarter of the available memory", capped);
+            config.max_payload_size = capped;
+        }
+    }
     let mut errors = "".to_owned();
     if !config.github_source.exists() || !config.github_source.is_dir() {
         let err1 = format!(
@@ -318,12 +672,189 @@ fn validate_vars() -> settings::Config {
         let err2 = "\nauthorization\n\tshould be at least 4 or more characters [value=invalid]\n";
         errors.push_str(err2);
     }
+    if config.git_host.is_empty()
+        || config.git_host.contains(char::is_whitespace)
+        || config.git_host.contains("://") {
+        let err3 = format!(
+            "\ngit_host\n\texpected a hostname without protocol, received '{}' [value=invalid]\n",
+            config.git_host
+        );
+        errors.push_str(&err3);
+    }
+    if let Some(hook) = &config.post_backup_hook {
+        let placeholder = regex::Regex::new(r"\{([^}]*)}").unwrap();
+        let unsupported: Vec<&str> = placeholder.captures_iter(hook)
+            .map(|capture| capture.get(1).unwrap().as_str())
+            .filter(|name| !settings::HOOK_PLACEHOLDERS.contains(name))
+            .collect();
+        if !unsupported.is_empty() {
+            let err5 = format!(
+                "\npost_backup_hook\n\tunsupported placeholders {:?}, expected one of {:?} [value=invalid]\n",
+                unsupported, settings::HOOK_PLACEHOLDERS
+            );
+            errors.push_str(&err5);
+        }
+    }
+    if let Some(url) = &config.backup_notification_url {
+        if !reqwest::Url::parse(url).is_ok_and(|parsed| ["http", "https"].contains(&parsed.scheme())) {
+            let err12 = format!(
+                "\nbackup_notification_url\n\texpected an http:// or https:// URL, received '{}' [value=invalid]\n",
+                url
+            );
+            errors.push_str(&err12);
+        }
+    } else if config.backup_notification_secret.is_some() {
+        log::warn!("backup_notification_secret is only used when backup_notification_url is set");
+    }
+    // Schemes like git:// and ssh:// either send credentials in plain text, or bypass the HTTP header used for the token
+    if config.require_https && config.git_clone_scheme != "https" {
+        let err6 = format!(
+            "\ngit_clone_scheme\n\texpected 'https' when require_https is enabled, received '{}' [value=invalid]\n",
+            config.git_clone_scheme
+        );
+        errors.push_str(&err6);
+    }
+    if config.github_token.is_some() && !config.require_https {
+        let err7 = "\nrequire_https\n\tcannot be disabled when github_token is set [value=invalid]\n";
+        errors.push_str(err7);
+    }
+    if let Some(ssh_key_file) = &config.ssh_key_file {
+        if !ssh_key_file.is_file() {
+            let err10 = format!(
+                "\nssh_key_file\n\tInput [{}] is not a valid file [value=invalid]\n",
+                ssh_key_file.to_string_lossy()
+            );
+            errors.push_str(&err10);
+        } else if config.git_clone_scheme != "ssh" {
+            log::warn!("ssh_key_file is only used when git_clone_scheme is 'ssh'");
+        }
+    }
+    // Header names are a 'token' as defined in RFC 7230, section 3.2.6
+    let is_token = |name: &str| !name.is_empty() && name.chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    for header in config.cors_expose_headers.iter().filter(|header| !is_token(header)) {
+        let err13 = format!("\ncors_expose_headers\n\t'{}' is not a valid header name [value=invalid]\n", header);
+        errors.push_str(&err13);
+    }
+    if actix_web::http::header::HeaderValue::from_str(&config.content_security_policy).is_err() {
+        let err14 = format!(
+            "\ncontent_security_policy\n\t'{}' is not a valid header value [value=invalid]\n",
+            config.content_security_policy.escape_debug()
+        );
+        errors.push_str(&err14);
+    }
+    match (config.github_app_id, &config.github_app_private_key_file) {
+        (Some(app_id), Some(private_key_file)) => {
+            let valid = std::fs::read(private_key_file).ok()
+                .is_some_and(|pem| jsonwebtoken::EncodingKey::from_rsa_pem(&pem).is_ok());
+            if !valid {
+                let err15 = format!(
+                    "\ngithub_app_private_key_file\n\tInput [{}] is not a valid RSA private key for app {} [value=invalid]\n",
+                    private_key_file.to_string_lossy(), app_id
+                );
+                errors.push_str(&err15);
+            }
+        }
+        (None, None) => (),
+        _ => {
+            let err15 = "\ngithub_app_id\n\tgithub_app_id and github_app_private_key_file are required together [value=invalid]\n";
+            errors.push_str(err15);
+        }
+    }
+    if config.max_repo_name_length < settings::MIN_REPO_NAME_LENGTH {
+        let err16 = format!(
+            "\nmax_repo_name_length\n\tshould be at least {}, received {} [value=invalid]\n",
+            settings::MIN_REPO_NAME_LENGTH, config.max_repo_name_length
+        );
+        errors.push_str(&err16);
+    }
+    if !["authorization", "x-api-key"].contains(&config.preferred_auth_header.as_str()) {
+        let err8 = format!(
+            "\npreferred_auth_header\n\texpected 'authorization' or 'x-api-key', received '{}' [value=invalid]\n",
+            config.preferred_auth_header
+        );
+        errors.push_str(&err8);
+    }
+    // Both the files are required to serve over HTTPS, a lone file is ignored
+    if !config.key_file.as_os_str().is_empty() && !config.cert_file.as_os_str().is_empty() {
+        if let Err(reason) = settings::validate_tls_pair(&config.key_file, &config.cert_file) {
+            let err9 = format!("\nkey_file\n\t{} [value=invalid]\n", reason);
+            errors.push_str(&err9);
+        }
+    }
+    let address = config.address();
+    let (host_key, port_key) = if config.bind_address.is_some() {
+        ("bind_address", "bind_address")
+    } else {
+        ("server_host", "server_port")
+    };
+    match address.to_socket_addrs() {
+        Ok(resolved) => {
+            let secure = config.cert_file.exists() && config.key_file.exists();
+            if let Some(exposed) = resolved.into_iter().find(|addr| !addr.ip().is_loopback()) {
+                if !secure {
+                    log::warn!("Serving over plain HTTP on {}, which is reachable beyond this machine", exposed);
+                }
+            }
+            // Listener is dropped right away, this only verifies that the port can be bound
+            if std::net::TcpListener::bind(&address).is_err() {
+                let err4 = format!(
+                    "\n{}\n\t{} is already in use [value=invalid]\n",
+                    port_key, address
+                );
+                errors.push_str(&err4);
+            }
+        }
+        Err(err) => {
+            let err4 = format!(
+                "\n{}\n\tunable to resolve '{}': {} [value=invalid]\n",
+                host_key, address, err
+            );
+            errors.push_str(&err4);
+        }
+    }
     if !errors.is_empty() {
         panic!("{}", errors);
     }
     config
 }
 
+/// Loads the keys from a JSON object in an env var as environment variables.
+///
+/// # Arguments
+///
+/// * `key` - Key for the environment variable with the JSON object.
+///
+/// ## See Also
+///
+/// - Variables that are already set in the environment take precedence over the JSON object.
+/// - Values that are not strings are loaded in their JSON representation, so arrays match the format of `websites`
+///
+/// # Panics
+///
+/// If the value is present, but it is not a JSON object.
+fn load_json_config(key: &str) {
+    let value = match std::env::var(key) {
+        Ok(value) => value,
+        Err(_) => return
+    };
+    let object = match serde_json::from_str::<serde_json::Value>(&value) {
+        Ok(serde_json::Value::Object(object)) => object,
+        Ok(_) => panic!("\n{}\n\texpected a JSON object [value=invalid]\n", key),
+        Err(err) => panic!("\n{}\n\tmalformed JSON: {} [value=invalid]\n", key, err)
+    };
+    for (name, value) in object {
+        if std::env::var(&name).is_ok() {
+            continue;
+        }
+        match value {
+            serde_json::Value::Null => (),
+            serde_json::Value::String(value) => std::env::set_var(&name, value),
+            value => std::env::set_var(&name, value.to_string())
+        }
+    }
+}
+
 /// Retrieves the environment variables and parses as the data-type specified in Config struct.
 ///
 /// # Arguments
@@ -340,9 +871,115 @@ pub fn get_config(metadata: &constant::MetaData) -> std::sync::Arc<settings::Con
             .unwrap_or(std::env::var("ENV_FILE")
                 .unwrap_or(".env".to_string()));
     }
+    load_json_config("GITHUB_CONFIG");
     let env_file_path = std::env::current_dir()
         .unwrap_or_default()
         .join(env_file);
     let _ = dotenv::from_path(env_file_path.as_path());
-    std::sync::Arc::new(validate_vars())
+    let config = load_env_vars();
+    // Logger is initialized before validation, so the warnings raised during validation are not lost
+    init_logger(config.debug, config.utc_logging, &metadata.crate_name, config.log_file.as_deref());
+    std::sync::Arc::new(validate_vars(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the parser against the value set under a key that is unique to the caller,
+    /// and returns `Err` with the panic message when the value is rejected.
+    fn parse_env<T>(key: &str, value: &str, parser: fn(&str) -> Option<T>) -> Result<Option<T>, String> {
+        std::env::set_var(key, value);
+        let result = std::panic::catch_unwind(|| parser(key));
+        std::env::remove_var(key);
+        result.map_err(|payload| payload.downcast_ref::<String>().cloned().unwrap_or_default())
+    }
+
+    #[actix_web::test]
+    async fn test_parse_bool() {
+        let truthy = ["true", "TRUE", "True", "1", "yes", "YES", "on", "On", "enabled", " yes "];
+        for value in truthy {
+            assert_eq!(parse_env("test_parse_bool", value, parse_bool), Ok(Some(true)), "{}", value);
+        }
+        let falsy = ["false", "FALSE", "0", "no", "No", "off", "OFF", "disabled", " off "];
+        for value in falsy {
+            assert_eq!(parse_env("test_parse_bool", value, parse_bool), Ok(Some(false)), "{}", value);
+        }
+        for value in ["", "2", "y", "maybe", "truthy"] {
+            let error = parse_env("test_parse_bool", value, parse_bool).unwrap_err();
+            assert!(error.contains("expected bool"), "{}", error);
+        }
+        assert_eq!(parse_bool("test_parse_bool_unset"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_parse_vec() {
+        let strings = |values: &[&str]| Some(values.iter().map(|value| value.to_string()).collect::<Vec<String>>());
+        assert_eq!(parse_env("test_parse_vec", r#"["a","b"]"#, parse_vec), Ok(strings(&["a", "b"])));
+        assert_eq!(parse_env("test_parse_vec", " [ \"a\" ] ", parse_vec), Ok(strings(&["a"])));
+        assert_eq!(parse_env("test_parse_vec", "a,b,c", parse_vec), Ok(strings(&["a", "b", "c"])));
+        assert_eq!(parse_env("test_parse_vec", " a , b ", parse_vec), Ok(strings(&["a", "b"])));
+        assert_eq!(parse_env("test_parse_vec", "a,,b,", parse_vec), Ok(strings(&["a", "b"])));
+        assert_eq!(parse_env("test_parse_vec", "single", parse_vec), Ok(strings(&["single"])));
+        assert_eq!(parse_env("test_parse_vec", "[]", parse_vec), Ok(strings(&[])));
+        assert_eq!(parse_env("test_parse_vec", "", parse_vec), Ok(strings(&[])));
+        for value in [r#"["a","#, "[a, b]", "[1, 2]"] {
+            let error = parse_env("test_parse_vec", value, parse_vec).unwrap_err();
+            assert!(error.contains("expected vec"), "{}", error);
+        }
+        assert_eq!(parse_vec("test_parse_vec_unset"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_parse_usize_bounded() {
+        let parse = |key: &str| parse_usize_bounded(key, 1, 1024);
+        assert_eq!(parse_env("test_parse_usize_bounded", "1", parse), Ok(Some(1)));
+        assert_eq!(parse_env("test_parse_usize_bounded", "1024", parse), Ok(Some(1024)));
+        for value in ["0", "1025"] {
+            let error = parse_env("test_parse_usize_bounded", value, parse).unwrap_err();
+            assert!(error.contains("expected value between 1 and 1024"), "{}", error);
+        }
+        for value in ["-1", "four", ""] {
+            let error = parse_env("test_parse_usize_bounded", value, parse).unwrap_err();
+            assert!(error.contains("expected usize"), "{}", error);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_parse_f64_bounded() {
+        let parse = |key: &str| parse_f64_bounded(key, 0.25, 4.0);
+        assert_eq!(parse_env("test_parse_f64_bounded", "0.25", parse), Ok(Some(0.25)));
+        assert_eq!(parse_env("test_parse_f64_bounded", " 1.5 ", parse), Ok(Some(1.5)));
+        assert_eq!(parse_env("test_parse_f64_bounded", "4", parse), Ok(Some(4.0)));
+        for value in ["0.2", "4.01", "NaN", "inf"] {
+            let error = parse_env("test_parse_f64_bounded", value, parse).unwrap_err();
+            assert!(error.contains("expected value between 0.25 and 4"), "{}", error);
+        }
+        let error = parse_env("test_parse_f64_bounded", "half", parse).unwrap_err();
+        assert!(error.contains("expected f64"), "{}", error);
+    }
+
+    #[actix_web::test]
+    async fn test_parse_memory() {
+        assert_eq!(parse_memory("100 MB"), Some(100 * 1024 * 1024));
+        assert_eq!(parse_memory(" 2 gb "), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_memory("512 KB"), Some(512 * 1024));
+        assert_eq!(parse_memory("100MB"), None);
+        assert_eq!(parse_memory("100 PB"), None);
+        assert_eq!(parse_memory("ten MB"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_parse_websites() {
+        let origins = parse_env("test_parse_websites", "https://example.com, ^https://.*\\.example\\.com$", parse_websites)
+            .unwrap().unwrap();
+        assert!(matches!(&origins[0], settings::CorsOrigin::Exact(origin) if origin == "https://example.com"));
+        assert!(matches!(&origins[1], settings::CorsOrigin::Pattern(_)));
+        // All the invalid entries are reported at once
+        let error = parse_env("test_parse_websites", "https://example.com,ftp://example.com,https://example.com/path",
+                              parse_websites).unwrap_err();
+        assert!(error.contains("'ftp://example.com' must use the http:// or https:// scheme"), "{}", error);
+        assert!(error.contains("'https://example.com/path' must not have a path"), "{}", error);
+        assert!(!error.contains("'https://example.com' "), "{}", error);
+    }
 }
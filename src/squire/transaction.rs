@@ -0,0 +1,76 @@
+use std::{fs, io, path};
+use std::io::Write;
+
+use chrono::{NaiveDate, Utc};
+
+use crate::constant;
+
+/// Name of the directory within `github_source` that stores the transaction logs.
+const LOG_DIRECTORY: &str = ".backup_log";
+
+/// Returns the path to the transaction log file for a given date.
+///
+/// # Arguments
+///
+/// * `github_source` - GitHub source directory.
+/// * `date` - Date of the transaction log.
+///
+/// ## See Also
+///
+/// The filename is built from the parsed date, so user input never reaches the filesystem path.
+pub fn log_file(github_source: &path::Path, date: &NaiveDate) -> path::PathBuf {
+    github_source
+        .join(LOG_DIRECTORY)
+        .join(format!("{}.ndjson", date.format("%Y-%m-%d")))
+}
+
+/// Appends the operations of a backup request that succeeded completely to the transaction log.
+///
+/// # Arguments
+///
+/// * `session` - Session struct that holds the writer for the current day's log file.
+/// * `github_source` - GitHub source directory.
+/// * `repository` - Name of the repository.
+/// * `request_id` - Unique identifier for the backup request.
+/// * `operations` - List of operations as `(operation, path)`
+///
+/// ## See Also
+///
+/// The writer is replaced when the date changes, so each day gets its own log file.
+///
+/// # Returns
+///
+/// Returns a `Result` object.
+pub fn append(session: &constant::Session,
+              github_source: &path::Path,
+              repository: &str,
+              request_id: &str,
+              operations: &[(&str, String)]) -> Result<(), io::Error> {
+    let now = Utc::now();
+    let today = now.date_naive();
+    let mut backup_log = session.backup_log.lock().unwrap();
+    if !matches!(backup_log.as_ref(), Some((date, _)) if *date == today) {
+        let filepath = log_file(github_source, &today);
+        if let Some(parent) = filepath.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).open(&filepath)?;
+        log::info!("Writing transaction log to {:?}", filepath);
+        *backup_log = Some((today, io::BufWriter::new(file)));
+    }
+    if let Some((_, writer)) = backup_log.as_mut() {
+        let timestamp = now.to_rfc3339();
+        for (operation, path) in operations {
+            let entry = serde_json::json!({
+                "timestamp": timestamp,
+                "repository": repository,
+                "operation": operation,
+                "path": path,
+                "request_id": request_id
+            });
+            writeln!(writer, "{}", entry)?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::{thread, time};
+
+#[test]
+fn test_over_provisioned_workers_warning() {
+    let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+    let github_source = std::env::temp_dir().join(format!("github-startup-{}", std::process::id()));
+    std::fs::create_dir_all(&github_source).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_github"))
+        .env("authorization", "startup-test-token")
+        .env("github_source", &github_source)
+        .env("server_host", "127.0.0.1")
+        .env("server_port", (40_000 + std::process::id() % 10_000).to_string())
+        .env("workers", (cores * 2 + 1).to_string())
+        .env("RUST_LOG", "warn")
+        .env("NO_COLOR", "1")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let deadline = time::Instant::now() + time::Duration::from_secs(30);
+    let mut found = false;
+    while let Some(remaining) = deadline.checked_duration_since(time::Instant::now()) {
+        match receiver.recv_timeout(remaining) {
+            Ok(line) if line.contains("more than twice") && line.contains("WARN") => {
+                found = true;
+                break;
+            }
+            Ok(_) => continue,
+            Err(_) => break
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    let _ = std::fs::remove_dir_all(&github_source);
+    assert!(found, "warning for the over-provisioned workers was not logged");
+}